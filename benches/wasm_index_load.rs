@@ -0,0 +1,76 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Load time and memory of the WASM index for the bundled `indexes/*.tidx`, compared with
+//! building the kmer `HashMap` that earlier versions built on every load.
+//!
+//! cargo bench --bench wasm_index_load
+use std::collections::HashMap;
+use std::fs;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+
+use tinyt::build_index::{WasmIndex, WasmRuntimeIndex};
+use tinyt::index_file::{self, IndexHeader, IndexKind, LoadedIndex, NativeIndex};
+
+const ROUNDS: u32 = 20;
+
+fn mean_time<T, F: FnMut() -> T>(mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let _ = f();
+    }
+    start.elapsed() / ROUNDS
+}
+
+fn wasm_index_bytes(path: &str) -> Result<Vec<u8>, Error> {
+    let wasm_index = match index_file::read_index(path)?.1 {
+        LoadedIndex::Native(index) => index.export_wasm(),
+        LoadedIndex::Wasm(index) => index,
+    };
+    let mut bytes = Vec::new();
+    index_file::write_index_to(&wasm_index, IndexHeader::new(IndexKind::Wasm, wasm_index.k as usize, None), &mut bytes)?;
+    Ok(bytes)
+}
+
+fn load(bytes: &[u8]) -> WasmRuntimeIndex {
+    WasmRuntimeIndex::from_wasm_index(WasmIndex::from_bytes(bytes).unwrap()).unwrap()
+}
+
+fn main() -> Result<(), Error> {
+    let mut paths: Vec<String> = fs::read_dir("indexes")?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().to_string_lossy().to_string())
+        .filter(|path| path.ends_with(".tidx"))
+        .collect();
+    paths.sort();
+
+    println!("Index\tKmers\tFileBytes\tLoadMicros\tHeapBytes\tHashMapMicros\tHashMapBytes");
+    for path in &paths {
+        let bytes = wasm_index_bytes(path)?;
+        let load_time = mean_time(|| load(&bytes));
+        let runtime = load(&bytes);
+
+        // the kmer map earlier versions built on top of the deserialised index
+        let map_time = mean_time(|| {
+            runtime.kmers.iter().map(|(kmer, node, offset)| (*kmer, (*node, *offset))).collect::<HashMap<u64, (u32, u32)>>()
+        });
+        let map: HashMap<u64, (u32, u32)> =
+            runtime.kmers.iter().map(|(kmer, node, offset)| (*kmer, (*node, *offset))).collect();
+        // entries plus one control byte per bucket
+        let map_bytes = map.capacity() * (size_of::<(u64, (u32, u32))>() + 1);
+
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            path,
+            runtime.kmers.len(),
+            bytes.len(),
+            load_time.as_micros(),
+            runtime.heap_size(),
+            map_time.as_micros(),
+            map_bytes
+        );
+    }
+    Ok(())
+}
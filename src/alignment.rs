@@ -0,0 +1,288 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! SAM, BAM and CRAM read input, so archived aligned or unaligned samples can be mapped
+//! without a round trip to FASTQ. Only built with the `htslib` feature.
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use bio::alphabets::dna;
+use failure::{self, Error};
+use log::{info, warn};
+use rust_htslib::bam::{self, FetchDefinition, Read};
+
+use crate::utils::{ReadInput, ReadRecord, RecordPairs, RecordResult, Records};
+
+type BamRecords = Box<dyn Iterator<Item = io::Result<bam::Record>> + Send>;
+
+fn htslib_error(err: rust_htslib::errors::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+/// Primary records of a whole file, or of a region and then the unmapped reads of an
+/// indexed file. Secondary and supplementary alignments would count a read twice.
+enum PrimaryRecords {
+    Whole(bam::Reader),
+    Region {
+        reader: bam::IndexedReader,
+        unmapped_fetched: bool,
+    },
+}
+
+impl PrimaryRecords {
+    fn read_next(&mut self, record: &mut bam::Record) -> Option<rust_htslib::errors::Result<()>> {
+        match self {
+            PrimaryRecords::Whole(reader) => reader.read(record),
+            PrimaryRecords::Region {
+                reader,
+                unmapped_fetched,
+            } => match reader.read(record) {
+                None if !*unmapped_fetched => {
+                    *unmapped_fetched = true;
+                    if let Err(err) = reader.fetch(FetchDefinition::Unmapped) {
+                        return Some(Err(err));
+                    }
+                    reader.read(record)
+                }
+                result => result,
+            },
+        }
+    }
+}
+
+impl Iterator for PrimaryRecords {
+    type Item = io::Result<bam::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut record = bam::Record::new();
+            match self.read_next(&mut record)? {
+                Err(err) => return Some(Err(htslib_error(err))),
+                Ok(()) if record.is_secondary() || record.is_supplementary() => continue,
+                Ok(()) => return Some(Ok(record)),
+            }
+        }
+    }
+}
+
+/// The read as sequenced: reverse strand alignments are reverse complemented back
+fn to_read(record: &bam::Record) -> ReadRecord {
+    let id = String::from_utf8_lossy(record.qname()).to_string();
+    let mut seq = record.seq().as_bytes();
+    let mut qual: Vec<u8> = match record.qual().first() {
+        // 0xff marks missing base qualities
+        Some(0xff) | None => vec![b'I'; seq.len()],
+        Some(_) => record.qual().iter().map(|q| q.saturating_add(33)).collect(),
+    };
+    if record.is_reverse() {
+        seq = dna::revcomp(&seq);
+        qual.reverse();
+    }
+    ReadRecord {
+        id,
+        seq,
+        qual: Some(qual),
+    }
+}
+
+/// Pairs mates by read name and the first/last in template flags. Mates of a coordinate
+/// sorted file can be far apart, so unmatched mates are held until their mate is read.
+struct MatePairs {
+    records: BamRecords,
+    pending: HashMap<Vec<u8>, ReadRecord>,
+    unpaired: usize,
+}
+
+impl Iterator for MatePairs {
+    type Item = (RecordResult, RecordResult);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let record = match self.records.next() {
+                Some(Ok(record)) => record,
+                Some(Err(err)) => {
+                    let mate_err = io::Error::new(io::ErrorKind::Other, "mate of an unreadable record");
+                    return Some((Err(err), Err(mate_err)));
+                }
+                None => {
+                    if self.unpaired + self.pending.len() > 0 {
+                        warn!(
+                            "{} single end and {} reads without their mate were not mapped",
+                            self.unpaired,
+                            self.pending.len()
+                        );
+                    }
+                    self.pending.clear();
+                    return None;
+                }
+            };
+            if !record.is_paired() {
+                self.unpaired += 1;
+                continue;
+            }
+
+            let first = record.is_first_in_template();
+            let read = to_read(&record);
+            match self.pending.remove(record.qname()) {
+                Some(mate) if first => return Some((Ok(read), Ok(mate))),
+                Some(mate) => return Some((Ok(mate), Ok(read))),
+                None => {
+                    self.pending.insert(record.qname().to_vec(), read);
+                }
+            }
+        }
+    }
+}
+
+/// Reads of a SAM, BAM or CRAM file, paired if the first primary record is paired.
+/// CRAM needs the `reference` FASTA it was compressed against. With a `region`, only reads
+/// aligned there and unmapped reads are taken, which needs an indexed file.
+pub fn alignment_reads(path: &Path, reference: Option<&Path>, region: Option<&str>) -> Result<ReadInput, Error> {
+    let open_err = |e: rust_htslib::errors::Error| failure::err_msg(format!("Could not open {}: {}", path.display(), e));
+
+    let records = match region {
+        Some(region) => {
+            let mut reader = bam::IndexedReader::from_path(path).map_err(open_err)?;
+            if let Some(reference) = reference {
+                reader.set_reference(reference)?;
+            }
+            reader
+                .fetch(region)
+                .map_err(|e| failure::err_msg(format!("Could not fetch region {}: {}", region, e)))?;
+            info!("Reading reads in {} and unmapped reads from {}", region, path.display());
+            PrimaryRecords::Region {
+                reader,
+                unmapped_fetched: false,
+            }
+        }
+        None => {
+            let mut reader = bam::Reader::from_path(path).map_err(open_err)?;
+            if let Some(reference) = reference {
+                reader.set_reference(reference)?;
+            }
+            PrimaryRecords::Whole(reader)
+        }
+    };
+
+    let mut records = records.peekable();
+    let is_paired = match records.peek() {
+        Some(Ok(record)) => record.is_paired(),
+        _ => false,
+    };
+
+    if is_paired {
+        info!("Paired end reads in {}", path.display());
+        let pairs: RecordPairs = Box::new(MatePairs {
+            records: Box::new(records),
+            pending: HashMap::new(),
+            unpaired: 0,
+        });
+        Ok(ReadInput::Paired(pairs))
+    } else {
+        info!("Single end reads in {}", path.display());
+        let reads: Records = Box::new(records.map(|record| record.map(|r| to_read(&r))));
+        Ok(ReadInput::Single(reads))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    const HEADER: &str = "@HD\tVN:1.6\tSO:unsorted\n@SQ\tSN:tx1\tLN:100\n";
+
+    /// Write a SAM file of `records` for a test, removed by the caller
+    fn write_sam(name: &str, records: &[&str]) -> Result<PathBuf, Error> {
+        let path = std::env::temp_dir().join(format!("tinyt_{}_{}.sam", name, std::process::id()));
+        let mut sam = HEADER.to_string();
+        for record in records {
+            sam.push_str(record);
+            sam.push('\n');
+        }
+        std::fs::write(&path, sam)?;
+        Ok(path)
+    }
+
+    fn read(id: &str, seq: &str, qual: &str) -> ReadRecord {
+        ReadRecord {
+            id: id.to_string(),
+            seq: seq.as_bytes().to_vec(),
+            qual: Some(qual.as_bytes().to_vec()),
+        }
+    }
+
+    #[test]
+    fn single_reads_test() -> Result<(), Error> {
+        let path = write_sam(
+            "single_reads_test",
+            &[
+                "s1\t0\ttx1\t1\t60\t10M\t*\t0\t0\tAAAACCCCGG\tABCDEFGHIJ",
+                // reverse strand, without base qualities
+                "s2\t16\ttx1\t20\t60\t10M\t*\t0\t0\tACGTTTGGCA\t*",
+                // secondary and supplementary alignments of s1
+                "s1\t256\ttx1\t40\t0\t10M\t*\t0\t0\tAAAACCCCGG\tABCDEFGHIJ",
+                "s1\t2048\ttx1\t60\t0\t10M\t*\t0\t0\tAAAACCCCGG\tABCDEFGHIJ",
+                "s3\t16\ttx1\t80\t60\t10M\t*\t0\t0\tACGTTTGGCA\tABCDEFGHIJ",
+            ],
+        )?;
+        let input = alignment_reads(&path, None, None);
+        std::fs::remove_file(&path)?;
+
+        let reads = match input? {
+            ReadInput::Single(records) => records.collect::<io::Result<Vec<_>>>()?,
+            ReadInput::Paired(_) => panic!("single end reads read as pairs"),
+        };
+        assert_eq!(
+            reads,
+            vec![
+                read("s1", "AAAACCCCGG", "ABCDEFGHIJ"),
+                read("s2", "TGCCAAACGT", "IIIIIIIIII"),
+                read("s3", "TGCCAAACGT", "JIHGFEDCBA"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mate_pairs_test() -> Result<(), Error> {
+        let path = write_sam(
+            "mate_pairs_test",
+            &[
+                // second mates before their first mates, as in a coordinate sorted file
+                "p1\t163\ttx1\t1\t60\t10M\t=\t50\t59\tAAAACCCCGG\tABCDEFGHIJ",
+                "p2\t163\ttx1\t5\t60\t10M\t=\t30\t35\tGGGGAAAACC\tABCDEFGHIJ",
+                "p1\t2129\ttx1\t10\t0\t10M\t=\t1\t0\tACGTTTGGCA\tABCDEFGHIJ",
+                "p2\t83\ttx1\t30\t60\t10M\t=\t5\t-35\tTTTTGGGGCC\tABCDEFGHIJ",
+                "p1\t339\ttx1\t20\t0\t10M\t=\t1\t0\tACGTTTGGCA\tABCDEFGHIJ",
+                "p1\t83\ttx1\t50\t60\t10M\t=\t1\t-59\tACGTTTGGCA\tABCDEFGHIJ",
+                // single end read and a mate without its pair
+                "s1\t0\ttx1\t60\t60\t10M\t*\t0\t0\tAAAACCCCGG\tABCDEFGHIJ",
+                "p3\t99\ttx1\t70\t60\t10M\t=\t90\t30\tAAAACCCCGG\tABCDEFGHIJ",
+            ],
+        )?;
+        let input = alignment_reads(&path, None, None);
+        std::fs::remove_file(&path)?;
+
+        let pairs = match input? {
+            ReadInput::Paired(pairs) => pairs
+                .map(|(r1, r2)| Ok((r1?, r2?)))
+                .collect::<io::Result<Vec<_>>>()?,
+            ReadInput::Single(_) => panic!("pairs read as single end reads"),
+        };
+        assert_eq!(
+            pairs,
+            vec![
+                (
+                    read("p2", "GGCCCCAAAA", "JIHGFEDCBA"),
+                    read("p2", "GGGGAAAACC", "ABCDEFGHIJ")
+                ),
+                (
+                    read("p1", "TGCCAAACGT", "JIHGFEDCBA"),
+                    read("p1", "AAAACCCCGG", "ABCDEFGHIJ")
+                ),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,423 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Read canonical transcript definitions from GTF or GFF3 gene annotations.
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+
+use failure::{self, Error};
+use log::{debug, info, warn};
+
+use crate::reference::{CanonicalTranscript, Strand};
+use crate::utils;
+
+/// Transcript tags marking a canonical transcript, in order of preference
+const CANONICAL_TAGS: [&str; 2] = ["MANE_Select", "Ensembl_canonical"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnotationFormat {
+    Gtf,
+    Gff3,
+}
+
+/// How to choose transcripts from an annotation
+#[derive(Clone, Debug)]
+pub enum TranscriptSelector {
+    /// Transcript IDs, matched with or without the version suffix
+    Ids(Vec<String>),
+    /// The MANE Select (or failing that, Ensembl canonical) transcript of a gene,
+    /// matched by gene name or gene ID
+    Canonical(String),
+}
+
+#[derive(Clone, Debug)]
+struct TranscriptRecord {
+    tx_id: String,
+    gene_id: String,
+    gene_name: Option<String>,
+    chrom: String,
+    strand: Strand,
+    tags: Vec<String>,
+    exons: Vec<(u64, u64)>,
+}
+
+/// Split a GTF (`key "value";`) or GFF3 (`key=value,value;`) attribute column
+/// into key/value pairs. Repeated or comma separated values give one pair each.
+fn parse_attributes(column: &str, format: AnnotationFormat) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+
+    for field in column.split(';').map(|f| f.trim()).filter(|f| !f.is_empty()) {
+        match format {
+            AnnotationFormat::Gtf => {
+                let mut parts = field.splitn(2, char::is_whitespace);
+                let key = parts.next().unwrap_or("");
+                let value = parts.next().unwrap_or("").trim().trim_matches('"');
+                attributes.push((key.to_string(), value.to_string()));
+            }
+            AnnotationFormat::Gff3 => {
+                let mut parts = field.splitn(2, '=');
+                let key = parts.next().unwrap_or("");
+                for value in parts.next().unwrap_or("").split(',') {
+                    attributes.push((key.to_string(), value.to_string()));
+                }
+            }
+        }
+    }
+
+    attributes
+}
+
+fn get_attribute<'a>(attributes: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Ensembl GFF3 prefixes IDs with the feature type, e.g. `transcript:ENST...`
+fn strip_id_prefix(id: &str) -> &str {
+    match id.find(':') {
+        Some(pos) => &id[pos + 1..],
+        None => id,
+    }
+}
+
+/// Compare transcript IDs, ignoring the version suffix if the query has none
+fn transcript_id_matches(query: &str, tx_id: &str) -> bool {
+    query == tx_id || (!query.contains('.') && tx_id.split('.').next() == Some(query))
+}
+
+/// Detect the annotation format from the file name, ignoring any `.gz`, falling back to GTF.
+pub fn detect_format<P: AsRef<Path>>(path: P) -> AnnotationFormat {
+    let name = path.as_ref().to_string_lossy().to_lowercase();
+    let name = name.trim_end_matches(".gz");
+    if name.ends_with(".gff3") || name.ends_with(".gff") {
+        AnnotationFormat::Gff3
+    } else {
+        AnnotationFormat::Gtf
+    }
+}
+
+pub fn read_annotation_file<P: AsRef<Path>>(
+    path: P,
+    selector: &TranscriptSelector,
+) -> Result<Vec<CanonicalTranscript>, Error> {
+    let format = detect_format(&path);
+    info!("Reading {:?} annotation from {}", format, path.as_ref().display());
+    // gzipped annotations, e.g. GENCODE's *.gtf.gz, are detected from the file contents
+    let reader = utils::open_with_gz(path.as_ref())?;
+    read_annotation(reader, format, selector)
+}
+
+/// Read the exon structure of the selected transcripts from a GTF or GFF3 annotation.
+pub fn read_annotation<R: BufRead>(
+    reader: R,
+    format: AnnotationFormat,
+    selector: &TranscriptSelector,
+) -> Result<Vec<CanonicalTranscript>, Error> {
+    let mut transcripts: HashMap<String, TranscriptRecord> = HashMap::new();
+    // GFF3 gene ID -> gene name, as Ensembl GFF3 transcripts only name their parent gene
+    let mut gene_names: HashMap<String, String> = HashMap::new();
+
+    let keep_gene = |gene_id: &str, gene_name: Option<&str>| -> bool {
+        match selector {
+            TranscriptSelector::Ids(_) => true,
+            TranscriptSelector::Canonical(gene) => {
+                gene_id == gene
+                    || gene_id.split('.').next() == Some(gene.as_str())
+                    || gene_name == Some(gene.as_str())
+            }
+        }
+    };
+    let keep_transcript = |tx_id: &str| -> bool {
+        match selector {
+            TranscriptSelector::Ids(ids) => ids.iter().any(|id| transcript_id_matches(id, tx_id)),
+            TranscriptSelector::Canonical(_) => true,
+        }
+    };
+
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            return Err(failure::err_msg(format!(
+                "Annotation line {} has {} columns, expected 9",
+                line_no + 1,
+                fields.len()
+            )));
+        }
+
+        let feature = fields[2];
+        let attributes = parse_attributes(fields[8], format);
+
+        if feature == "gene" {
+            if let (Some(id), Some(name)) = (get_attribute(&attributes, "ID"), get_attribute(&attributes, "Name")) {
+                gene_names.insert(strip_id_prefix(id).to_string(), name.to_string());
+            }
+            continue;
+        }
+
+        let is_transcript = feature == "transcript" || feature == "mRNA";
+        if !is_transcript && feature != "exon" {
+            continue;
+        }
+
+        let tx_id = match (format, is_transcript) {
+            (AnnotationFormat::Gtf, _) => get_attribute(&attributes, "transcript_id"),
+            (AnnotationFormat::Gff3, true) => get_attribute(&attributes, "transcript_id")
+                .or_else(|| get_attribute(&attributes, "ID").map(strip_id_prefix)),
+            (AnnotationFormat::Gff3, false) => get_attribute(&attributes, "transcript_id")
+                .or_else(|| get_attribute(&attributes, "Parent").map(strip_id_prefix)),
+        };
+        let tx_id = match tx_id {
+            Some(id) => id.to_string(),
+            None => continue,
+        };
+
+        if !keep_transcript(&tx_id) {
+            continue;
+        }
+
+        if !transcripts.contains_key(&tx_id) {
+            // GFF3 exons only link to a transcript already seen
+            if format == AnnotationFormat::Gff3 && !is_transcript {
+                continue;
+            }
+
+            let gene_id = get_attribute(&attributes, "gene_id")
+                .or_else(|| get_attribute(&attributes, "Parent").map(strip_id_prefix))
+                .unwrap_or("")
+                .to_string();
+            let gene_name = get_attribute(&attributes, "gene_name")
+                .map(|n| n.to_string())
+                .or_else(|| gene_names.get(&gene_id).cloned());
+
+            if !keep_gene(&gene_id, gene_name.as_deref()) {
+                continue;
+            }
+
+            transcripts.insert(
+                tx_id.clone(),
+                TranscriptRecord {
+                    tx_id: tx_id.clone(),
+                    gene_id,
+                    gene_name,
+                    chrom: fields[0].to_string(),
+                    strand: Strand::from_symbol(fields[6])?,
+                    tags: Vec::new(),
+                    exons: Vec::new(),
+                },
+            );
+        }
+
+        let record = transcripts.get_mut(&tx_id).unwrap();
+        if is_transcript {
+            record.tags.extend(
+                attributes
+                    .iter()
+                    .filter(|(k, _)| k == "tag")
+                    .map(|(_, v)| v.clone()),
+            );
+        } else {
+            // 1-based inclusive to 0-based half-open
+            let position = |s: &str| {
+                s.parse::<u64>().map_err(|e| {
+                    failure::err_msg(format!("Annotation line {} has position {}: {}", line_no + 1, s, e))
+                })
+            };
+            let start = position(fields[3])?.checked_sub(1).ok_or_else(|| {
+                failure::err_msg(format!("Annotation line {} has start 0, positions are 1-based", line_no + 1))
+            })?;
+            let end = position(fields[4])?;
+            record.exons.push((start, end));
+        }
+    }
+
+    debug!("Found {} candidate transcripts in annotation", transcripts.len());
+
+    // in ID order, so the same transcript is chosen on every run when several match,
+    // e.g. a transcript and its PAR_Y copy
+    let mut records: Vec<&TranscriptRecord> = transcripts.values().collect();
+    records.sort_by(|a, b| a.tx_id.cmp(&b.tx_id));
+
+    let selected: Vec<TranscriptRecord> = match selector {
+        TranscriptSelector::Ids(ids) => ids
+            .iter()
+            .map(|id| {
+                records
+                    .iter()
+                    .find(|t| transcript_id_matches(id, &t.tx_id))
+                    .map(|t| (*t).clone())
+                    .ok_or_else(|| failure::err_msg(format!("Transcript {} not found in annotation", id)))
+            })
+            .collect::<Result<_, Error>>()?,
+        TranscriptSelector::Canonical(gene) => {
+            let tagged = CANONICAL_TAGS.iter().find_map(|tag| {
+                let matches: Vec<&TranscriptRecord> = records
+                    .iter()
+                    .filter(|t| t.tags.iter().any(|t| t == tag))
+                    .cloned()
+                    .collect();
+                if matches.is_empty() {
+                    None
+                } else {
+                    Some((tag, matches))
+                }
+            });
+            match tagged {
+                Some((tag, matches)) => {
+                    let t = matches[0];
+                    if matches.len() > 1 {
+                        let others: Vec<&str> = matches[1..].iter().map(|t| t.tx_id.as_str()).collect();
+                        warn!(
+                            "{} {} transcripts for {}, selected {} (also {}); choose one with --transcript",
+                            matches.len(),
+                            tag,
+                            gene,
+                            t.tx_id,
+                            others.join(",")
+                        );
+                    }
+                    info!("Selected {} transcript {} for {}", tag, t.tx_id, gene);
+                    vec![t.clone()]
+                }
+                None => {
+                    let mut candidates: Vec<&str> = transcripts.keys().map(|k| k.as_str()).collect();
+                    candidates.sort();
+                    return Err(failure::err_msg(format!(
+                        "No {} transcript found for gene {}, choose one with --transcript from: {}",
+                        CANONICAL_TAGS.join(" or "),
+                        gene,
+                        candidates.join(",")
+                    )));
+                }
+            }
+        }
+    };
+
+    selected
+        .into_iter()
+        .map(|mut t| {
+            if t.exons.is_empty() {
+                return Err(failure::err_msg(format!("Transcript {} has no exons", t.tx_id)));
+            }
+            t.exons.sort();
+            Ok(CanonicalTranscript {
+                gene: t.gene_name.unwrap_or(t.gene_id),
+                tx_id: t.tx_id,
+                chrom: t.chrom,
+                strand: t.strand,
+                exons: t.exons,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GTF: &str = "\
+#!genome-build GRCh38
+chr1\tHAVANA\tgene\t100\t900\t.\t-\t.\tgene_id \"ENSG01.1\"; gene_name \"GENEA\";
+chr1\tHAVANA\ttranscript\t100\t900\t.\t-\t.\tgene_id \"ENSG01.1\"; transcript_id \"ENST01.2\"; gene_name \"GENEA\"; tag \"basic\"; tag \"MANE_Select\";
+chr1\tHAVANA\texon\t800\t900\t.\t-\t.\tgene_id \"ENSG01.1\"; transcript_id \"ENST01.2\"; gene_name \"GENEA\"; exon_number 1;
+chr1\tHAVANA\texon\t400\t500\t.\t-\t.\tgene_id \"ENSG01.1\"; transcript_id \"ENST01.2\"; gene_name \"GENEA\"; exon_number 2;
+chr1\tHAVANA\texon\t100\t200\t.\t-\t.\tgene_id \"ENSG01.1\"; transcript_id \"ENST01.2\"; gene_name \"GENEA\"; exon_number 3;
+chr1\tHAVANA\ttranscript\t100\t500\t.\t-\t.\tgene_id \"ENSG01.1\"; transcript_id \"ENST02.1\"; gene_name \"GENEA\"; tag \"basic\";
+chr1\tHAVANA\texon\t100\t500\t.\t-\t.\tgene_id \"ENSG01.1\"; transcript_id \"ENST02.1\"; gene_name \"GENEA\"; exon_number 1;
+";
+
+    const GFF3: &str = "\
+##gff-version 3
+chr1\tensembl\tgene\t100\t900\t.\t-\t.\tID=gene:ENSG01;Name=GENEA
+chr1\tensembl\tmRNA\t100\t900\t.\t-\t.\tID=transcript:ENST01;Parent=gene:ENSG01;tag=basic,Ensembl_canonical
+chr1\tensembl\texon\t800\t900\t.\t-\t.\tParent=transcript:ENST01
+chr1\tensembl\texon\t100\t200\t.\t-\t.\tParent=transcript:ENST01
+";
+
+    #[test]
+    fn gtf_canonical_test() -> Result<(), Error> {
+        let selector = TranscriptSelector::Canonical("GENEA".to_string());
+        let txs = read_annotation(GTF.as_bytes(), AnnotationFormat::Gtf, &selector)?;
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].tx_id, "ENST01.2");
+        assert_eq!(txs[0].gene, "GENEA");
+        assert_eq!(txs[0].exons, vec![(99, 200), (399, 500), (799, 900)]);
+        // exon 1 is the 5' exon of the minus strand transcript
+        assert_eq!(txs[0].ordered_exons()[0], (799, 900));
+        Ok(())
+    }
+
+    #[test]
+    fn gtf_canonical_copies_test() -> Result<(), Error> {
+        // a PAR_Y copy of the MANE Select transcript, listed first
+        let par_y = GTF
+            .lines()
+            .filter(|l| l.contains("ENST01.2"))
+            .map(|l| l.replace("ENST01.2", "ENST01.2_PAR_Y").replace("chr1", "chrY"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let gtf = format!("{}\n{}", par_y, GTF);
+        let selector = TranscriptSelector::Canonical("GENEA".to_string());
+        for _ in 0..5 {
+            let txs = read_annotation(gtf.as_bytes(), AnnotationFormat::Gtf, &selector)?;
+            assert_eq!(txs[0].tx_id, "ENST01.2");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn gtf_transcript_id_test() -> Result<(), Error> {
+        let selector = TranscriptSelector::Ids(vec!["ENST02".to_string()]);
+        let txs = read_annotation(GTF.as_bytes(), AnnotationFormat::Gtf, &selector)?;
+        assert_eq!(txs[0].tx_id, "ENST02.1");
+
+        let selector = TranscriptSelector::Ids(vec!["ENST03".to_string()]);
+        assert!(read_annotation(GTF.as_bytes(), AnnotationFormat::Gtf, &selector).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn gzipped_gtf_test() -> Result<(), Error> {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("tinyt_gzipped_gtf_test_{}.gtf.gz", std::process::id()));
+        let mut gz = GzEncoder::new(std::fs::File::create(&path)?, flate2::Compression::default());
+        gz.write_all(GTF.as_bytes())?;
+        gz.finish()?;
+
+        assert_eq!(detect_format(&path), AnnotationFormat::Gtf);
+        let selector = TranscriptSelector::Canonical("GENEA".to_string());
+        let txs = read_annotation_file(&path, &selector);
+        std::fs::remove_file(&path)?;
+        assert_eq!(txs?[0].tx_id, "ENST01.2");
+        Ok(())
+    }
+
+    #[test]
+    fn gff3_canonical_test() -> Result<(), Error> {
+        let selector = TranscriptSelector::Canonical("GENEA".to_string());
+        let txs = read_annotation(GFF3.as_bytes(), AnnotationFormat::Gff3, &selector)?;
+        assert_eq!(txs[0].tx_id, "ENST01");
+        assert_eq!(txs[0].gene, "GENEA");
+        assert_eq!(txs[0].strand, Strand::Reverse);
+        assert_eq!(txs[0].exons, vec![(99, 200), (799, 900)]);
+        Ok(())
+    }
+
+    #[test]
+    fn bad_position_test() {
+        let selector = TranscriptSelector::Canonical("GENEA".to_string());
+        for bad in &["\t0\t200\t", "\tx\t200\t"] {
+            let gtf = GTF.replace("\t100\t200\t", bad);
+            let err = read_annotation(gtf.as_bytes(), AnnotationFormat::Gtf, &selector).unwrap_err();
+            assert!(err.to_string().starts_with("Annotation line 6 "), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Batch mapping from a sample sheet, and the combined long and wide (deletions x samples)
+//! tables of counts and proportions over all samples.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use failure::{self, Error};
+use log::info;
+
+use crate::call::CountsTable;
+
+/// A sample's R1 and (for paired end) R2 FASTQ files, one per lane
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleSheetEntry {
+    pub sample: String,
+    pub reads: Vec<PathBuf>,
+    pub reads_pair: Vec<PathBuf>,
+}
+
+/// Split a comma separated list of FASTQ files, e.g. one per lane
+pub fn fastq_list(list: &str, base: &Path) -> Vec<PathBuf> {
+    list.split(',')
+        .map(|f| f.trim())
+        .filter(|f| !f.is_empty())
+        .map(|f| base.join(f))
+        .collect()
+}
+
+/// Read a tab separated sample sheet of `sample, R1 fastq[, R2 fastq]`, where the FASTQ
+/// columns may be comma separated lists of lanes. A header line starting with `sample`
+/// and `#` comments are skipped, and relative paths are taken from the sample sheet's directory.
+pub fn read_samplesheet<P: AsRef<Path>>(path: P) -> Result<Vec<SampleSheetEntry>, Error> {
+    let file = File::open(path.as_ref()).map_err(|e| {
+        failure::err_msg(format!("Could not open sample sheet {}: {}", path.as_ref().display(), e))
+    })?;
+    let base = path.as_ref().parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    parse_samplesheet(BufReader::new(file), &base)
+}
+
+pub fn parse_samplesheet<R: BufRead>(reader: R, base: &Path) -> Result<Vec<SampleSheetEntry>, Error> {
+    let mut entries: Vec<SampleSheetEntry> = Vec::new();
+
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line.trim_end().split('\t').map(|f| f.trim()).collect();
+        if fields[0].is_empty() || fields[0].starts_with('#') || (line_no == 0 && fields[0].eq_ignore_ascii_case("sample")) {
+            continue;
+        }
+        if fields.len() < 2 || fields.len() > 3 {
+            return Err(failure::err_msg(format!(
+                "Sample sheet line {} has {} columns, expected sample, R1 fastq and optionally R2 fastq",
+                line_no + 1,
+                fields.len()
+            )));
+        }
+        // sample names become `<outdir>/<sample>.csv` and columns of the combined CSVs
+        let sample = fields[0];
+        if sample.contains(&['/', '\\', ','][..]) || sample.contains("..") || sample.starts_with('.') {
+            return Err(failure::err_msg(format!(
+                "Sample sheet line {} has sample name {}, which must not start with '.' or contain '/', '\\', '..' or ','",
+                line_no + 1,
+                sample
+            )));
+        }
+        if entries.iter().any(|e| e.sample == fields[0]) {
+            return Err(failure::err_msg(format!(
+                "Sample {} is listed more than once in the sample sheet",
+                fields[0]
+            )));
+        }
+
+        let reads = fastq_list(fields[1], base);
+        let reads_pair = fields.get(2).map_or(Vec::new(), |f| fastq_list(f, base));
+        if !reads_pair.is_empty() && reads_pair.len() != reads.len() {
+            return Err(failure::err_msg(format!(
+                "Sample {} has {} R1 and {} R2 files",
+                fields[0],
+                reads.len(),
+                reads_pair.len()
+            )));
+        }
+
+        entries.push(SampleSheetEntry {
+            sample: fields[0].to_string(),
+            reads,
+            reads_pair,
+        });
+    }
+
+    if entries.is_empty() {
+        return Err(failure::err_msg("Sample sheet lists no samples"));
+    }
+    Ok(entries)
+}
+
+/// Write `combined.long.csv`, and the wide `combined.counts.csv` and
+/// `combined.proportions.csv` tables, from the per sample `tinyt map` results.
+pub fn write_combined<P: AsRef<Path>>(results: &[(String, CountsTable)], outdir: P) -> Result<(), Error> {
+    // (gene, transcript) in order of first appearance, with (count, proportion) per sample
+    let mut rows: Vec<(String, String)> = Vec::new();
+    let mut values: HashMap<(String, String), HashMap<&str, (String, String)>> = HashMap::new();
+
+    let long_path = outdir.as_ref().join("combined.long.csv");
+    let mut long = BufWriter::new(File::create(&long_path)?);
+    writeln!(long, "Sample,Gene,Deletion,Count,Total,Proportion")?;
+
+    for (sample, table) in results {
+        let gene = table.column("Gene")?;
+        let deletion = table.column("Deletion")?;
+        let count = table.column("Count")?;
+        let total = table.column("Total")?;
+        let proportion = table.column("Proportion")?;
+
+        for row in &table.rows {
+            writeln!(
+                long,
+                "{},{},{},{},{},{}",
+                sample, row[gene], row[deletion], row[count], row[total], row[proportion]
+            )?;
+
+            let key = (row[gene].clone(), row[deletion].clone());
+            if !values.contains_key(&key) {
+                rows.push(key.clone());
+            }
+            values
+                .entry(key)
+                .or_insert_with(HashMap::new)
+                .insert(sample.as_str(), (row[count].clone(), row[proportion].clone()));
+        }
+    }
+    long.flush()?;
+
+    let samples: Vec<&str> = results.iter().map(|(sample, _)| sample.as_str()).collect();
+    for (name, pick) in &[("counts", 0), ("proportions", 1)] {
+        let path = outdir.as_ref().join(format!("combined.{}.csv", name));
+        let mut wide = BufWriter::new(File::create(&path)?);
+        writeln!(wide, "Gene,Deletion,{}", samples.join(","))?;
+        for key in &rows {
+            let sample_values: Vec<&str> = samples
+                .iter()
+                .map(|sample| match values[key].get(sample) {
+                    Some(v) if *pick == 0 => v.0.as_str(),
+                    Some(v) => v.1.as_str(),
+                    // transcripts without reads are left out of a sample's results
+                    None => "0",
+                })
+                .collect();
+            writeln!(wide, "{},{},{}", key.0, key.1, sample_values.join(","))?;
+        }
+        wide.flush()?;
+    }
+
+    info!(
+        "Combined results for {} samples written to {}",
+        samples.len(),
+        outdir.as_ref().display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn samplesheet_test() -> Result<(), Error> {
+        let sheet = "sample\tr1\tr2\ns1\ts1_L001_R1.fq,s1_L002_R1.fq\ts1_L001_R2.fq,s1_L002_R2.fq\n# comment\ns2\t/data/s2.fq\n";
+        let entries = parse_samplesheet(sheet.as_bytes(), Path::new("batch"))?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].reads.len(), 2);
+        assert_eq!(entries[0].reads[1], PathBuf::from("batch/s1_L002_R1.fq"));
+        assert_eq!(entries[0].reads_pair[0], PathBuf::from("batch/s1_L001_R2.fq"));
+        assert_eq!(entries[1].reads, vec![PathBuf::from("/data/s2.fq")]);
+        assert!(entries[1].reads_pair.is_empty());
+
+        assert!(parse_samplesheet("s1\ta.fq\ns1\tb.fq\n".as_bytes(), Path::new("")).is_err());
+        assert!(parse_samplesheet("s1\ta.fq,b.fq\tc.fq\n".as_bytes(), Path::new("")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn samplesheet_names_test() {
+        for name in &["../s1", "/tmp/s1", "runs/s1", "runs\\s1", "s1..2", ".s1", "s1,s2"] {
+            let sheet = format!("sample\tr1\ns0\ta.fq\n{}\tb.fq\n", name);
+            let err = parse_samplesheet(sheet.as_bytes(), Path::new("")).expect_err(name);
+            assert!(err.to_string().starts_with("Sample sheet line 3 "), "{}", err);
+        }
+        assert!(parse_samplesheet("s1.v2_A-B\ta.fq\n".as_bytes(), Path::new("")).is_ok());
+    }
+}
@@ -10,20 +10,31 @@ use bio::io::{fasta, fastq};
 use docopt::Docopt;
 use failure::Error;
 use std::{env, fs};
-use std::{path::PathBuf, str};
+use std::{path::{Path, PathBuf}, str};
 use boomphf::Mphf;
 use serde::{Serialize, Deserialize};
 
 use tinyt::{
-    build_index::{build_index,export_wasm_index,WasmRuntimeIndex, WasmIndex,IndexLike},
+    build_index::{build_index,export_wasm_index,IndexParams,WasmRuntimeIndex, WasmIndex,IndexLike, deletion_detectability, write_detectability_report},
+    annotation::{self, TranscriptSelector},
+    index_file::{self, IndexHeader, IndexKind, LoadedIndex, NativeIndex},
+    inspect::{self, TableFormat},
     pseudoaligner,
     // pseudoaligner::process_reads,
-   pseudoaligner::{process_reads, Pseudoaligner},
+   pseudoaligner::{process_reads, MapParams, Pseudoaligner},
+    read_output::{DeletionReads, PseudoAlignments, ReadAssignments, ReadOutputs},
+    report::{OutputFormat, ReportOptions, RunInfo, RunParameters},
+    batch,
+    bootstrap::BootstrapParams,
+    call::{self, CallParams, CountsTable},
+    compare,
+    normals::PanelOfNormals,
+    reference,
 
 };
-use tinyt::{config, utils};
-use crate::config::{LEFT_EXTEND_FRACTION, READ_COVERAGE_THRESHOLD, DEFAULT_ALLOWED_MISMATCHES, TRIM_VAL};
+use tinyt::{config, utils::{self, ReadInput}};
 use debruijn::dna_string::{DnaString, DnaStringSlice};
+use debruijn::Kmer;
 use std::collections::{HashMap, HashSet};
 
 
@@ -34,19 +45,69 @@ const USAGE: &'static str = "
 tinyt
 
 Usage:
-  tinyt index [--num-threads=<n>] [--wasm] --index=<index> <ref-fasta>
-  tinyt map [--num-threads=<n>] [--read-length=<r>] [--trim-size=<t>] [--skip-trim] [--mismatch=<m>] [--output=<file>]  [--wasm]  --index=<index>  <reads-fastq> [<reads-pair-fastq>]
+  tinyt reference [--num-threads=<n>] [--gene=<gene>] [--output=<file>] [--index=<index>] [--kmer-size=<k>] [--min-kmers=<n>] [--wasm] <bed12> <genome-fasta>
+  tinyt reference [--num-threads=<n>] --gtf=<gtf> (--transcript=<id>... | --gene=<gene>) [--output=<file>] [--index=<index>] [--kmer-size=<k>] [--min-kmers=<n>] [--wasm] <genome-fasta>
+  tinyt index [--num-threads=<n>] [--kmer-size=<k>] [--min-kmers=<n>] [--wasm] --index=<index> <ref-fasta>
+  tinyt inspect [--ec-table=<file>] [--ec-format=<fmt>] --index=<index>
+  tinyt map [--num-threads=<n>] [--read-length=<r>] [--trim-size=<t>] [--skip-trim] [--mismatch=<m>] [--min-coverage=<n>] [--min-coverage-fraction=<f>] [--left-extend-fraction=<f>] [--bootstraps=<n>] [--seed=<s>] [--interleaved] [--allow-unequal-pairs] [--cram-reference=<fasta>] [--region=<region>] [--read-assignments=<file>] [--deletion-reads=<dir>] [--max-deletion-reads=<n>] [--pseudo-bam=<file> --transcripts=<fasta>] [--output-format=<fmt>] [--output=<file>] --index=<index>  <reads-fastq> [<reads-pair-fastq>]
+  tinyt map [--num-threads=<n>] [--read-length=<r>] [--trim-size=<t>] [--skip-trim] [--mismatch=<m>] [--min-coverage=<n>] [--min-coverage-fraction=<f>] [--left-extend-fraction=<f>] [--bootstraps=<n>] [--seed=<s>] [--interleaved] [--allow-unequal-pairs] [--read-assignments=<file>] [--deletion-reads=<dir>] [--max-deletion-reads=<n>] [--pseudo-bam=<file> --transcripts=<fasta>] [--output-format=<fmt>] [--output=<file>] --index=<index> (--r1=<fastq>)... [(--r2=<fastq>)...]
+  tinyt map [--num-threads=<n>] [--read-length=<r>] [--trim-size=<t>] [--skip-trim] [--mismatch=<m>] [--min-coverage=<n>] [--min-coverage-fraction=<f>] [--left-extend-fraction=<f>] [--bootstraps=<n>] [--seed=<s>] [--interleaved] [--allow-unequal-pairs] [--cram-reference=<fasta>] [--region=<region>] [--outdir=<dir>] --index=<index> --samplesheet=<sheet>
+  tinyt compare-backends [--wasm-index=<index>] [--mismatch=<m>] [--left-extend-fraction=<f>] [--max-discordant=<n>] [--discordant-reads=<file>] [--output=<file>] --index=<index> <reads-fastq> [<reads-pair-fastq>]
+  tinyt call [--error-rate=<e>] [--min-reads=<n>] [--alpha=<a>] [--dispersion=<rho>] [--count-column=<col>] [--normals=<file>] [--output=<file>] <counts-csv>
+  tinyt normals [--count-column=<col>] --output=<file> <normal-csv>...
   tinyt -h | --help | -v | --version
 
 Options:
   -n --num-threads N  Number of worker threads [default: 2]
-  -w --wasm           Create or read index in WASM compatible format
+  -w --wasm           Also write the index in WASM compatible format, to <index>.wasm.idx
   -i --index INDEX    Index file to write to or read from
+  -k --kmer-size K    k-mer length of a new index, 15 to 31 [default: 20]
+  --min-kmers N       Minimum occurrences of a k-mer in the transcripts to keep it in the index [default: 1]
+  -g --gene GENE      Gene name for reference transcripts, instead of the BED name column.
+                      With --gtf, select the MANE Select or Ensembl canonical transcript of this gene
+  --gtf GTF           Read transcripts from a GTF or GFF3 (.gff3/.gff) annotation instead of BED12
+  --transcript ID     Transcript ID to select from the annotation, version optional (repeatable)
+  --ec-table FILE     Write the equivalence class table of the index to FILE, or - for stdout with the summary on stderr
+  --ec-format FMT     Format of the equivalence class table: csv, tsv or json [default: csv]
   -t --trim-size T    Size of base pairs to trim when checking unique read matches [default: 5] 
   -s --skip-trim      Skip the trim read check for unqiue read matches
   -m --mismatch M     Number of allowed mismatches for per read [default: 2]
+  --min-coverage N    Minimum bases of a read covered by the index for a unique match [default: 32]
+  --min-coverage-fraction F  Minimum fraction of the read length covered for a unique match,
+                      the larger of this and --min-coverage applies [default: 0]
+  --left-extend-fraction F  Extend a read back along the index graph when its first k-mer found
+                      is at least this fraction of the read length in [default: 0.2]
   -r --read-length R  Provide read length for depth estimation
+  -b --bootstraps N   Number of bootstrap resamples for count, proportion and EM count intervals [default: 0]
+  --seed S            Random seed for bootstraps, for reproducible intervals
+  --error-rate E      Background rate of reads supporting an absent deletion [default: 0.001]
+  --min-reads N       Minimum supporting reads for a deletion to be called present [default: 3]
+  --alpha A           P-value threshold for a deletion to be called present [default: 0.01]
+  --dispersion RHO    Use a beta-binomial test with this overdispersion instead of a binomial test
+  --count-column COL  Column of supporting reads to test, e.g. EMCount [default: Count]
+  --normals FILE      Compare each deletion to a panel of normals built by tinyt normals
   -o --output FILE    Output results to file instead of stdout
+  --output-format FMT  Format of the mapping results: csv, or json with run details [default: csv]
+  --read-assignments FILE  Write the mapping of each read or pair to a TSV, gzipped if FILE ends in .gz
+  --deletion-reads DIR  Write the reads mapping uniquely to each deletion to FASTQ files in DIR
+  --max-deletion-reads N  Maximum reads (or pairs) written per deletion [default: 1000]
+  --pseudo-bam FILE   Write mapped reads placed on the index transcripts as SAM, or BAM
+                      if FILE ends in .bam (needs the htslib feature)
+  --transcripts FASTA  Transcriptome FASTA the index was built from, for --pseudo-bam
+  --r1 FASTQ          R1 (or single end) FASTQ, repeated for each lane
+  --r2 FASTQ          R2 FASTQ, repeated for each lane in the same order as --r1
+  --interleaved       Read R1 and R2 as consecutive records of the same FASTQ
+  --allow-unequal-pairs  Warn and stop at the end of the shorter file when R1 and R2
+                      have different numbers of reads, instead of failing
+  --cram-reference FASTA  Reference FASTA for CRAM read input
+  --region REGION     Only map reads aligned to REGION (e.g. chr7:50300000-50410000) and
+                      unmapped reads, from an indexed BAM or CRAM
+  --wasm-index INDEX  WASM index to compare with the native --index, instead of exporting
+                      one from it
+  --max-discordant N  Maximum discordant reads written with --discordant-reads [default: 100]
+  --discordant-reads FILE  Write reads mapping differently with the two indexes to a TSV
+  --samplesheet FILE  Map each sample of a tab separated sheet of sample, R1 fastq and optional R2 fastq
+  --outdir DIR        Directory for per sample and combined results with --samplesheet [default: .]
   -h --help           Show this screen.
   -v --version        Show version.
 ";
@@ -54,6 +115,11 @@ Options:
 #[derive(Clone, Debug, Deserialize)]
 struct Args {
     arg_ref_fasta: String,
+    arg_bed12: String,
+    arg_genome_fasta: String,
+    flag_gene: Option<String>,
+    flag_gtf: Option<String>,
+    flag_transcript: Vec<String>,
     flag_index: String,
     arg_reads_fastq: String,
     arg_reads_pair_fastq: String,
@@ -61,15 +127,58 @@ struct Args {
     flag_num_threads: usize,
 
     flag_wasm: bool,
+    flag_kmer_size: usize,
+    flag_min_kmers: usize,
 
+    cmd_reference: bool,
     cmd_index: bool,
 
+    cmd_inspect: bool,
+    flag_ec_table: Option<String>,
+    flag_ec_format: String,
+
     cmd_map: bool,
     flag_trim_size: usize,
     flag_mismatch: usize,
+    flag_min_coverage: usize,
+    flag_min_coverage_fraction: f64,
+    flag_left_extend_fraction: f64,
     flag_skip_trim: bool,
     flag_read_length: Option<usize>,
-
+    flag_bootstraps: usize,
+    flag_samplesheet: Option<String>,
+    flag_r1: Vec<String>,
+    flag_r2: Vec<String>,
+    flag_interleaved: bool,
+    flag_allow_unequal_pairs: bool,
+    flag_cram_reference: Option<String>,
+    flag_region: Option<String>,
+    flag_read_assignments: Option<String>,
+    flag_deletion_reads: Option<String>,
+    flag_max_deletion_reads: usize,
+    flag_pseudo_bam: Option<String>,
+    flag_transcripts: Option<String>,
+    flag_output_format: String,
+    flag_outdir: String,
+    flag_seed: Option<u64>,
+
+
+    cmd_compare_backends: bool,
+    flag_wasm_index: Option<String>,
+    flag_max_discordant: usize,
+    flag_discordant_reads: Option<String>,
+
+    cmd_call: bool,
+    arg_counts_csv: String,
+    flag_error_rate: f64,
+    flag_min_reads: u64,
+    flag_alpha: f64,
+    flag_dispersion: Option<f64>,
+    flag_count_column: String,
+    flag_normals: Option<String>,
+
+    cmd_normals: bool,
+    arg_normal_csv: Vec<String>,
 
     flag_version: bool,
     flag_v: bool,
@@ -113,281 +222,501 @@ if args.flag_trim_size <= args.flag_mismatch {
 
 
 
-    if args.cmd_index {
-        info!("Building index from fasta: {}",&args.arg_ref_fasta);
-        let fasta = fasta::Reader::from_file(&args.arg_ref_fasta)?;
-        let (seqs, tx_names, tx_gene_map, gene_length_map) = utils::read_transcripts(fasta)?;
-                    info!("Building native index");
-
-                    let index =             build_index::<config::KmerType>(&seqs, &tx_names, &tx_gene_map,&gene_length_map,  args.flag_num_threads)?;
-
-
-        if args.flag_wasm {    
-        let wasm_idx = export_wasm_index::<config::KmerType>(&index);
-        let wasm_path = format!("{}.wasm.idx", &args.flag_index);
-        utils::write_obj(&wasm_idx, &wasm_path)?;
-        info!("WASM index written to {}", wasm_path);
-              
-        } 
-      
-
-       
-        info!("Finished building index!");
+    if args.cmd_reference {
+        let canonical = match &args.flag_gtf {
+            Some(gtf) => {
+                let selector = if args.flag_transcript.is_empty() {
+                    TranscriptSelector::Canonical(args.flag_gene.clone().unwrap_or_default())
+                } else {
+                    TranscriptSelector::Ids(
+                        args.flag_transcript.iter().flat_map(|t| t.split(',')).map(|t| t.to_string()).collect(),
+                    )
+                };
+                annotation::read_annotation_file(gtf, &selector)?
+            }
+            None => {
+                info!("Reading canonical transcripts from BED12: {}", &args.arg_bed12);
+                reference::read_bed12(&args.arg_bed12, args.flag_gene.as_deref())?
+            }
+        };
 
-        info!("Writing index to disk");
-        utils::write_obj(&index, &args.flag_index)?;
-        info!("Finished writing index!");
+        info!("Reading indexed genome: {}", &args.arg_genome_fasta);
+        let mut genome = fasta::IndexedReader::from_file(&args.arg_genome_fasta)
+            .map_err(|e| failure::err_msg(format!("Could not open indexed genome {}: {}", &args.arg_genome_fasta, e)))?;
+        let transcripts = reference::build_reference(&canonical, &mut genome)?;
 
-        info!("Total equivalence classes: {}", index.dbg.len() );
+        match &args.flag_output {
+            Some(filename) => {
+                info!("Writing {} transcripts to {}", transcripts.len(), filename);
+                reference::write_fasta(&transcripts, fs::File::create(filename)?)?;
+            }
+            None => {
+                if args.flag_index.is_empty() {
+                    reference::write_fasta(&transcripts, std::io::stdout())?;
+                }
+            }
+        }
 
-        use debruijn::Mer;
-        use std::fs::File;
-        use std::io::Write;
+        if !args.flag_index.is_empty() {
+            let (seqs, tx_names, tx_gene_map, gene_length_map) = reference::to_index_input(&transcripts);
+            let mut fasta_bytes = Vec::new();
+            reference::write_fasta(&transcripts, &mut fasta_bytes)?;
+            config::check_kmer_size(args.flag_kmer_size)?;
+            let index_params = index_params(&args)?;
+            info!("Building native index with k={} and {:?}", args.flag_kmer_size, index_params);
+            tinyt::with_kmer_type!(args.flag_kmer_size, K => {
+                let index = build_index::<K>(&seqs, &tx_names, &tx_gene_map, &gene_length_map, args.flag_num_threads, &index_params)?;
+                write_index(&index, &seqs, &args, &index_params, Some(index_file::checksum(&fasta_bytes)))?;
+            });
+        }
 
+    } else if args.cmd_index {
+        info!("Building index from fasta: {}",&args.arg_ref_fasta);
+        let fasta = fasta::Reader::from_file(&args.arg_ref_fasta)?;
+        let (seqs, tx_names, tx_gene_map, gene_length_map) = utils::read_transcripts(fasta)?;
+        config::check_kmer_size(args.flag_kmer_size)?;
+        let index_params = index_params(&args)?;
+        info!("Building native index with k={} and {:?}", args.flag_kmer_size, index_params);
+        tinyt::with_kmer_type!(args.flag_kmer_size, K => {
+            let index = build_index::<K>(&seqs, &tx_names, &tx_gene_map, &gene_length_map, args.flag_num_threads, &index_params)?;
+            write_index(&index, &seqs, &args, &index_params, Some(index_file::file_checksum(&args.arg_ref_fasta)?))?;
+        });
+
+    } else if args.cmd_inspect {
+        let (header, loaded_index) = index_file::read_index(&args.flag_index)?;
+        // keep the summary out of a table written to stdout
+        if args.flag_ec_table.as_deref() == Some("-") {
+            print_inspect_report(&args.flag_index, &header, &loaded_index, std::io::stderr())?;
+        } else {
+            print_inspect_report(&args.flag_index, &header, &loaded_index, std::io::stdout())?;
+        }
 
-        // output index statistics to "args.arg_index.index.ec.csv"
-        let mut w = File::create(format!("{}.ec.csv", &args.flag_index)).unwrap();
-        let mut unique_ec = 0;
-            //println!("EC,SeqLength,TranscriptCount,TranscriptNames");
-            writeln!(&mut w,"EC,SeqLength,TranscriptCount,TranscriptNames").unwrap();
-        for e in index.dbg.iter_nodes() {
-            let eqid = e.data();
-            let eq = &index.eq_classes[*eqid as usize];
-            if eq.len() == 1 {
-		unique_ec += 1;
-            writeln!(&mut w,"EC{},{},{},{:?}", e.node_id, e.sequence().len(), eq.len(), index.tx_names[eq[0] as usize]).unwrap();
-	    } else {
-
-            //let nameslist = eq.iter().map(|x| &index.tx_names[*x as usize]).collect().join(",");
-            let mut nameslist = String::new();
-            for n in eq {
-
-               //println!("{}",index.tx_names[*n as usize]);
-            if !nameslist.is_empty() {
-            nameslist.push(',');
+        if let Some(table_path) = &args.flag_ec_table {
+            let format = TableFormat::from_name(&args.flag_ec_format)?;
+            let rows = inspect::ec_table(&loaded_index);
+            if table_path == "-" {
+                inspect::write_ec_table(&rows, format, std::io::stdout())?;
+            } else {
+                inspect::write_ec_table(&rows, format, fs::File::create(table_path)?)?;
+                info!("Equivalence class table written to {}", table_path);
             }
-             nameslist.push_str(&index.tx_names[*n as usize]);
-
+        }
 
-           //    &nameslist.append(index.tx_names[*n as usize]);
+    } else if args.cmd_call {
+        let params = CallParams {
+            error_rate: args.flag_error_rate,
+            min_reads: args.flag_min_reads,
+            alpha: args.flag_alpha,
+            dispersion: args.flag_dispersion,
+        };
+        info!("Calling deletions in {} with {:?}", &args.arg_counts_csv, params);
+
+        let table = CountsTable::from_file(&args.arg_counts_csv)?;
+        let calls: Vec<_> = table
+            .deletion_counts(&args.flag_count_column)?
+            .into_iter()
+            .map(|count| {
+                let deletion_call = call::call_deletion(count.count, count.total, &params);
+                (count, deletion_call)
+            })
+            .collect();
+        info!(
+            "{} of {} deletions called present",
+            calls.iter().filter(|(_, c)| c.call == call::Call::Present).count(),
+            calls.len()
+        );
+
+        let normals = match &args.flag_normals {
+            Some(path) => {
+                let panel = PanelOfNormals::read(path)?;
+                info!("Read panel of {} normals from {}", panel.samples.len(), path);
+                if panel.count_column != args.flag_count_column {
+                    warn!(
+                        "Panel of normals was built from {}, calls use {}",
+                        panel.count_column, args.flag_count_column
+                    );
+                }
+                Some(panel)
             }
+            None => None,
+        };
 
-            writeln!(&mut w,"EC{},{},{},{:?}", e.node_id, e.sequence().len(), eq.len(),nameslist).unwrap();
-            //debug!("EC{}\t{}\t{}\t{:?}", e.node_id, e.sequence().len(), eq.len(),nameslist);
+        match &args.flag_output {
+            Some(filename) => call::write_calls(&table, &calls, normals.as_ref(), fs::File::create(filename)?)?,
+            None => call::write_calls(&table, &calls, normals.as_ref(), std::io::stdout())?,
+        }
+
+    } else if args.cmd_normals {
+        let mut tables = Vec::new();
+        for path in &args.arg_normal_csv {
+            let sample = std::path::Path::new(path)
+                .file_stem()
+                .map_or(path.clone(), |stem| stem.to_string_lossy().to_string());
+            tables.push((sample, CountsTable::from_file(path)?));
+        }
 
+        let panel = PanelOfNormals::build(&tables, &args.flag_count_column)?;
+        // --output is required for this subcommand
+        let filename = args.flag_output.as_ref().unwrap();
+        panel.write(filename)?;
+        info!(
+            "Panel of {} normals for {} deletions written to {}",
+            panel.samples.len(),
+            panel.deletions.len(),
+            filename
+        );
+
+    } else if args.cmd_compare_backends {
+        info!("Reading native index from disk");
+        let native = match index_file::read_index(&args.flag_index)?.1 {
+            LoadedIndex::Native(index) => index,
+            LoadedIndex::Wasm(_) => {
+                return Err(failure::err_msg(format!("{} is a WASM index, --index needs a native index", &args.flag_index)))
             }
+        };
+        let wasm_index = match &args.flag_wasm_index {
+            Some(path) => match index_file::read_index(path)?.1 {
+                LoadedIndex::Wasm(index) => index,
+                LoadedIndex::Native(_) => {
+                    return Err(failure::err_msg(format!("{} is a native index, --wasm-index needs a WASM index", path)))
+                }
+            },
+            None => {
+                info!("No --wasm-index given, exporting one from the native index");
+                native.export_wasm()
+            }
+        };
+        let wasm = WasmRuntimeIndex::from_wasm_index(wasm_index)?;
 
-        
+        let mut read_files = vec![PathBuf::from(&args.arg_reads_fastq)];
+        if !args.arg_reads_pair_fastq.is_empty() {
+            read_files.push(PathBuf::from(&args.arg_reads_pair_fastq));
         }
+        info!("Comparing native and WASM mapping of reads from: {:?}", read_files);
+        let reads = utils::read_records(&read_files)?;
+        let concordance = compare::compare_backends(native.as_index_like(), &wasm, reads, &map_params(&args)?, args.flag_max_discordant)?;
 
-            info!("Unique equivalence classes: {}", unique_ec);
+        match &args.flag_output {
+            Some(filename) => concordance.write_matrix(&mut fs::File::create(filename)?)?,
+            None => concordance.write_matrix(&mut std::io::stdout())?,
+        }
+        if let Some(path) = &args.flag_discordant_reads {
+            concordance.write_discordant(native.tx_names(), &mut fs::File::create(path)?)?;
+            info!("{} discordant reads written to {}", concordance.examples.len(), path);
+        }
 
+        // a non-zero exit fails a release check
+        if concordance.discordant > 0 {
+            return Err(failure::err_msg(format!(
+                "{} of {} read orientations map differently with the native and WASM indexes",
+                concordance.discordant, concordance.queries
+            )));
+        }
+        info!("Native and WASM indexes agree on all {} read orientations", concordance.queries);
 
     } else if args.cmd_map {
         info!("Reading index from disk");
-        if args.flag_wasm  {
-            info!("Reading WASM compatible index");
-        } else {
-            info!("Reading native index");
-        }
-        // let index =if args.flag_wasm  {
-        //     let wasm_idx = utils::read_obj(args.flag_index)?;
-        //     WasmRuntimeIndex::from_wasm_index(wasm_idx)
-        // } else {
-        //     utils::read_obj(args.flag_index)?
-            
-        // };
-
-
-         let index_box: Box<dyn IndexLike> = if args.flag_wasm {
-            // read the compact WasmIndex (must implement Deserialize)
-            let wasm_idx: WasmIndex = utils::read_obj(&args.flag_index)?;
-            Box::new(WasmRuntimeIndex::from_wasm_index(wasm_idx))
+        let (header, loaded_index) = index_file::read_index(&args.flag_index)?;
+        let is_wasm = loaded_index.kind() == IndexKind::Wasm;
+
+        let index_box: Box<dyn IndexLike> = loaded_index.into_index_like()?;
+        //
+        info!("Finished reading index!");
+
+
+        // without --seed, draw one and log it so that the intervals can be reproduced
+        let bootstrap = if args.flag_bootstraps > 0 {
+            let seed = args.flag_seed.unwrap_or_else(rand::random);
+            info!("Bootstrap seed: {}", seed);
+            Some(BootstrapParams { bootstraps: args.flag_bootstraps, seed })
         } else {
-            // read the full native Pseudoaligner (concrete type)
-             let native_idx: Pseudoaligner<config::KmerType> = utils::read_obj(&args.flag_index)?;
-                    // let native_idx  = utils::read_obj(&args.flag_index)?;
+            None
+        };
 
-            Box::new(native_idx)
+        let report_options = ReportOptions {
+            format: OutputFormat::from_name(&args.flag_output_format)?,
+            run: RunInfo {
+                tinyt_version: PKG_VERSION.to_string(),
+                index: args.flag_index.clone(),
+                index_header: header,
+                parameters: RunParameters {
+                    interleaved: args.flag_interleaved,
+                    num_threads: args.flag_num_threads,
+                    trim: !args.flag_skip_trim,
+                    trim_size: args.flag_trim_size,
+                    mapping: map_params(&args)?,
+                    read_length: args.flag_read_length,
+                    bootstraps: args.flag_bootstraps,
+                    seed: bootstrap.map(|params| params.seed),
+                    ..RunParameters::default()
+                },
+            },
         };
 
-        // let index = index_box;
+        match &args.flag_samplesheet {
+            Some(sheet) => {
+                let samples = batch::read_samplesheet(sheet)?;
+                let outdir = PathBuf::from(&args.flag_outdir);
+                fs::create_dir_all(&outdir)?;
+                info!("Mapping {} samples from {}, index loaded once", samples.len(), sheet);
+
+                // check every sample before mapping any, so a rerun does not fail part way through
+                let outputs: Vec<PathBuf> = samples
+                    .iter()
+                    .map(|entry| outdir.join(format!("{}.csv", entry.sample)))
+                    .collect();
+                let existing: Vec<String> = outputs
+                    .iter()
+                    .filter(|output| output.exists())
+                    .map(|output| output.display().to_string())
+                    .collect();
+                if !existing.is_empty() {
+                    return Err(failure::err_msg(format!("Outputs already exist: {}", existing.join(", "))));
+                }
 
+                let mut results = Vec::new();
+                for (entry, output) in samples.iter().zip(outputs) {
+                    info!("Sample {}", entry.sample);
+                    map_sample(
+                        &*index_box,
+                        &args,
+                        &entry.reads,
+                        &entry.reads_pair,
+                        Some(output.to_string_lossy().to_string()),
+                        is_wasm,
+                        bootstrap,
+                        &report_options,
+                    )?;
+                    results.push((entry.sample.clone(), CountsTable::from_file(&output)?));
+                }
 
-        // debug
-    use std::cmp::min;
-    // If we loaded a WasmRuntimeIndex, do extra diagnostics
-    if args.flag_wasm {
-        let wasm_idx: WasmIndex = utils::read_obj(&args.flag_index)?;
-         let wasm_rt = WasmRuntimeIndex::from_wasm_index(wasm_idx);
+                batch::write_combined(&results, &outdir)?;
+            }
+            None => {
+                // comma separated lists of lanes, or repeated --r1 and --r2
+                let (reads, reads_pair) = if args.flag_r1.is_empty() {
+                    (
+                        batch::fastq_list(&args.arg_reads_fastq, Path::new("")),
+                        batch::fastq_list(&args.arg_reads_pair_fastq, Path::new("")),
+                    )
+                } else {
+                    (
+                        args.flag_r1.iter().map(PathBuf::from).collect(),
+                        args.flag_r2.iter().map(PathBuf::from).collect(),
+                    )
+                };
+                map_sample(
+                    &*index_box,
+                    &args,
+                    &reads,
+                    &reads_pair,
+                    args.flag_output.clone(),
+                    is_wasm,
+                    bootstrap,
+                    &report_options,
+                )?;
+            }
+        }
 
+    }
 
-        info!("WASM index k = {}", wasm_rt.k);
-        info!("WASM kmers (flat) count = {}", wasm_rt.lookup.len());
-        info!("WASM eq_classes count = {}", wasm_rt.eq_classes.len());
+    info!("Done!");
+    Ok(())
+}
 
-        // print a few entries from the lookup
-        for (i, (kmer, (node, off))) in wasm_rt.lookup.iter().take(8).enumerate() {
-            // info!("sample lookup #{} kmer=0x{:x} node={} off={}", i, kmer, node, off);
-        }
 
-        // quick test: open reads and test first record's kmers
-        if let Ok(mut reader) = bio::io::fastq::Reader::from_file(&args.arg_reads_fastq) {
-            if let Some(Ok(rec)) = reader.records().next() {
-                let seq = tinyt::utils::dna_from_fastq_record(&rec); // helper below if you don't have one
-                let kmers = tinyt::utils::kmers_to_u64_vec(&seq, wasm_rt.k as usize);
-                // info!("First read length {} produced {} kmers (k={})", seq.len(), kmers.len(), wasm_rt.k);
-                // for (i, kmer) in kmers.iter().take(min(12, kmers.len())).enumerate() {
-                //     match wasm_rt.lookup.get(kmer) {
-                //         Some((node_id, off)) => info!("read kmer[{}]=0x{:x} FOUND -> node {} off {}", i, kmer, node_id, off),
-                //         None => info!("read kmer[{}]=0x{:x} MISS", i, kmer),
-                //     }
-                // }
-            }
+/// Graph construction settings of `tinyt index` and `tinyt reference`
+fn index_params(args: &Args) -> Result<IndexParams, Error> {
+    if args.flag_min_kmers == 0 {
+        return Err(failure::err_msg("--min-kmers must be at least 1"));
+    }
+    Ok(IndexParams {
+        min_kmers: args.flag_min_kmers,
+    })
+}
+
+/// Mapping thresholds of `tinyt map` and `tinyt compare-backends`
+fn map_params(args: &Args) -> Result<MapParams, Error> {
+    let params = MapParams {
+        mismatches: args.flag_mismatch,
+        min_coverage: args.flag_min_coverage,
+        min_coverage_fraction: args.flag_min_coverage_fraction,
+        left_extend_fraction: args.flag_left_extend_fraction,
+    };
+    params.validate()?;
+    Ok(params)
+}
+
+/// Map one sample's single or paired end reads with an already loaded index. Each end
+/// may be split over several files, e.g. lanes, which are read in order as one sample.
+fn map_sample(
+    index: &dyn IndexLike,
+    args: &Args,
+    reads_fastq: &[PathBuf],
+    reads_pair_fastq: &[PathBuf],
+    output: Option<String>,
+    is_wasm: bool,
+    bootstrap: Option<BootstrapParams>,
+    report_options: &ReportOptions,
+) -> Result<(), Error> {
+    if !reads_pair_fastq.is_empty() && reads_pair_fastq.len() != reads_fastq.len() {
+        return Err(failure::err_msg(format!(
+            "{} R1 files but {} R2 files given, each R1 file needs its R2 file",
+            reads_fastq.len(),
+            reads_pair_fastq.len()
+        )));
+    }
+    let stdin_count = reads_fastq.iter().chain(reads_pair_fastq).filter(|p| p.as_path() == Path::new("-")).count();
+    if stdin_count > 1 {
+        return Err(failure::err_msg("Only one of the read files can be read from stdin"));
+    }
+
+    let read_input = if reads_fastq.iter().any(utils::is_alignment_file) {
+        if reads_fastq.len() > 1 || !reads_pair_fastq.is_empty() || args.flag_interleaved {
+            return Err(failure::err_msg(
+                "SAM/BAM/CRAM input is a single file, with mates paired from the file itself",
+            ));
         }
+        alignment_input(&reads_fastq[0], args)?
     } else {
-        info!("Loaded native index ");
-       let index: Pseudoaligner<config::KmerType> = utils::read_obj(args.flag_index.clone())?;
- info!("Native index: tx count = {}", index.tx_names.len());
-     info!("Native index: eq_classes count = {}", index.eq_classes.len());
-       // dbg.len() returns number of nodes/entries in DBG
-      info!("Native index: dbg size = {}", index.dbg.len());
-        // Probe first read (if available) and run a single map_read to see hits/misses
-        if let Ok(mut reader) = fastq::Reader::from_file(&args.arg_reads_fastq) {
-            if let Some(Ok(rec)) = reader.records().next() {
-                let seq_str = std::str::from_utf8(rec.seq()).unwrap_or("");
-               let seq = DnaString::from_dna_string(seq_str);
-                let kmers = utils::kmers_to_u64_vec(&seq, /* use index k (if exposed) fallback to 20 */ 20);
-                info!("First read length {} produced {} kmers (probe)", seq.len(), kmers.len());
-
-                // call the native Pseudoaligner map_read (uses concrete type)
-                match index.map_read(&seq, args.flag_mismatch) {
-                    Some((eq, cov, mm, rl)) => {
-                        info!("map_read probe -> eq.len={} cov={} mm={} readlen={}", eq.len(), cov, mm, rl);
-                        if !eq.is_empty() {
-                            let name = &index.tx_names[eq[0] as usize];
-                            info!("map_read probe -> first tx hit = {}", name);
-                        }
-                    }
-                   None => info!("map_read probe -> no hit"),
-                }
+        if args.flag_region.is_some() || args.flag_cram_reference.is_some() {
+            warn!("--region and --cram-reference only apply to SAM/BAM/CRAM input");
+        }
+
+        info!("Mapping reads from: {:?}", reads_fastq);
+        let reads = utils::read_records(reads_fastq)?;
+        if args.flag_interleaved {
+            if !reads_pair_fastq.is_empty() {
+                return Err(failure::err_msg("R2 files cannot be given with --interleaved"));
             }
+            info!("Interleaved paired end reads provided");
+            ReadInput::Paired(utils::interleaved_pairs(reads))
+        } else if reads_pair_fastq.is_empty() {
+            info!("Single end reads provided");
+            ReadInput::Single(reads)
+        } else {
+            info!("Paired end reads provided: {:?}", reads_pair_fastq);
+            let reads_pair = utils::read_records(reads_pair_fastq)?;
+            ReadInput::Paired(utils::paired_records(reads, reads_pair, args.flag_allow_unequal_pairs))
         }
+    };
+
+    let mut outputs = ReadOutputs::default();
+    if let Some(path) = &args.flag_read_assignments {
+        outputs.assignments = Some(ReadAssignments::create(path)?);
+    }
+    if let Some(dir) = &args.flag_deletion_reads {
+        outputs.deletion_reads = Some(DeletionReads::create(dir, args.flag_max_deletion_reads)?);
+    }
+    if let (Some(path), Some(transcripts)) = (&args.flag_pseudo_bam, &args.flag_transcripts) {
+        outputs.alignments = Some(PseudoAlignments::create(path, transcripts, index)?);
     }
-        //
-        info!("Finished reading index!");
 
+    let mut report_options = report_options.clone();
+    report_options.run.parameters.reads = reads_fastq.iter().map(|p| p.display().to_string()).collect();
+    report_options.run.parameters.reads_pair = reads_pair_fastq.iter().map(|p| p.display().to_string()).collect();
 
-        // --- diagnostic: load both native and wasm index for side-by-side comparison ---
-        // let native_idx: Pseudoaligner<config::KmerType> = utils::read_obj("compiletest.idx")?;
-        // let wasm_path = format!("{}.wasm.idx", &args.flag_index);
-        // let wasm_rt = if std::path::Path::new(&wasm_path).exists() {
-        //     let wasm_idx: WasmIndex = utils::read_obj(&wasm_path)?;
-        //     WasmRuntimeIndex::from_wasm_index(wasm_idx)
-        // } else {
-        //     // no wasm index available - build one from native for diagnostics
-        //     let wasm_idx = tinyt::build_index::export_wasm_index::<config::KmerType>(&native_idx);
-        //     WasmRuntimeIndex::from_wasm_index(wasm_idx)
-        // };
-// {
-//     use debruijn::dna_string::DnaString;
-//     use bio::io::fastq;
-//     use std::cmp::min;
-
-//     let mut reader = fastq::Reader::from_file(&args.arg_reads_fastq)?;
-//     info!("Cross-debug: scanning for native-none/wasm-some and native-multi/wasm-unique (up to 200 examples)");
-
-//     let mut printed_none_wasm_some = 0usize;
-//     let mut printed_native_multi_wasm_unique = 0usize;
-//     let mut total_reads = 0usize;
-//     let mut examples_limit = 500usize;
-
-//     for rec in reader.records() {
-//         if printed_none_wasm_some + printed_native_multi_wasm_unique >= examples_limit { break; }
-//         let rec = rec?;
-//         total_reads += 1;
-//         let id = rec.id().to_string();
-//         let s = std::str::from_utf8(rec.seq()).unwrap_or("").to_string();
-//         let seq = DnaString::from_dna_string(&s);
-
-//         let native_opt = native_idx.map_read(&seq, args.flag_mismatch);
-//         // info!("Cross-debug: read id={} native map_read result: {:?}", id, native_opt);
-//         let wasm_opt = wasm_rt.map_read(&seq, args.flag_mismatch);
-//         // info!("Cross-debug: read id={} wasm map_read result: {:?}", id, wasm_opt);
-//         // Case A: native none, wasm some
-//         if native_opt.is_none() && wasm_opt.is_some() && printed_none_wasm_some < 100 {
-//             printed_none_wasm_some += 1;
-//             let (weq, wcov, wmm, wrl) = wasm_opt.as_ref().unwrap();
-//             // let (ne, ncov, nmm, nrl) = native_opt.as_ref().unwrap();
-//             // info!("NATIVE-NONE n_eq_len={} n_cov={} n_mm={} n_rl={} / WASM-SOME id={} wasm_eq_len={} wasm_cov={} wasm_mm={} wasm_rl={}",
-//                 // id, ne.len(), ncov, nmm, nrl,weq.len(), wcov, wmm, wrl);
-
-//                 //   info!("CASE A NATIVE-NONE / WASM-SOME id={} wasm_eq_len={} wasm_cov={} wasm_mm={} wasm_rl={}",
-//                 // id, weq.len(), wcov, wmm, wrl);
-
-
-//             let k = wasm_rt.k as usize;
-//             let kmers = utils::kmers_to_u64_vec(&seq, k);
-//             let total_hits = kmers.iter().filter(|k| wasm_rt.lookup.contains_key(k)).count();
-//             info!(" kmers={} total_hits={}", kmers.len(), total_hits);
-
-//             let wasm_names: Vec<_> = weq.iter().filter_map(|&t| wasm_rt.tx_names.get(t as usize)).take(32).cloned().collect();
-//             info!(" wasm tx_names (first 32) = {:?}", wasm_names);
-//         }
-
-//         // Case B: native unique , wasm unique
-//         if let Some((ne, ncov, nmm, nrl)) = native_opt.as_ref() {
-//             if ne.len() == 1 {
-//                 if let Some((weq, wcov, wmm, wrl)) = wasm_opt.as_ref() {
-//                     if weq.len() == 1  {
-//                         printed_native_multi_wasm_unique += 1;
-//                         info!("CASE B - NATIVE-unqiue / WASM-UNIQUE id={} native_eq_len={} wasm_eq_len={} native_cov={} wasm_cov={}",
-//                             id, ne.len(), weq.len(), ncov, wcov);
-//                         let k = wasm_rt.k as usize;
-//                         let kmers = utils::kmers_to_u64_vec(&seq, k);
-//                         let total_hits = kmers.iter().filter(|k| wasm_rt.lookup.contains_key(k)).count();
-//                         info!("CASE B kmers={} total_hits={}", kmers.len(), total_hits);
-
-//                         info!("CASE B native nmm{:?},nrl{:?}, wasm wmm {:?}, wrl, {:?}", nmm, nrl, wmm, wrl);
-
-
-
-
-//                         let native_names: Vec<_> = ne.iter().filter_map(|&t| native_idx.tx_names.get(t as usize)).take(32).cloned().collect();
-//                         let wasm_names: Vec<_> = weq.iter().filter_map(|&t| wasm_rt.tx_names.get(t as usize)).take(32).cloned().collect();
-//                         info!("CASE B native tx_names (first 32) = {:?}", native_names);
-//                         info!("CASE B wasm tx_names (first 32)   = {:?}", wasm_names);
-//                     } else {
-//                         info!("hello");
-//                         // wasm multi - skip
-//                            info!("CASE C - NATIVE-UNIQUE   / WASM-UNIQUE id={} native_eq_len={} wasm_eq_len={} native_cov={} wasm_cov={}",
-//                             id, ne.len(), weq.len(), ncov, wcov);
-//                 }
-//                 } 
-//                     // wasm none
-//             }
-//         }
-//     }
-
-//     info!("Cross-debug summary: total_reads={} native-none/wasm-some_printed={} native-multi/wasm-unique_printed={}",
-//         total_reads, printed_none_wasm_some, printed_native_multi_wasm_unique);
-// }
-        // --- end diagnostics ---
-
-        info!("Mapping reads from fastq");
-        let reads = fastq::Reader::from_file(args.arg_reads_fastq)?;
-    if args.arg_reads_pair_fastq == ""  {
-        info!("Single end reads provided");
-        process_reads::<config::KmerType>(reads,None, &*index_box, args.flag_output, args.flag_num_threads,!args.flag_skip_trim,args.flag_trim_size,args.flag_mismatch,args.flag_read_length,args.flag_wasm)?;
-    } else {
-        info!("Paired end reads provided");
-        let reads_pair = fastq::Reader::from_file(args.arg_reads_pair_fastq)?;
-        process_reads::<config::KmerType>(reads,Some(reads_pair), &*index_box, args.flag_output, args.flag_num_threads,!args.flag_skip_trim,args.flag_trim_size,args.flag_mismatch,args.flag_read_length,args.flag_wasm)?;
-    }
+    process_reads(read_input, index, output, args.flag_num_threads,!args.flag_skip_trim,args.flag_trim_size,&report_options.run.parameters.mapping,args.flag_read_length,is_wasm,bootstrap,outputs,&report_options)
+}
 
 
-    }
+#[cfg(feature = "htslib")]
+fn alignment_input(path: &Path, args: &Args) -> Result<ReadInput, Error> {
+    info!("Mapping reads from {}", path.display());
+    tinyt::alignment::alignment_reads(
+        path,
+        args.flag_cram_reference.as_ref().map(Path::new),
+        args.flag_region.as_deref(),
+    )
+}
 
-    info!("Done!");
+#[cfg(not(feature = "htslib"))]
+fn alignment_input(path: &Path, _args: &Args) -> Result<ReadInput, Error> {
+    Err(failure::err_msg(format!(
+        "Cannot read {}: SAM/BAM/CRAM input needs tinyt built with --features htslib",
+        path.display()
+    )))
+}
+
+
+/// Write a native index (and optionally its WASM export) plus the `{index}.ec.csv` summary
+/// and `{index}.detect.csv` deletion detectability report.
+/// `index_params` and `fasta_checksum`, the checksum of the transcriptome FASTA, are recorded in the index header.
+fn write_index<K: Kmer + Sync + Send>(index: &Pseudoaligner<K>, seqs: &[DnaString], args: &Args, index_params: &IndexParams, fasta_checksum: Option<u32>) -> Result<(), Error> {
+
+        if args.flag_wasm {    
+        let wasm_idx = export_wasm_index(index);
+        let wasm_path = format!("{}.wasm.idx", &args.flag_index);
+        index_file::write_index(&wasm_idx, IndexHeader::new(IndexKind::Wasm, wasm_idx.k as usize, fasta_checksum).with_index_params(index_params), &wasm_path)?;
+        info!("WASM index written to {}", wasm_path);
+              
+        } 
+      
+
+       
+        info!("Finished building index!");
+
+        info!("Writing index to disk");
+        index_file::write_index(index, IndexHeader::new(IndexKind::Native, K::k(), fasta_checksum).with_index_params(index_params), &args.flag_index)?;
+        info!("Finished writing index!");
+
+        info!("Total equivalence classes: {}", index.dbg.len() );
+
+        // output index statistics to "args.arg_index.index.ec.csv"
+        let ec_rows = inspect::native_ec_table(index);
+        inspect::write_ec_table(&ec_rows, TableFormat::Csv, fs::File::create(format!("{}.ec.csv", &args.flag_index))?)?;
+        info!("Unique equivalence classes: {}", ec_rows.iter().filter(|r| r.transcripts.len() == 1).count());
+
+        let detect_path = format!("{}.detect.csv", &args.flag_index);
+        let report = deletion_detectability(seqs, index);
+        write_detectability_report(&report, fs::File::create(&detect_path)?)?;
+        info!("Deletion detectability report written to {}", detect_path);
+
+    Ok(())
+}
+
+
+/// Write index provenance and statistics for `tinyt inspect`
+fn print_inspect_report<W: Write>(path: &str, header: &Option<IndexHeader>, index: &LoadedIndex, mut out: W) -> Result<(), Error> {
+    let summary = inspect::summarise(index);
+
+    writeln!(out, "Index: {}", path)?;
+    writeln!(out, "Kind: {}", index.kind())?;
+    match header {
+        Some(h) => {
+            writeln!(out, "Format version: {}", h.format_version)?;
+            writeln!(out, "Built by: tinyt {}", h.tinyt_version)?;
+            writeln!(out, "Stranded: {}", h.stranded)?;
+            writeln!(out, "Min kmers: {}", h.min_kmers)?;
+            match h.fasta_checksum {
+                Some(crc) => writeln!(out, "Source FASTA CRC32: {:08x}", crc)?,
+                None => writeln!(out, "Source FASTA CRC32: unknown")?,
+            }
+        }
+        None => writeln!(out, "Format version: legacy (no header)")?,
+    }
+    writeln!(out, "k: {}", summary.k)?;
+    writeln!(out, "Transcripts: {}", summary.transcripts)?;
+    writeln!(out, "Genes: {}", summary.genes)?;
+    writeln!(out, "Nodes: {}", summary.nodes)?;
+    writeln!(out, "Kmers: {}", summary.kmers)?;
+    writeln!(
+        out,
+        "Equivalence classes: {} ({} unique, {} shared)",
+        summary.eq_classes,
+        summary.unique_eq_classes,
+        summary.eq_classes - summary.unique_eq_classes
+    )?;
+    writeln!(
+        out,
+        "Nodes by equivalence class: {} unique, {} shared",
+        summary.unique_nodes,
+        summary.nodes - summary.unique_nodes
+    )?;
+    writeln!(out)?;
+    writeln!(out, "Transcript\tGene\tUniqueKmers")?;
+    for (name, gene, count) in &summary.unique_kmers {
+        writeln!(out, "{}\t{}\t{}", name, gene, count)?;
+    }
     Ok(())
 }
@@ -0,0 +1,204 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Bootstrap intervals for deletion counts, by resampling the mapped reads across
+//! equivalence classes with replacement and re-estimating the counts, including by EM.
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Binomial, Distribution};
+use serde::Serialize;
+
+use crate::em;
+
+#[derive(Clone, Copy, Debug)]
+pub struct BootstrapParams {
+    pub bootstraps: usize,
+    pub seed: u64,
+}
+
+/// Summary of one value over all bootstraps, with a 95% percentile interval
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct BootstrapSummary {
+    pub mean: f64,
+    pub sd: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+/// Draw `total` reads from `counts` with replacement, in proportion to the counts. The
+/// multinomial draw is made as a binomial draw per category from the reads left, so it
+/// costs one draw per category rather than per read.
+fn resample<R: Rng>(counts: &[u32], total: u32, rng: &mut R) -> Vec<u32> {
+    let mut resampled = vec![0u32; counts.len()];
+    let mut remaining = total as u64;
+    let mut remaining_weight: u64 = counts.iter().map(|c| *c as u64).sum();
+    for (count, drawn) in counts.iter().zip(resampled.iter_mut()) {
+        if remaining == 0 || remaining_weight == 0 {
+            break;
+        }
+        let p = *count as f64 / remaining_weight as f64;
+        let n = if p >= 1.0 {
+            remaining
+        } else {
+            Binomial::new(remaining, p).expect("probability is in [0, 1]").sample(rng)
+        };
+        *drawn = n as u32;
+        remaining -= n;
+        remaining_weight -= *count as u64;
+    }
+    resampled
+}
+
+/// Per transcript values in each bootstrap, indexed by transcript id
+#[derive(Debug, PartialEq)]
+pub struct BootstrapCounts {
+    /// reads in equivalence classes of only the transcript
+    pub unique: Vec<Vec<f64>>,
+    /// reads estimated by EM
+    pub em: Vec<Vec<f64>>,
+}
+
+/// Resample the reads of each equivalence class `params.bootstraps` times, giving the unique
+/// and EM counts per transcript. `unmatched` is the number of mapped reads not in `ecs`, which
+/// are resampled with them so the total is unchanged. `eff_lens` are the EM effective lengths.
+pub fn bootstrap_counts(
+    ecs: &[Vec<u32>],
+    counts: &[u32],
+    unmatched: u32,
+    eff_lens: &[f64],
+    params: BootstrapParams,
+) -> BootstrapCounts {
+    let n_tx = eff_lens.len();
+    let mut rng = StdRng::seed_from_u64(params.seed);
+    let mut categories = counts.to_vec();
+    categories.push(unmatched);
+    let total = categories.iter().sum();
+
+    let mut unique = vec![Vec::with_capacity(params.bootstraps); n_tx];
+    let mut em_counts = vec![Vec::with_capacity(params.bootstraps); n_tx];
+    for _ in 0..params.bootstraps {
+        let resampled = resample(&categories, total, &mut rng);
+        let ec_counts = &resampled[..ecs.len()];
+
+        let mut tx_counts = vec![0u32; n_tx];
+        for (ec, count) in ecs.iter().zip(ec_counts) {
+            if ec.len() == 1 {
+                tx_counts[ec[0] as usize] += count;
+            }
+        }
+        for (values, count) in unique.iter_mut().zip(tx_counts) {
+            values.push(count as f64);
+        }
+
+        let em_result = em::expectation_maximisation(ecs, ec_counts, eff_lens);
+        for (values, count) in em_counts.iter_mut().zip(em_result.counts) {
+            values.push(count);
+        }
+    }
+    BootstrapCounts { unique, em: em_counts }
+}
+
+/// Mean, sample standard deviation and 2.5/97.5 percentiles (nearest rank)
+pub fn summarise(values: &[f64]) -> BootstrapSummary {
+    if values.is_empty() {
+        return BootstrapSummary {
+            mean: 0.0,
+            sd: 0.0,
+            lower: 0.0,
+            upper: 0.0,
+        };
+    }
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let sd = if values.len() > 1 {
+        (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+    } else {
+        0.0
+    };
+
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let rank = |p: f64| ((p * n).ceil() as usize).max(1).min(sorted.len()) - 1;
+
+    BootstrapSummary {
+        mean,
+        sd,
+        lower: sorted[rank(0.025)],
+        upper: sorted[rank(0.975)],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reproducible_test() {
+        let ecs = vec![vec![0], vec![1], vec![0, 1]];
+        let counts = vec![3, 40, 10];
+        let params = BootstrapParams {
+            bootstraps: 20,
+            seed: 42,
+        };
+
+        let first = bootstrap_counts(&ecs, &counts, 5, &[100.0, 100.0], params);
+        let second = bootstrap_counts(&ecs, &counts, 5, &[100.0, 100.0], params);
+        assert_eq!(first, second);
+        assert_eq!(first.unique[0].len(), 20);
+        assert_eq!(first.em[1].len(), 20);
+        for i in 0..20 {
+            let (unique, em) = (first.unique[0][i] + first.unique[1][i], first.em[0][i] + first.em[1][i]);
+            // only unique ecs are counted, and the EM shares out the shared ec too, from a
+            // total of 58 reads
+            assert!(unique <= em + 1e-6);
+            assert!(em <= 58.0 + 1e-6);
+        }
+    }
+
+    #[test]
+    fn resample_test() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let counts = vec![0, 1000, 3000, 0, 6000];
+        let mut totals = vec![0u32; counts.len()];
+        for _ in 0..10 {
+            let resampled = resample(&counts, 10_000, &mut rng);
+            assert_eq!(resampled.iter().sum::<u32>(), 10_000);
+            for (total, n) in totals.iter_mut().zip(resampled) {
+                *total += n;
+            }
+        }
+        assert_eq!((totals[0], totals[3]), (0, 0));
+        // within a few standard deviations of the expected proportions
+        for (total, expected) in totals.iter().zip(&[0.0, 10_000.0, 30_000.0, 0.0, 60_000.0]) {
+            assert!((*total as f64 - expected).abs() < 600.0, "{} vs {}", total, expected);
+        }
+
+        assert_eq!(resample(&[0, 0], 10, &mut rng), vec![0, 0]);
+    }
+
+    #[test]
+    fn em_bootstrap_test() {
+        // as em_shared_test: the shared reads are split 3:1, so the EM counts centre on 60 and 20
+        let ecs = vec![vec![0], vec![1], vec![0, 1]];
+        let params = BootstrapParams {
+            bootstraps: 200,
+            seed: 7,
+        };
+        let counts = bootstrap_counts(&ecs, &[30, 10, 40], 0, &[100.0, 100.0], params);
+        let em0 = summarise(&counts.em[0]);
+        let em1 = summarise(&counts.em[1]);
+        assert!((em0.mean - 60.0).abs() < 3.0, "{:?}", em0);
+        assert!((em1.mean - 20.0).abs() < 3.0, "{:?}", em1);
+        assert!(em0.lower < 60.0 && em0.upper > 60.0);
+        // the EM counts spread more than the unique counts, as shared reads follow them
+        assert!(em0.sd > summarise(&counts.unique[0]).sd);
+    }
+
+    #[test]
+    fn summarise_test() {
+        let values: Vec<f64> = (1..=100).map(|v| v as f64).collect();
+        let summary = summarise(&values);
+        assert_eq!(summary.mean, 50.5);
+        assert_eq!(summary.lower, 3.0);
+        assert_eq!(summary.upper, 98.0);
+    }
+}
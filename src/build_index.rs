@@ -4,10 +4,11 @@
 
 use lazy_static::lazy_static;
 use std::collections::{HashMap, HashSet};
+use std::io::Write;
 
 use std::sync::Arc;
 
-use crate::config::{KmerType, MEM_SIZE, REPORT_ALL_KMER, STRANDED};
+use crate::config::{MEM_SIZE, REPORT_ALL_KMER, STRANDED};
 use boomphf::hashmap::{BoomHashMap2, NoKeyBoomHashMap};
 use debruijn;
 use debruijn::compression::*;
@@ -18,26 +19,42 @@ use debruijn::*;
 
 use crate::config::{MIN_KMERS, U32_MAX,DEFAULT_ALLOWED_MISMATCHES};
 use crate::equiv_classes::{CountFilterEqClass, EqClassIdType};
-use crate::pseudoaligner::Pseudoaligner;
+use crate::pseudoaligner::{map_read_to_graph, MapParams, Pseudoaligner, ReadGraph};
+use crate::index_file::{self, IndexHeader, IndexKind, LoadedIndex};
 use boomphf;
 use boomphf::Mphf;
 use serde::{Serialize, Deserialize};
-use bincode;
 
 
 use failure::Error;
-use log::info;
+use log::{info, warn};
 use rayon::prelude::*;
 use rayon::{self, ThreadPool};
 
 const MIN_SHARD_SEQUENCES: usize = 2000;
 
+/// Graph construction settings, defaulting to the values in `config`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct IndexParams {
+    /// minimum number of observations of a kmer to keep it in the graph
+    pub min_kmers: usize,
+}
+
+impl Default for IndexParams {
+    fn default() -> IndexParams {
+        IndexParams {
+            min_kmers: MIN_KMERS,
+        }
+    }
+}
+
 pub fn build_index<K: Kmer + Sync + Send>(
     seqs: &[DnaString],
     tx_names: &Vec<String>,
     tx_gene_map: &HashMap<String, String>,
     gene_length_map: &HashMap<String, usize>,
     num_threads: usize,
+    params: &IndexParams,
 ) -> Result<Pseudoaligner<K>, Error> {
     // Thread pool Configuration for calling BOOMphf
     let pool = rayon::ThreadPoolBuilder::new()
@@ -53,7 +70,7 @@ pub fn build_index<K: Kmer + Sync + Send>(
     let mut buckets: Vec<_> = seqs
         .iter()
         .enumerate()
-        .flat_map(|(id, seq)| partition_contigs::<KmerType>(seq, id as u32))
+        .flat_map(|(id, seq)| partition_contigs::<K>(seq, id as u32))
         .collect();
 
     pool.install(|| {
@@ -61,7 +78,7 @@ pub fn build_index<K: Kmer + Sync + Send>(
     });
     info!("Got {} sequence chunks", buckets.len());
 
-    let summarizer = Arc::new(CountFilterEqClass::new(MIN_KMERS));
+    let summarizer = Arc::new(CountFilterEqClass::new(params.min_kmers));
     let sequence_shards = group_by_slices(&buckets, |x| x.0, MIN_SHARD_SEQUENCES);
 
     info!("Assembling {} shards...", sequence_shards.len());
@@ -97,6 +114,7 @@ pub fn build_index<K: Kmer + Sync + Send>(
         tx_names.clone(),
         tx_gene_map.clone(),
         gene_length_map.clone(),
+        seqs.iter().map(|s| s.len()).collect(),
     ))
 }
 
@@ -106,7 +124,7 @@ pub fn build_index<K: Kmer + Sync + Send>(
 pub struct WasmIndex {
     /// kmer length
     pub k: u8,
-    /// flat list of (kmer_u64, node_id, offset_in_node)
+    /// flat list of (kmer_u64, node_id, offset_in_node), sorted by kmer for binary search
     pub kmers: Vec<(u64, u32, u32)>,
     /// equivalence classes (copy of eq_classes used by Pseudoaligner)
     pub eq_classes: Vec<Vec<u32>>,
@@ -114,27 +132,41 @@ pub struct WasmIndex {
     pub tx_names: Vec<String>,
     pub tx_gene_map: HashMap<String, String>,
     pub gene_length_map: HashMap<String, usize>,
-    pub node_eq: Vec<u32>
+    /// length of each transcript by id
+    pub tx_lengths: Vec<usize>,
+    pub node_eq: Vec<u32>,
+    /// node sequences, for base by base extension over mismatches
+    pub node_seqs: Vec<DnaString>,
+    /// left and right neighbour of each node by extension base (A, C, G, T), `NO_EDGE` if none
+    pub left_edges: Vec<[u32; 4]>,
+    pub right_edges: Vec<[u32; 4]>,
 }
 
+/// Marks a missing extension in `WasmIndex::left_edges` and `right_edges`
+pub const NO_EDGE: u32 = u32::MAX;
+
 impl WasmIndex {
     pub fn save_to_file(&self, path: &std::path::Path) -> Result<(), failure::Error> {
-        let f = std::fs::File::create(path)?;
-        bincode::serialize_into(f, self)?;
-        Ok(())
+        index_file::write_index(self, IndexHeader::new(IndexKind::Wasm, self.k as usize, None), path)
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, failure::Error> {
-        let idx: WasmIndex = bincode::deserialize(bytes)?;
-        Ok(idx)
+        match index_file::parse_index(bytes)? {
+            (_, LoadedIndex::Wasm(idx)) => Ok(idx),
+            (_, LoadedIndex::Native(_)) => Err(failure::err_msg("Expected a WASM index, found a native index")),
+        }
     }
 }
 
 pub trait IndexLike: Sync {
-    fn map_read(&self, read_seq: &DnaString, mismatch_size: usize) -> Option<(Vec<u32>, usize, usize, usize)>;
+    fn map_read(&self, read_seq: &DnaString, params: &MapParams) -> Option<(Vec<u32>, usize, usize, usize)>;
+    /// k-mer length the index was built with
+    fn k(&self) -> usize;
     fn tx_names(&self) -> &Vec<String>;
     fn tx_gene_mapping(&self) -> &HashMap<String, String>;
     fn gene_length_mapping(&self) -> &HashMap<String, usize>;
+    /// length of each transcript by id, empty if the index does not record them
+    fn tx_lengths(&self) -> &[usize];
 }
 
 
@@ -343,60 +375,12 @@ pub trait IndexLike: Sync {
 
 
 impl IndexLike for WasmRuntimeIndex {
-    fn map_read(&self, read_seq: &DnaString, _allowed_mismatches: usize) -> Option<(Vec<u32>, usize, usize, usize)> {
-        // convert read into canonical k-mer u64s (utils::kmers_to_u64_vec must match exporter encoding)
-        let kmers = crate::utils::kmers_to_u64_vec(read_seq, self.k as usize);
-        if kmers.is_empty() {
-            return None;
-        }
-
-        // collect node_eq ids hit by kmers (node -> eq_id)
-        let mut eq_ids: Vec<u32> = Vec::with_capacity(kmers.len());
-        for k in kmers.iter() {
-            if let Some((node_id, _offset)) = self.lookup.get(k) {
-                let node_idx = *node_id as usize;
-                if let Some(&eqid) = self.node_eq.get(node_idx) {
-                    eq_ids.push(eqid);
-                }
-            }
-        }
-        if eq_ids.is_empty() {
-            return None;
-        }
-
-        // Start with eq-class of first eq_id, then intersect with others
-        let first_eqid = eq_ids[0] as usize;
-        let mut eq = match self.eq_classes.get(first_eqid) {
-            Some(v) => v.clone(),
-            None => return None,
-        };
-        // ensure sorted & dedup for intersection correctness
-        eq.sort_unstable();
-        eq.dedup();
-
-        for &eid in eq_ids.iter().skip(1) {
-            if let Some(other) = self.eq_classes.get(eid as usize) {
-                let other_set: HashSet<u32> = other.iter().cloned().collect();
-                eq.retain(|x| other_set.contains(x));
-                if eq.is_empty() {
-                    return None;
-                }
-            } else {
-                return None;
-            }
-        }
-
-        // coverage = number of kmers that matched (eq_ids.len())
-        let coverage = eq_ids.len();
-        let mismatches = 0usize; // simple placeholder; keep native mismatch logic outside wasm shim
-        let readlen = read_seq.len();
+    fn map_read(&self, read_seq: &DnaString, params: &MapParams) -> Option<(Vec<u32>, usize, usize, usize)> {
+        map_read_to_graph(self, read_seq, params)
+    }
 
-        // Some((eq, coverage, mismatches, readlen))
-         // final normalize the eq vector to ensure stable ordering for downstream comparisons
-        let mut eq_final = eq;
-        eq_final.sort_unstable();
-        eq_final.dedup();
-        Some((eq_final, coverage, mismatches, readlen))
+    fn k(&self) -> usize {
+        self.k as usize
     }
 
     fn tx_names(&self) -> &Vec<String> {
@@ -410,10 +394,14 @@ impl IndexLike for WasmRuntimeIndex {
     fn gene_length_mapping(&self) -> &HashMap<String, usize> {
         &self.gene_length_map
     }
+
+    fn tx_lengths(&self) -> &[usize] {
+        &self.tx_lengths
+    }
 }
 
 // Exporter: walk the de Bruijn graph and collect all kmers -> node+offset
-pub fn export_wasm_index<K: Kmer>(al: &Pseudoaligner<K>) -> WasmIndex {
+pub fn export_wasm_index<K: Kmer + Sync + Send>(al: &Pseudoaligner<K>) -> WasmIndex {
     let mut kmers = Vec::new();
     let klen = K::k() as u8;
 
@@ -424,6 +412,8 @@ pub fn export_wasm_index<K: Kmer>(al: &Pseudoaligner<K>) -> WasmIndex {
             kmers.push((kmer.to_u64(), node_id as u32, offset as u32));
         }
     }
+    // sorted, so the WASM runtime can search the deserialised kmers without building a map
+    kmers.sort_unstable_by_key(|(kmer, _, _)| *kmer);
 
     // Build node -> eq_id mapping
     let mut node_eq = Vec::with_capacity(al.dbg.len());
@@ -432,6 +422,25 @@ pub fn export_wasm_index<K: Kmer>(al: &Pseudoaligner<K>) -> WasmIndex {
         node_eq.push(*eqid as u32);
     }
 
+    // node sequences and edges, so the WASM index can extend reads like the native graph
+    let mut node_seqs = Vec::with_capacity(al.dbg.len());
+    let mut left_edges = Vec::with_capacity(al.dbg.len());
+    let mut right_edges = Vec::with_capacity(al.dbg.len());
+    for node_id in 0..al.dbg.len() {
+        node_seqs.push(al.dbg.get_node(node_id).sequence().to_owned());
+        let edges = |dir: Dir| {
+            let mut edges = [NO_EDGE; 4];
+            for base in 0..4u8 {
+                if let Some(next) = al.extension(node_id, dir, base) {
+                    edges[base as usize] = next as u32;
+                }
+            }
+            edges
+        };
+        left_edges.push(edges(Dir::Left));
+        right_edges.push(edges(Dir::Right));
+    }
+
     WasmIndex {
         k: klen,
         kmers,
@@ -439,7 +448,11 @@ pub fn export_wasm_index<K: Kmer>(al: &Pseudoaligner<K>) -> WasmIndex {
         tx_names: al.tx_names.clone(),
         tx_gene_map: al.tx_gene_mapping.clone(),
         gene_length_map: al.gene_length_mapping.clone(),
-         node_eq,
+        tx_lengths: al.tx_lengths.clone(),
+        node_eq,
+        node_seqs,
+        left_edges,
+        right_edges,
     }
 }
 
@@ -477,65 +490,122 @@ pub fn intersect<T: Eq + Ord>(v1: &mut Vec<T>, v2: &[T]) {
 
 
 
+/// A `WasmIndex` ready for mapping. The kmers are used as deserialised: lookups are
+/// binary searches over the sorted kmer array, so loading needs no extra memory.
 pub struct WasmRuntimeIndex {
     pub k: u8,
-    /// runtime lookup for kmer u64 -> (node_id, offset)
-    pub lookup: HashMap<u64, (u32, u32)>,
+    /// (kmer_u64, node_id, offset_in_node), sorted by kmer
+    pub kmers: Vec<(u64, u32, u32)>,
     pub eq_classes: Vec<Vec<u32>>,
     pub tx_names: Vec<String>,
     pub tx_gene_map: HashMap<String, String>,
     pub gene_length_map: HashMap<String, usize>,
-    pub node_eq: Vec<u32>
+    pub tx_lengths: Vec<usize>,
+    pub node_eq: Vec<u32>,
+    pub node_seqs: Vec<DnaString>,
+    pub left_edges: Vec<[u32; 4]>,
+    pub right_edges: Vec<[u32; 4]>,
+    /// `read_kmer_u64` for the index k, chosen once rather than per k-mer lookup
+    read_kmer: fn(&DnaString, usize) -> u64,
+}
+
+/// The read k-mer at `pos` as the exporter records node k-mers: `Kmer::to_u64` of the
+/// index's k-mer type, not canonical k-mers
+fn read_kmer_u64<K: Kmer>(read_seq: &DnaString, pos: usize) -> u64 {
+    let kmer: K = read_seq.get_kmer(pos);
+    kmer.to_u64()
+}
+
+fn is_sorted_by<T, F: Fn(&T, &T) -> bool>(items: &[T], ordered: F) -> bool {
+    items.windows(2).all(|w| ordered(&w[0], &w[1]))
 }
 
 impl WasmRuntimeIndex {
-    pub fn from_wasm_index(idx: WasmIndex) -> Self {
-        let lookup = idx.kmers.into_iter().map(|(kmer, node, offset)| (kmer, (node, offset)))
-    .collect::<HashMap<u64, (u32, u32)>>();
+    /// Errors if the index k-mer size is not supported, as k-mer lookups need its k-mer type
+    pub fn from_wasm_index(idx: WasmIndex) -> Result<Self, Error> {
+        crate::config::check_kmer_size(idx.k as usize)?;
+        let read_kmer = crate::with_kmer_type!(idx.k as usize, K => read_kmer_u64::<K> as fn(&DnaString, usize) -> u64);
+
+        // exported indexes are already sorted; only sort (in place) if one is not
+        let mut kmers = idx.kmers;
+        if !is_sorted_by(&kmers[..], |a, b| a.0 <= b.0) {
+            warn!("WASM index kmers are not sorted, sorting on load; re-export the index to avoid this");
+            kmers.sort_unstable_by_key(|(kmer, _, _)| *kmer);
+        }
 
-     let mut eqs = idx.eq_classes;
+        let mut eqs = idx.eq_classes;
         for v in eqs.iter_mut() {
-           v.sort_unstable();
-            v.dedup();
+            if !is_sorted_by(&v[..], |a, b| a < b) {
+                v.sort_unstable();
+                v.dedup();
+            }
         }
 
-        WasmRuntimeIndex {
+        Ok(WasmRuntimeIndex {
             k: idx.k,
-            lookup,
+            kmers,
             eq_classes: eqs,
             tx_names: idx.tx_names,
             tx_gene_map: idx.tx_gene_map,
             gene_length_map: idx.gene_length_map,
-            node_eq: idx.node_eq
-        }
+            tx_lengths: idx.tx_lengths,
+            node_eq: idx.node_eq,
+            node_seqs: idx.node_seqs,
+            left_edges: idx.left_edges,
+            right_edges: idx.right_edges,
+            read_kmer,
+        })
     }
 
     /// lookup a single kmer given as u64
     pub fn lookup_kmer(&self, kmer_u64: u64) -> Option<(u32, u32)> {
-        self.lookup.get(&kmer_u64).cloned()
+        self.kmers
+            .binary_search_by_key(&kmer_u64, |(kmer, _, _)| *kmer)
+            .ok()
+            .map(|i| (self.kmers[i].1, self.kmers[i].2))
     }
 
- fn map_read_from_u64_kmers(&self, kmers_u64: &[u64]) -> Option<(Vec<u32>, usize, usize, usize)> {
-        let mut nodes: Vec<usize> = Vec::new();
-        for &k in kmers_u64 {
-            if let Some((node_id, _off)) = self.lookup.get(&k) {
-                nodes.push(*node_id as usize);
-            }
-        }
-        if nodes.is_empty() {
-            return None;
-        }
-        // intersect eq classes for nodes
-        let mut eq_class: Vec<u32> = Vec::new();
-        // first node's eq-class
-        let first_color = self.lookup.get(&kmers_u64[0]).map(|(nid, _)| *nid as usize).unwrap_or(0);
-        eq_class.extend(&self.eq_classes[first_color]); // careful: node id -> eq index mapping must match
-        for &n in nodes.iter().skip(1) {
-            intersect(&mut eq_class, &self.eq_classes[n]);
+    /// Approximate heap memory of the graph and kmer arrays, in bytes (transcript names excluded)
+    pub fn heap_size(&self) -> usize {
+        use std::mem::size_of;
+        self.kmers.capacity() * size_of::<(u64, u32, u32)>()
+            + self.eq_classes.iter().map(|v| size_of::<Vec<u32>>() + v.capacity() * size_of::<u32>()).sum::<usize>()
+            + self.node_eq.capacity() * size_of::<u32>()
+            + self.node_seqs.iter().map(|seq| size_of::<DnaString>() + (seq.len() + 3) / 4).sum::<usize>()
+            + (self.left_edges.capacity() + self.right_edges.capacity()) * size_of::<[u32; 4]>()
+    }
+}
+
+
+impl ReadGraph for WasmRuntimeIndex {
+    fn kmer_length(&self) -> usize {
+        self.k as usize
+    }
+
+    fn find_kmer(&self, read_seq: &DnaString, pos: usize) -> Option<(usize, usize)> {
+        self.lookup_kmer((self.read_kmer)(read_seq, pos))
+            .map(|(node_id, offset)| (node_id as usize, offset as usize))
+    }
+
+    fn node_sequence(&self, node_id: usize) -> DnaStringSlice<'_> {
+        let seq = &self.node_seqs[node_id];
+        seq.slice(0, seq.len())
+    }
+
+    fn extension(&self, node_id: usize, dir: Dir, base: u8) -> Option<usize> {
+        let edges = match dir {
+            Dir::Left => &self.left_edges[node_id],
+            Dir::Right => &self.right_edges[node_id],
+        };
+        match edges[base as usize] {
+            NO_EDGE => None,
+            next => Some(next as usize),
         }
-        Some((eq_class, kmers_u64.len(), 0, (kmers_u64.len() + self.k as usize - 1)))
     }
 
+    fn node_eq_class(&self, node_id: usize) -> &[u32] {
+        &self.eq_classes[self.node_eq[node_id] as usize]
+    }
 }
 
 
@@ -650,6 +720,157 @@ pub fn validate_dbg<K: Kmer + Sync + Send>(seqs: &[DnaString], al: &Pseudoaligne
     }
 }
 
+/// How uniquely a deletion transcript can be identified by reads
+#[derive(Debug)]
+pub struct DeletionDetectability {
+    pub tx_name: String,
+    pub gene: String,
+    pub length: usize,
+    /// k-mers spanning the deletion junction, i.e. not shared with the canonical
+    /// transcript of the gene. `None` if the gene has no canonical transcript.
+    pub junction_kmers: Option<usize>,
+    /// k-mers whose equivalence class is only this transcript
+    pub unique_kmers: usize,
+    /// shortest exact read that maps to an equivalence class of only this transcript
+    pub min_read_length: Option<usize>,
+    /// other transcripts sharing a junction k-mer with this transcript
+    pub confusable_with: Vec<String>,
+}
+
+/// For each `del` transcript, count the k-mers that identify it uniquely and find the
+/// shortest read that reaches a unique equivalence class, by intersecting the equivalence
+/// classes of consecutive k-mers along the transcript as the pseudoaligner would.
+pub fn deletion_detectability<K: Kmer + Sync + Send>(
+    seqs: &[DnaString],
+    al: &Pseudoaligner<K>,
+) -> Vec<DeletionDetectability> {
+    let kmer_length = K::k();
+    let eq_classes = &al.eq_classes;
+
+    // the full length (non deletion) transcript of each gene
+    let mut canonical: HashMap<&str, u32> = HashMap::new();
+    for (i, name) in al.tx_names.iter().enumerate() {
+        if !name.contains("del") {
+            if let Some(gene) = al.tx_gene_mapping.get(name) {
+                canonical.entry(gene.as_str()).or_insert(i as u32);
+            }
+        }
+    }
+
+    let mut report = Vec::new();
+    for (i, name) in al.tx_names.iter().enumerate() {
+        if !name.contains("del") {
+            continue;
+        }
+        let tx_id = i as u32;
+        let gene = al.tx_gene_mapping.get(name).cloned().unwrap_or_default();
+        let canonical_id = canonical.get(gene.as_str()).cloned();
+        let seq = &seqs[i];
+
+        // runs of consecutive k-mers in the same equivalence class: (eq class, first pos, last pos)
+        let mut runs: Vec<(EqClassIdType, usize, usize)> = Vec::new();
+        if seq.len() >= kmer_length {
+            for (pos, kmer) in seq.iter_kmers::<K>().enumerate() {
+                let eq_id = match al.dbg_index.get(&kmer) {
+                    Some((node_id, _)) => *al.dbg.get_node(*node_id as usize).data(),
+                    None => continue,
+                };
+                match runs.last_mut() {
+                    Some(run) if run.0 == eq_id && run.2 + 1 == pos => run.2 = pos,
+                    _ => runs.push((eq_id, pos, pos)),
+                }
+            }
+        }
+        let run_kmers = |run: &(EqClassIdType, usize, usize)| run.2 - run.1 + 1;
+
+        let unique_kmers = runs
+            .iter()
+            .filter(|run| eq_classes[run.0 as usize] == [tx_id])
+            .map(run_kmers)
+            .sum();
+
+        let junction_runs: Vec<_> = match canonical_id {
+            Some(c) => runs
+                .iter()
+                .filter(|run| !eq_classes[run.0 as usize].contains(&c))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let mut confusable: Vec<u32> = junction_runs
+            .iter()
+            .flat_map(|run| eq_classes[run.0 as usize].iter().cloned())
+            .filter(|t| *t != tx_id)
+            .collect();
+        confusable.sort();
+        confusable.dedup();
+
+        // shortest window of runs a..=b whose intersected equivalence class is this transcript,
+        // covering the last k-mer of run a and the first k-mer of run b
+        let mut min_read_length: Option<usize> = None;
+        for a in 0..runs.len() {
+            let mut eq = eq_classes[runs[a].0 as usize].clone();
+            for b in a..runs.len() {
+                if b > a {
+                    intersect(&mut eq, &eq_classes[runs[b].0 as usize]);
+                }
+                if eq == [tx_id] {
+                    let read_length = if b == a {
+                        kmer_length
+                    } else {
+                        runs[b].1 - runs[a].2 + kmer_length
+                    };
+                    min_read_length = Some(min_read_length.map_or(read_length, |m| m.min(read_length)));
+                    break;
+                }
+            }
+        }
+
+        if min_read_length.is_none() {
+            warn!("{} ({}) has no read that maps uniquely to it", name, gene);
+        }
+
+        report.push(DeletionDetectability {
+            tx_name: name.clone(),
+            gene,
+            length: seq.len(),
+            junction_kmers: canonical_id.map(|_| junction_runs.iter().map(|run| run_kmers(run)).sum()),
+            unique_kmers,
+            min_read_length,
+            confusable_with: confusable
+                .iter()
+                .map(|t| al.tx_names[*t as usize].clone())
+                .collect(),
+        });
+    }
+
+    report
+}
+
+pub fn write_detectability_report<W: Write>(
+    report: &[DeletionDetectability],
+    mut writer: W,
+) -> Result<(), Error> {
+    let na = |v: Option<usize>| v.map_or("NA".to_string(), |v| v.to_string());
+
+    writeln!(writer, "Gene,Deletion,Length,JunctionKmers,UniqueKmers,MinReadLength,ConfusableWith")?;
+    for d in report {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},\"{}\"",
+            d.gene,
+            d.tx_name,
+            d.length,
+            na(d.junction_kmers),
+            d.unique_kmers,
+            na(d.min_read_length),
+            d.confusable_with.join(",")
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
 type PmerType = debruijn::kmer::Kmer6;
 
 lazy_static! {
@@ -837,11 +1058,103 @@ mod test {
         }
     }
 
+    #[test]
+    fn wasm_lookup_test() {
+        let index = |k: u8| WasmIndex {
+            k,
+            kmers: vec![(9, 1, 0), (2, 0, 1), (5, 0, 0)],
+            eq_classes: vec![vec![1, 0], vec![0]],
+            tx_names: vec!["a".to_string(), "b".to_string()],
+            tx_gene_map: HashMap::new(),
+            gene_length_map: HashMap::new(),
+            tx_lengths: vec![40, 30],
+            node_eq: vec![0, 1],
+            node_seqs: Vec::new(),
+            left_edges: Vec::new(),
+            right_edges: Vec::new(),
+        };
+        let runtime = WasmRuntimeIndex::from_wasm_index(index(config::KmerType::k() as u8)).unwrap();
+        assert_eq!(runtime.lookup_kmer(5), Some((0, 0)));
+        assert_eq!(runtime.lookup_kmer(9), Some((1, 0)));
+        assert_eq!(runtime.lookup_kmer(7), None);
+        assert_eq!(runtime.eq_classes[0], vec![0, 1]);
+
+        // k-mer lookups need a k-mer type of the index k
+        assert!(WasmRuntimeIndex::from_wasm_index(index(40)).is_err());
+    }
+
+    fn check_wasm_map_read<K: Kmer + Sync + Send>() -> Result<(), Error> {
+        let tx = "ATGGCGTACCTGAAGCTTGACCGTATCGGATCCAAGTTCGAGCTAGGCATTCGACTGACGTTAGCCATGGTACGATCAGGTC";
+        let del = format!("{}{}", &tx[..30], &tx[55..]);
+        let seqs = vec![DnaString::from_dna_string(tx), DnaString::from_dna_string(&del)];
+        let tx_names = vec!["wt".to_string(), "del1".to_string()];
+        let index = build_index::<K>(&seqs, &tx_names, &HashMap::new(), &HashMap::new(), 1, &IndexParams::default())?;
+        let wasm = WasmRuntimeIndex::from_wasm_index(export_wasm_index(&index))?;
+        assert_eq!(IndexLike::k(&wasm), K::k());
+
+        let mut reads = vec![tx[5..65].to_string(), del[2..52].to_string(), tx[20..80].to_string()];
+        // a read with a mismatch
+        let mut snp = tx[10..70].as_bytes().to_vec();
+        snp[25] = if snp[25] == b'A' { b'C' } else { b'A' };
+        reads.push(String::from_utf8(snp)?);
+
+        let params = MapParams::default();
+        for read in &reads {
+            let seq = DnaString::from_dna_string(read);
+            assert_eq!(IndexLike::map_read(&index, &seq, &params), IndexLike::map_read(&wasm, &seq, &params));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wasm_map_read_test() -> Result<(), Error> {
+        check_wasm_map_read::<config::KmerType>()?;
+        check_wasm_map_read::<config::Kmer25>()
+    }
+
+    fn detectability(seqs: &[DnaString], tx_names: &[&str]) -> Result<Vec<DeletionDetectability>, Error> {
+        let tx_names: Vec<String> = tx_names.iter().map(|name| name.to_string()).collect();
+        let tx_gene_map = tx_names.iter().map(|name| (name.clone(), "G".to_string())).collect();
+        let index = build_index::<config::KmerType>(seqs, &tx_names, &tx_gene_map, &HashMap::new(), 1, &IndexParams::default())?;
+        Ok(deletion_detectability(seqs, &index))
+    }
+
+    #[test]
+    fn deletion_detectability_test() -> Result<(), Error> {
+        let tx = "ATGGCGTACCTGAAGCTTGACCGTATCGGATCCAAGTTCGAGCTAGGCATTCGACTGACGTTAGCCATGGTACGATCAGGTC";
+        let del = format!("{}{}", &tx[..30], &tx[55..]);
+        let mut seqs = vec![DnaString::from_dna_string(tx), DnaString::from_dna_string(&del)];
+
+        let report = detectability(&seqs, &["wt", "del1"])?;
+        assert_eq!(report.len(), 1);
+        let del1 = &report[0];
+        assert_eq!((del1.tx_name.as_str(), del1.gene.as_str(), del1.length), ("del1", "G", 57));
+        // the 18 k-mers spanning the deletion junction are only in del1
+        assert_eq!(del1.junction_kmers, Some(18));
+        assert_eq!(del1.unique_kmers, 18);
+        assert_eq!(del1.min_read_length, Some(config::KmerType::k()));
+        assert!(del1.confusable_with.is_empty());
+
+        // a fragment of del1 over the start of the junction shares its first 14 junction k-mers
+        seqs.push(DnaString::from_dna_string(&del[5..45]));
+        let report = detectability(&seqs, &["wt", "del1", "del2"])?;
+        let (del1, del2) = (&report[0], &report[1]);
+        assert_eq!(del1.junction_kmers, Some(18));
+        assert_eq!(del1.unique_kmers, 4);
+        assert_eq!(del1.min_read_length, Some(config::KmerType::k()));
+        assert_eq!(del1.confusable_with, vec!["del2".to_string()]);
+        assert_eq!(del2.junction_kmers, Some(14));
+        assert_eq!(del2.unique_kmers, 0);
+        assert_eq!(del2.min_read_length, None);
+        assert_eq!(del2.confusable_with, vec!["del1".to_string()]);
+        Ok(())
+    }
+
     #[test]
     fn test_gencode_small_build() -> Result<(), Error> {
         let fasta = fasta::Reader::from_file("test/gencode_small.fa")?;
         let (seqs, tx_names, tx_gene_map, gene_length_map) = utils::read_transcripts(fasta)?;
-        let index = build_index::<config::KmerType>(&seqs, &tx_names, &tx_gene_map, &gene_length_map, 2)?;
+        let index = build_index::<config::KmerType>(&seqs, &tx_names, &tx_gene_map, &gene_length_map, 2, &IndexParams::default())?;
         validate_dbg(&seqs, &index);
         Ok(())
     }
@@ -851,7 +1164,7 @@ mod test {
         let msg = "For full txome indexing test, download from ftp://ftp.ebi.ac.uk/pub/databases/gencode/Gencode_human/release_28/gencode.v28.transcripts.fa.gz, un-gzip and place in test/gencode.v28.transcripts.fa";
         let fasta = fasta::Reader::from_file("test/gencode.v28.transcripts.fa").context(msg)?;
         let (seqs, tx_names, tx_gene_map, gene_length_map) = utils::read_transcripts(fasta)?;
-        let index = build_index::<config::KmerType>(&seqs, &tx_names, &tx_gene_map, &gene_length_map,  2)?;
+        let index = build_index::<config::KmerType>(&seqs, &tx_names, &tx_gene_map, &gene_length_map,  2, &IndexParams::default())?;
         validate_dbg(&seqs, &index);
         Ok(())
     }
@@ -0,0 +1,361 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Deletion calls from `tinyt map` results. Each `del` transcript's supporting reads are
+//! tested against a background error rate with a binomial (or beta-binomial) upper tail
+//! test, and combined with a minimum supporting reads rule.
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use failure::{self, Error};
+
+use crate::normals::PanelOfNormals;
+
+/// A `tinyt map` results table, keeping every column so they can be passed through
+#[derive(Clone, Debug)]
+pub struct CountsTable {
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+/// Supporting and total reads of one deletion row of a `CountsTable`
+#[derive(Clone, Debug)]
+pub struct DeletionCount {
+    pub row: usize,
+    pub gene: String,
+    pub deletion: String,
+    pub count: u64,
+    pub total: u64,
+}
+
+impl CountsTable {
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<CountsTable, Error> {
+        let mut lines = reader.lines();
+        let header: Vec<String> = match lines.next() {
+            Some(line) => line?.split(',').map(|f| f.trim().to_string()).collect(),
+            None => return Err(failure::err_msg("Counts table is empty")),
+        };
+
+        let mut rows = Vec::new();
+        for (line_no, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let row: Vec<String> = line.split(',').map(|f| f.trim().to_string()).collect();
+            if row.len() != header.len() {
+                return Err(failure::err_msg(format!(
+                    "Counts table line {} has {} columns, header has {}",
+                    line_no + 2,
+                    row.len(),
+                    header.len()
+                )));
+            }
+            rows.push(row);
+        }
+
+        Ok(CountsTable { header, rows })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<CountsTable, Error> {
+        let file = File::open(path.as_ref()).map_err(|e| {
+            failure::err_msg(format!("Could not open counts table {}: {}", path.as_ref().display(), e))
+        })?;
+        CountsTable::from_reader(BufReader::new(file))
+    }
+
+    pub fn column(&self, name: &str) -> Result<usize, Error> {
+        self.header
+            .iter()
+            .position(|h| h == name)
+            .ok_or_else(|| failure::err_msg(format!("Counts table has no {} column", name)))
+    }
+
+    /// Counts of each `del` transcript, reading supporting reads from `count_column`.
+    /// Fractional counts, e.g. `EMCount`, are rounded.
+    pub fn deletion_counts(&self, count_column: &str) -> Result<Vec<DeletionCount>, Error> {
+        let gene = self.column("Gene")?;
+        let deletion = self.column("Deletion")?;
+        let count = self.column(count_column)?;
+        let total = self.column("Total")?;
+
+        let parse = |row: &[String], col: usize| -> Result<u64, Error> {
+            row[col]
+                .parse::<f64>()
+                .map(|v| v.round() as u64)
+                .map_err(|_| failure::err_msg(format!("Invalid {} value '{}'", self.header[col], row[col])))
+        };
+
+        self.rows
+            .iter()
+            .enumerate()
+            .filter(|(_, row)| row[deletion].contains("del"))
+            .map(|(i, row)| {
+                Ok(DeletionCount {
+                    row: i,
+                    gene: row[gene].clone(),
+                    deletion: row[deletion].clone(),
+                    count: parse(row, count)?,
+                    total: parse(row, total)?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Call {
+    Present,
+    Absent,
+    Indeterminate,
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Call::Present => write!(f, "present"),
+            Call::Absent => write!(f, "absent"),
+            Call::Indeterminate => write!(f, "indeterminate"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CallParams {
+    /// expected fraction of reads supporting a deletion that is not there
+    pub error_rate: f64,
+    pub min_reads: u64,
+    /// p-value threshold
+    pub alpha: f64,
+    /// beta-binomial overdispersion (intra-class correlation); binomial if `None`
+    pub dispersion: Option<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DeletionCall {
+    pub p_value: f64,
+    pub call: Call,
+    /// `PASS`, or the rules the deletion failed
+    pub filter: String,
+}
+
+/// Present if significant with enough supporting reads, absent if neither, and
+/// indeterminate if only one holds or there are no reads.
+pub fn call_deletion(count: u64, total: u64, params: &CallParams) -> DeletionCall {
+    let p_value = match params.dispersion {
+        Some(rho) => beta_binomial_upper_tail(count, total, params.error_rate, rho),
+        None => binomial_upper_tail(count, total, params.error_rate),
+    };
+
+    let mut filters = Vec::new();
+    if total == 0 {
+        filters.push("NoReads");
+    }
+    if count < params.min_reads {
+        filters.push("LowSupport");
+    }
+    if p_value > params.alpha {
+        filters.push("NotSignificant");
+    }
+
+    let call = match (total == 0, count >= params.min_reads, p_value <= params.alpha) {
+        (true, _, _) => Call::Indeterminate,
+        (false, true, true) => Call::Present,
+        (false, false, false) => Call::Absent,
+        _ => Call::Indeterminate,
+    };
+
+    DeletionCall {
+        p_value,
+        call,
+        filter: if filters.is_empty() {
+            "PASS".to_string()
+        } else {
+            filters.join(";")
+        },
+    }
+}
+
+/// Write the deletion rows of `table` with `PValue`, `Call` and `Filter` columns appended,
+/// and the comparison to a panel of normals if given
+pub fn write_calls<W: Write>(
+    table: &CountsTable,
+    calls: &[(DeletionCount, DeletionCall)],
+    normals: Option<&PanelOfNormals>,
+    mut writer: W,
+) -> Result<(), Error> {
+    write!(writer, "{},PValue,Call,Filter", table.header.join(","))?;
+    if normals.is_some() {
+        write!(writer, ",NormalSamples,NormalMean,NormalSD,ZScore,EmpiricalP")?;
+    }
+    writeln!(writer)?;
+
+    for (count, call) in calls {
+        write!(
+            writer,
+            "{},{:.3e},{},{}",
+            table.rows[count.row].join(","),
+            call.p_value,
+            call.call,
+            call.filter
+        )?;
+
+        if let Some(panel) = normals {
+            match panel.get(&count.gene, &count.deletion) {
+                Some(background) if count.total > 0 => {
+                    let comparison = background.compare(count.count as f64 / count.total as f64);
+                    let z_score = comparison
+                        .z_score
+                        .map_or("NA".to_string(), |z| format!("{:.3}", z));
+                    write!(
+                        writer,
+                        ",{},{:.3e},{:.3e},{},{:.3}",
+                        comparison.samples, comparison.mean, comparison.sd, z_score, comparison.empirical_p
+                    )?;
+                }
+                _ => write!(writer, ",0,NA,NA,NA,NA")?,
+            }
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Lanczos approximation of ln(Gamma(x)) for x > 0
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if x < 0.5 {
+        // reflection formula
+        std::f64::consts::PI.ln() - (std::f64::consts::PI * x).sin().ln() - ln_gamma(1.0 - x)
+    } else {
+        let x = x - 1.0;
+        let mut a = COEFFICIENTS[0];
+        let t = x + 7.5;
+        for (i, c) in COEFFICIENTS.iter().enumerate().skip(1) {
+            a += c / (x + i as f64);
+        }
+        0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + a.ln()
+    }
+}
+
+fn ln_choose(n: u64, k: u64) -> f64 {
+    ln_gamma(n as f64 + 1.0) - ln_gamma(k as f64 + 1.0) - ln_gamma((n - k) as f64 + 1.0)
+}
+
+fn ln_beta(a: f64, b: f64) -> f64 {
+    ln_gamma(a) + ln_gamma(b) - ln_gamma(a + b)
+}
+
+/// P(X >= k) for a unimodal distribution on 0..=n given its log pmf. Above the mode the
+/// upper tail is summed until terms are negligible, otherwise one minus the lower tail.
+fn upper_tail<F: Fn(u64) -> f64>(k: u64, n: u64, mode: f64, ln_pmf: F) -> f64 {
+    if k == 0 {
+        return 1.0;
+    }
+    if k > n {
+        return 0.0;
+    }
+
+    if k as f64 > mode {
+        let mut sum = 0.0;
+        for x in k..=n {
+            let term = ln_pmf(x).exp();
+            sum += term;
+            if term <= sum * 1e-16 {
+                break;
+            }
+        }
+        sum.min(1.0)
+    } else {
+        let lower: f64 = (0..k).map(|x| ln_pmf(x).exp()).sum();
+        (1.0 - lower).max(0.0).min(1.0)
+    }
+}
+
+/// P(X >= k) for X ~ Binomial(n, p)
+pub fn binomial_upper_tail(k: u64, n: u64, p: f64) -> f64 {
+    if p <= 0.0 {
+        return if k == 0 { 1.0 } else { 0.0 };
+    }
+    if p >= 1.0 {
+        return if k <= n { 1.0 } else { 0.0 };
+    }
+    upper_tail(k, n, (n as f64 + 1.0) * p, |x| {
+        ln_choose(n, x) + x as f64 * p.ln() + (n - x) as f64 * (1.0 - p).ln()
+    })
+}
+
+/// P(X >= k) for a beta-binomial with mean `p` and overdispersion `rho` in (0, 1)
+pub fn beta_binomial_upper_tail(k: u64, n: u64, p: f64, rho: f64) -> f64 {
+    if rho <= 0.0 {
+        return binomial_upper_tail(k, n, p);
+    }
+    if p <= 0.0 || p >= 1.0 {
+        return binomial_upper_tail(k, n, p);
+    }
+    let a = p * (1.0 - rho) / rho;
+    let b = (1.0 - p) * (1.0 - rho) / rho;
+    let ln_norm = ln_beta(a, b);
+    upper_tail(k, n, n as f64 * p, |x| {
+        ln_choose(n, x) + ln_beta(x as f64 + a, (n - x) as f64 + b) - ln_norm
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn binomial_test() {
+        assert!((binomial_upper_tail(1, 10, 0.1) - (1.0 - 0.9f64.powi(10))).abs() < 1e-9);
+        assert!((binomial_upper_tail(3, 10, 0.5) - (1.0 - 56.0 / 1024.0)).abs() < 1e-9);
+        assert!((binomial_upper_tail(10, 10, 0.5) - 1.0 / 1024.0).abs() < 1e-12);
+        assert_eq!(binomial_upper_tail(0, 10, 0.5), 1.0);
+        assert_eq!(binomial_upper_tail(11, 10, 0.5), 0.0);
+
+        // overdispersion makes extreme counts more likely
+        let binomial = binomial_upper_tail(20, 10_000, 0.001);
+        assert!(beta_binomial_upper_tail(20, 10_000, 0.001, 1e-3) > binomial);
+    }
+
+    #[test]
+    fn call_test() {
+        let params = CallParams {
+            error_rate: 1e-4,
+            min_reads: 3,
+            alpha: 0.01,
+            dispersion: None,
+        };
+        assert_eq!(call_deletion(50, 10_000, &params).call, Call::Present);
+        assert_eq!(call_deletion(50, 10_000, &params).filter, "PASS");
+        assert_eq!(call_deletion(0, 10_000, &params).call, Call::Absent);
+        assert_eq!(call_deletion(2, 100, &params).call, Call::Indeterminate);
+        assert_eq!(call_deletion(2, 100, &params).filter, "LowSupport");
+        assert_eq!(call_deletion(0, 0, &params).call, Call::Indeterminate);
+    }
+
+    #[test]
+    fn counts_table_test() -> Result<(), Error> {
+        let csv = "Gene,Deletion,Count,Total\nIKZF1,IKZF1-201,900,1000\nIKZF1,del4_7,12,1000\n";
+        let table = CountsTable::from_reader(csv.as_bytes())?;
+        let counts = table.deletion_counts("Count")?;
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].deletion, "del4_7");
+        assert_eq!((counts[0].count, counts[0].total), (12, 1000));
+        assert!(table.deletion_counts("EMCount").is_err());
+        Ok(())
+    }
+}
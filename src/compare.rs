@@ -0,0 +1,242 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Concordance of read mapping between a native index and a WASM index of the same
+//! graph, used to check a WASM build maps reads exactly as the native tool does.
+//! Each read is mapped in both orientations, as `tinyt map` does.
+use std::io::Write;
+
+use bio::alphabets::dna;
+use debruijn::dna_string::DnaString;
+use failure::{self, Error};
+use log::info;
+
+use crate::build_index::IndexLike;
+use crate::pseudoaligner::MapParams;
+use crate::utils::{self, Records};
+
+/// Result of `IndexLike::map_read`: equivalence class, coverage, mismatches and read length
+pub type MapResult = Option<(Vec<u32>, usize, usize, usize)>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapClass {
+    Unmapped,
+    Unique,
+    Multi,
+}
+
+impl MapClass {
+    pub const ALL: [MapClass; 3] = [MapClass::Unmapped, MapClass::Unique, MapClass::Multi];
+
+    pub fn of(result: &MapResult) -> MapClass {
+        match result {
+            Some((eq_class, _, _, _)) if eq_class.len() == 1 => MapClass::Unique,
+            Some((eq_class, _, _, _)) if eq_class.len() > 1 => MapClass::Multi,
+            _ => MapClass::Unmapped,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MapClass::Unmapped => "unmapped",
+            MapClass::Unique => "unique",
+            MapClass::Multi => "multi",
+        }
+    }
+}
+
+/// A read orientation with different native and WASM results
+#[derive(Clone, Debug)]
+pub struct DiscordantRead {
+    pub id: String,
+    pub strand: &'static str,
+    pub native: MapResult,
+    pub wasm: MapResult,
+}
+
+#[derive(Clone, Debug)]
+pub struct Concordance {
+    /// mapped read orientations by native (rows) and WASM (columns) class
+    pub matrix: [[usize; 3]; 3],
+    pub queries: usize,
+    /// queries whose results differ in any of equivalence class, coverage, mismatches or read length
+    pub discordant: usize,
+    /// the first `max_examples` discordant queries
+    pub examples: Vec<DiscordantRead>,
+    max_examples: usize,
+}
+
+impl Concordance {
+    pub fn new(max_examples: usize) -> Concordance {
+        Concordance {
+            matrix: [[0; 3]; 3],
+            queries: 0,
+            discordant: 0,
+            examples: Vec::new(),
+            max_examples,
+        }
+    }
+
+    pub fn record(&mut self, id: &str, strand: &'static str, native: MapResult, wasm: MapResult) {
+        self.queries += 1;
+        self.matrix[MapClass::of(&native) as usize][MapClass::of(&wasm) as usize] += 1;
+        if native != wasm {
+            self.discordant += 1;
+            if self.examples.len() < self.max_examples {
+                self.examples.push(DiscordantRead {
+                    id: id.to_string(),
+                    strand,
+                    native,
+                    wasm,
+                });
+            }
+        }
+    }
+
+    /// Tab separated matrix of native classes (rows) by WASM classes (columns)
+    pub fn write_matrix(&self, output: &mut dyn Write) -> Result<(), Error> {
+        let names: Vec<&str> = MapClass::ALL.iter().map(|c| c.name()).collect();
+        writeln!(output, "Native\\WASM\t{}", names.join("\t"))?;
+        for class in &MapClass::ALL {
+            let row: Vec<String> = self.matrix[*class as usize].iter().map(|n| n.to_string()).collect();
+            writeln!(output, "{}\t{}", class.name(), row.join("\t"))?;
+        }
+        Ok(())
+    }
+
+    /// Tab separated discordant reads, with the transcripts of each equivalence class
+    pub fn write_discordant(&self, tx_names: &[String], output: &mut dyn Write) -> Result<(), Error> {
+        writeln!(
+            output,
+            "ReadID\tStrand\tNativeTranscripts\tNativeCoverage\tNativeMismatches\tWasmTranscripts\tWasmCoverage\tWasmMismatches"
+        )?;
+        let fields = |result: &MapResult| match result {
+            Some((eq_class, coverage, mismatches, _)) => {
+                let names: Vec<&str> = eq_class
+                    .iter()
+                    .map(|t| tx_names.get(*t as usize).map_or("?", |n| n.as_str()))
+                    .collect();
+                format!("{}\t{}\t{}", names.join(","), coverage, mismatches)
+            }
+            None => "-\t-\t-".to_string(),
+        };
+        for read in &self.examples {
+            writeln!(
+                output,
+                "{}\t{}\t{}\t{}",
+                read.id,
+                read.strand,
+                fields(&read.native),
+                fields(&read.wasm)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Map every read, and its reverse complement, through both indexes
+pub fn compare_backends(
+    native: &dyn IndexLike,
+    wasm: &dyn IndexLike,
+    reads: Records,
+    params: &MapParams,
+    max_examples: usize,
+) -> Result<Concordance, Error> {
+    if native.tx_names() != wasm.tx_names() {
+        return Err(failure::err_msg(
+            "Native and WASM indexes have different transcripts, they were not built from the same reference",
+        ));
+    }
+
+    let mut concordance = Concordance::new(max_examples);
+    for record in reads {
+        let record = record?;
+        let seq = utils::dna_from_read_record(&record);
+        concordance.record(
+            record.id(),
+            "forward",
+            native.map_read(&seq, params),
+            wasm.map_read(&seq, params),
+        );
+
+        let revcomp = dna::revcomp(record.seq());
+        let seq = DnaString::from_dna_string(std::str::from_utf8(&revcomp).unwrap_or(""));
+        concordance.record(
+            record.id(),
+            "reverse",
+            native.map_read(&seq, params),
+            wasm.map_read(&seq, params),
+        );
+    }
+
+    info!(
+        "Compared {} read orientations, {} discordant",
+        concordance.queries, concordance.discordant
+    );
+    Ok(concordance)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::build_index::{build_index, export_wasm_index, IndexParams, WasmRuntimeIndex};
+    use crate::config::KmerType;
+    use crate::utils::ReadRecord;
+    use std::collections::HashMap;
+    use std::io;
+
+    #[test]
+    fn concordance_test() -> Result<(), Error> {
+        let mut concordance = Concordance::new(1);
+        concordance.record("r1", "forward", Some((vec![0], 40, 0, 50)), Some((vec![0], 40, 0, 50)));
+        concordance.record("r2", "forward", Some((vec![0, 1], 40, 1, 50)), Some((vec![0], 40, 1, 50)));
+        concordance.record("r3", "reverse", None, Some((vec![1], 20, 0, 50)));
+        concordance.record("r4", "reverse", Some((vec![1], 45, 2, 50)), Some((vec![1], 45, 1, 50)));
+
+        assert_eq!(concordance.queries, 4);
+        assert_eq!(concordance.discordant, 3);
+        assert_eq!(concordance.matrix[MapClass::Unique as usize][MapClass::Unique as usize], 2);
+        assert_eq!(concordance.matrix[MapClass::Multi as usize][MapClass::Unique as usize], 1);
+        assert_eq!(concordance.matrix[MapClass::Unmapped as usize][MapClass::Unique as usize], 1);
+        assert_eq!(concordance.examples.len(), 1);
+        assert_eq!(concordance.examples[0].id, "r2");
+
+        let mut out = Vec::new();
+        concordance.write_discordant(&["a".to_string(), "b".to_string()], &mut out)?;
+        let out = String::from_utf8(out)?;
+        assert_eq!(out.lines().nth(1), Some("r2\tforward\ta,b\t40\t1\ta\t40\t1"));
+        Ok(())
+    }
+
+    #[test]
+    fn compare_backends_test() -> Result<(), Error> {
+        let tx = "ATGGCGTACCTGAAGCTTGACCGTATCGGATCCAAGTTCGAGCTAGGCATTCGACTGACGTTAGCCATGGTACGATCAGGTC";
+        let del = format!("{}{}", &tx[..30], &tx[55..]);
+        let seqs = vec![DnaString::from_dna_string(tx), DnaString::from_dna_string(&del)];
+        let tx_names = vec!["wt".to_string(), "del1".to_string()];
+        let tx_gene_map: HashMap<String, String> =
+            tx_names.iter().map(|name| (name.clone(), "G".to_string())).collect();
+        let native = build_index::<KmerType>(&seqs, &tx_names, &tx_gene_map, &HashMap::new(), 1, &IndexParams::default())?;
+        let wasm = WasmRuntimeIndex::from_wasm_index(export_wasm_index(&native))?;
+
+        // shared, deleted region, deletion junction, one mismatch and unmapped
+        let mismatch = format!("{}A{}", &tx[40..60], &tx[61..80]);
+        let reads = vec![
+            ("shared", &tx[..40]),
+            ("wt", &tx[25..65]),
+            ("del1", &del[10..50]),
+            ("mismatch", mismatch.as_str()),
+            ("unmapped", "TTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTT"),
+        ];
+        let records: Vec<io::Result<ReadRecord>> = reads
+            .iter()
+            .map(|(id, seq)| Ok(ReadRecord { id: id.to_string(), seq: seq.as_bytes().to_vec(), qual: None }))
+            .collect();
+
+        let concordance = compare_backends(&native, &wasm, Box::new(records.into_iter()), &MapParams::default(), 10)?;
+        assert_eq!(concordance.queries, 10);
+        assert_eq!(concordance.discordant, 0, "{:?}", concordance.examples);
+        assert!(concordance.matrix[MapClass::Unique as usize][MapClass::Unique as usize] > 0);
+        assert!(concordance.matrix[MapClass::Unmapped as usize][MapClass::Unmapped as usize] > 0);
+        Ok(())
+    }
+}
@@ -2,7 +2,9 @@
 // Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
 // Copyright (c) 2021 Andrew Lonsdale tinyt 
 
-use debruijn::kmer;
+use debruijn::kmer::{self, KmerSize, VarIntKmer};
+use failure::{self, Error};
+use serde::{Deserialize, Serialize};
 
 // transcriptome fasta header formats
 pub enum FastaFormat {
@@ -26,3 +28,83 @@ pub const U32_MAX: usize = u32::max_value() as usize;
 
 pub type KmerType = kmer::Kmer20;
 
+// k-mer lengths `tinyt index --kmer-size` can build with
+pub const DEFAULT_KMER_SIZE: usize = 20;
+pub const MIN_KMER_SIZE: usize = 15;
+pub const MAX_KMER_SIZE: usize = 31;
+
+macro_rules! kmer_sizes {
+    ($($size:ident, $kmer:ident, $k:expr;)*) => {
+        $(
+            #[derive(Debug, Hash, PartialEq, PartialOrd, Eq, Ord, Copy, Clone, Serialize, Deserialize)]
+            pub struct $size;
+
+            impl KmerSize for $size {
+                fn K() -> usize {
+                    $k
+                }
+            }
+
+            pub type $kmer = VarIntKmer<u64, $size>;
+        )*
+    };
+}
+
+// k=20 is debruijn's Kmer20, the default KmerType
+kmer_sizes! {
+    K15, Kmer15, 15;
+    K16, Kmer16, 16;
+    K17, Kmer17, 17;
+    K18, Kmer18, 18;
+    K19, Kmer19, 19;
+    K21, Kmer21, 21;
+    K22, Kmer22, 22;
+    K23, Kmer23, 23;
+    K24, Kmer24, 24;
+    K25, Kmer25, 25;
+    K26, Kmer26, 26;
+    K27, Kmer27, 27;
+    K28, Kmer28, 28;
+    K29, Kmer29, 29;
+    K30, Kmer30, 30;
+    K31, Kmer31, 31;
+}
+
+pub fn check_kmer_size(k: usize) -> Result<(), Error> {
+    if k < MIN_KMER_SIZE || k > MAX_KMER_SIZE {
+        return Err(failure::err_msg(format!(
+            "Unsupported k-mer size {}, expected {} to {}",
+            k, MIN_KMER_SIZE, MAX_KMER_SIZE
+        )));
+    }
+    Ok(())
+}
+
+/// Evaluate `$body` with the type `$K` set to the k-mer type of length `$k`.
+/// `$k` must have passed `check_kmer_size`.
+#[macro_export]
+macro_rules! with_kmer_type {
+    ($k:expr, $K:ident => $body:expr) => {
+        match $k {
+            15 => { type $K = $crate::config::Kmer15; $body }
+            16 => { type $K = $crate::config::Kmer16; $body }
+            17 => { type $K = $crate::config::Kmer17; $body }
+            18 => { type $K = $crate::config::Kmer18; $body }
+            19 => { type $K = $crate::config::Kmer19; $body }
+            20 => { type $K = $crate::config::KmerType; $body }
+            21 => { type $K = $crate::config::Kmer21; $body }
+            22 => { type $K = $crate::config::Kmer22; $body }
+            23 => { type $K = $crate::config::Kmer23; $body }
+            24 => { type $K = $crate::config::Kmer24; $body }
+            25 => { type $K = $crate::config::Kmer25; $body }
+            26 => { type $K = $crate::config::Kmer26; $body }
+            27 => { type $K = $crate::config::Kmer27; $body }
+            28 => { type $K = $crate::config::Kmer28; $body }
+            29 => { type $K = $crate::config::Kmer29; $body }
+            30 => { type $K = $crate::config::Kmer30; $body }
+            31 => { type $K = $crate::config::Kmer31; $body }
+            k => panic!("Unsupported k-mer size {}", k),
+        }
+    };
+}
+
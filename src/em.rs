@@ -0,0 +1,178 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Expectation-maximisation over equivalence class counts, as in kallisto, so that reads
+//! in equivalence classes shared by several transcripts (e.g. `del4_5` and `del4_5_6`)
+//! are distributed between them rather than discarded.
+use std::collections::HashMap;
+
+use crate::build_index::IndexLike;
+
+/// Reads mapped to each equivalence class
+pub type EcCounts = HashMap<Vec<u32>, u32>;
+
+const MIN_ROUNDS: usize = 50;
+const MAX_ROUNDS: usize = 10_000;
+/// Transcripts with fewer estimated reads than this are ignored when testing convergence
+const ALPHA_CHANGE_LIMIT: f64 = 1e-2;
+/// Maximum relative change of an estimated count between rounds at convergence
+const ALPHA_CHANGE: f64 = 1e-2;
+/// Estimated counts below this are reported as zero
+const ALPHA_ZERO: f64 = 1e-8;
+
+#[derive(Debug)]
+pub struct EmResult {
+    /// estimated reads per transcript, indexed by transcript id
+    pub counts: Vec<f64>,
+    /// transcripts per million, from counts normalised by effective length
+    pub tpm: Vec<f64>,
+    pub rounds: usize,
+}
+
+/// Effective length of each transcript for reads of `read_length`, from the transcript
+/// lengths recorded in the index. Indexes built before transcript lengths were recorded
+/// fall back to the length of the longest transcript of each gene.
+pub fn effective_lengths(index: &dyn IndexLike, read_length: usize) -> Vec<f64> {
+    let tx_lengths = index.tx_lengths();
+    index
+        .tx_names()
+        .iter()
+        .enumerate()
+        .map(|(tx_id, name)| {
+            let length = match tx_lengths.get(tx_id) {
+                Some(length) => *length,
+                None => index
+                    .tx_gene_mapping()
+                    .get(name)
+                    .and_then(|gene| index.gene_length_mapping().get(gene))
+                    .cloned()
+                    .unwrap_or(0),
+            };
+            if length > read_length {
+                (length - read_length + 1) as f64
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
+/// Equivalence classes and their counts in a fixed order, so that counts can be resampled
+pub fn ec_count_list(ec_counts: &EcCounts) -> (Vec<Vec<u32>>, Vec<u32>) {
+    let mut ecs: Vec<_> = ec_counts.iter().collect();
+    ecs.sort();
+    ecs.into_iter().map(|(ec, count)| (ec.clone(), *count)).unzip()
+}
+
+/// Estimate the reads from each transcript given the reads per equivalence class
+pub fn expectation_maximisation(ecs: &[Vec<u32>], counts: &[u32], eff_lens: &[f64]) -> EmResult {
+    let n_tx = eff_lens.len();
+    let total: f64 = counts.iter().map(|c| *c as f64).sum();
+
+    let mut alpha = vec![total / n_tx.max(1) as f64; n_tx];
+    let mut next_alpha = vec![0.0; n_tx];
+    let mut rounds = 0;
+
+    while rounds < MAX_ROUNDS {
+        rounds += 1;
+        for a in next_alpha.iter_mut() {
+            *a = 0.0;
+        }
+
+        for (ec, count) in ecs.iter().zip(counts) {
+            if *count == 0 {
+                continue;
+            }
+            let denom: f64 = ec.iter().map(|t| alpha[*t as usize] / eff_lens[*t as usize]).sum();
+            if denom <= 0.0 {
+                continue;
+            }
+            for t in ec {
+                let t = *t as usize;
+                next_alpha[t] += *count as f64 * (alpha[t] / eff_lens[t]) / denom;
+            }
+        }
+
+        let converged = alpha.iter().zip(next_alpha.iter()).all(|(a, next)| {
+            *next <= ALPHA_CHANGE_LIMIT || ((next - a).abs() / next) <= ALPHA_CHANGE
+        });
+        std::mem::swap(&mut alpha, &mut next_alpha);
+
+        if converged && rounds >= MIN_ROUNDS {
+            break;
+        }
+    }
+
+    for a in alpha.iter_mut() {
+        if *a < ALPHA_ZERO {
+            *a = 0.0;
+        }
+    }
+
+    let rates: Vec<f64> = alpha.iter().zip(eff_lens).map(|(a, l)| a / l).collect();
+    let rate_total: f64 = rates.iter().sum();
+    let tpm = rates
+        .iter()
+        .map(|r| if rate_total > 0.0 { r / rate_total * 1e6 } else { 0.0 })
+        .collect();
+
+    EmResult {
+        counts: alpha,
+        tpm,
+        rounds,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::build_index::{build_index, IndexParams};
+    use crate::config::KmerType;
+    use debruijn::dna_string::DnaString;
+    use failure::Error;
+
+    #[test]
+    fn em_shared_test() {
+        // transcript 0 has 30 unique reads, transcript 1 has 10, and 40 reads are shared
+        // between them, so they should be split 3:1
+        let ecs = vec![vec![0], vec![1], vec![0, 1], vec![2]];
+        let counts = vec![30, 10, 40, 0];
+        let result = expectation_maximisation(&ecs, &counts, &[100.0, 100.0, 100.0]);
+
+        assert!((result.counts[0] - 60.0).abs() < 0.5);
+        assert!((result.counts[1] - 20.0).abs() < 0.5);
+        assert_eq!(result.counts[2], 0.0);
+        assert!((result.counts.iter().sum::<f64>() - 80.0).abs() < 1e-6);
+        assert!((result.tpm.iter().sum::<f64>() - 1e6).abs() < 1e-3);
+    }
+
+    #[test]
+    fn effective_lengths_test() -> Result<(), Error> {
+        // a transcript and a shorter deletion of the same gene, sharing most of their kmers
+        let tx = "ATGGCGTACCTGAAGCTTGACCGTATCGGATCCAAGTTCGAGCTAGGCATTCGACTGACGTTAGCCATGGTACGATCAGGTC";
+        let del = format!("{}{}", &tx[..30], &tx[55..]);
+        let seqs = vec![DnaString::from_dna_string(tx), DnaString::from_dna_string(&del)];
+        let tx_names = vec!["wt".to_string(), "del1".to_string()];
+        let tx_gene_map: HashMap<String, String> = tx_names.iter().map(|t| (t.clone(), "G".to_string())).collect();
+        let gene_length_map: HashMap<String, usize> = vec![("G".to_string(), tx.len())].into_iter().collect();
+        let index = build_index::<KmerType>(&seqs, &tx_names, &tx_gene_map, &gene_length_map, 1, &IndexParams::default())?;
+
+        let eff_lens = effective_lengths(&index, 30);
+        assert_eq!(eff_lens, vec![(tx.len() - 29) as f64, (del.len() - 29) as f64]);
+
+        // with equal unique counts, the shared reads go mostly to the shorter transcript
+        let result = expectation_maximisation(&[vec![0], vec![1], vec![0, 1]], &[10, 10, 20], &eff_lens);
+        assert!(result.counts[1] > result.counts[0]);
+        assert!(result.tpm[1] > result.tpm[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn ec_count_list_test() {
+        let mut ec_counts = EcCounts::new();
+        ec_counts.insert(vec![1, 2], 3);
+        ec_counts.insert(vec![0], 5);
+        let (ecs, counts) = ec_count_list(&ec_counts);
+        assert_eq!(ecs, vec![vec![0], vec![1, 2]]);
+        assert_eq!(counts, vec![5, 3]);
+    }
+}
@@ -0,0 +1,419 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Self-describing index files. An index file is the magic bytes, a length-prefixed
+//! versioned header recording the index kind and build settings, then the bincode
+//! payload of either a native `Pseudoaligner` or a `WasmIndex`.
+//! Files written before the header was introduced are still read as legacy indexes.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use bincode::{self, Options};
+use boomphf::hashmap::NoKeyBoomHashMap;
+use debruijn::graph::DebruijnGraph;
+use debruijn::Kmer;
+use failure::{self, Error};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::build_index::{export_wasm_index, IndexLike, IndexParams, WasmIndex, WasmRuntimeIndex};
+use crate::config::{self, KmerType, MIN_KMERS, STRANDED};
+use crate::equiv_classes::EqClassIdType;
+use crate::inspect;
+use crate::pseudoaligner::Pseudoaligner;
+
+pub const MAGIC: &[u8; 8] = b"TINYTIDX";
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexKind {
+    Native,
+    Wasm,
+}
+
+impl fmt::Display for IndexKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexKind::Native => write!(f, "native"),
+            IndexKind::Wasm => write!(f, "wasm"),
+        }
+    }
+}
+
+/// Index file header. `format_version` must stay the first field so that newer
+/// headers can be recognised before the rest is decoded.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexHeader {
+    pub format_version: u32,
+    pub kind: IndexKind,
+    pub k: usize,
+    pub stranded: bool,
+    pub min_kmers: usize,
+    /// CRC32 of the transcriptome FASTA the index was built from
+    pub fasta_checksum: Option<u32>,
+    pub tinyt_version: String,
+    pub payload_length: u64,
+    /// CRC32 of the bincode payload
+    pub payload_checksum: u32,
+}
+
+impl IndexHeader {
+    /// Header for an index built by this version of tinyt. Payload fields are
+    /// filled in by `write_index`.
+    pub fn new(kind: IndexKind, k: usize, fasta_checksum: Option<u32>) -> IndexHeader {
+        IndexHeader {
+            format_version: FORMAT_VERSION,
+            kind,
+            k,
+            stranded: STRANDED,
+            min_kmers: MIN_KMERS,
+            fasta_checksum,
+            tinyt_version: env!("CARGO_PKG_VERSION").to_string(),
+            payload_length: 0,
+            payload_checksum: 0,
+        }
+    }
+
+    /// Record the graph construction settings the index was built with
+    pub fn with_index_params(mut self, params: &IndexParams) -> IndexHeader {
+        self.min_kmers = params.min_kmers;
+        self
+    }
+}
+
+/// A native `Pseudoaligner` of any supported k-mer length
+pub trait NativeIndex: IndexLike + Send {
+    fn eq_classes(&self) -> &Vec<Vec<u32>>;
+    /// (sequence length, equivalence class) of each graph node
+    fn node_table(&self) -> Vec<(usize, Vec<u32>)>;
+    fn export_wasm(&self) -> WasmIndex;
+    fn as_index_like(&self) -> &dyn IndexLike;
+    fn into_index_like(self: Box<Self>) -> Box<dyn IndexLike>;
+}
+
+impl<K: Kmer + Sync + Send + 'static> NativeIndex for Pseudoaligner<K> {
+    fn eq_classes(&self) -> &Vec<Vec<u32>> {
+        &self.eq_classes
+    }
+
+    fn node_table(&self) -> Vec<(usize, Vec<u32>)> {
+        inspect::native_nodes(self)
+    }
+
+    fn export_wasm(&self) -> WasmIndex {
+        export_wasm_index(self)
+    }
+
+    fn as_index_like(&self) -> &dyn IndexLike {
+        self
+    }
+
+    fn into_index_like(self: Box<Self>) -> Box<dyn IndexLike> {
+        self
+    }
+}
+
+/// Native index layout of legacy files without a header, e.g. the bundled `indexes/*.tidx`,
+/// which have no transcript lengths
+#[derive(Deserialize)]
+struct LegacyPseudoaligner<K: Kmer> {
+    dbg: DebruijnGraph<K, EqClassIdType>,
+    eq_classes: Vec<Vec<u32>>,
+    dbg_index: NoKeyBoomHashMap<K, (u32, u32)>,
+    tx_names: Vec<String>,
+    tx_gene_mapping: HashMap<String, String>,
+    gene_length_mapping: HashMap<String, usize>,
+}
+
+impl<K: Kmer + Sync + Send> From<LegacyPseudoaligner<K>> for Pseudoaligner<K> {
+    fn from(index: LegacyPseudoaligner<K>) -> Pseudoaligner<K> {
+        Pseudoaligner::new(
+            index.dbg,
+            index.eq_classes,
+            index.dbg_index,
+            index.tx_names,
+            index.tx_gene_mapping,
+            index.gene_length_mapping,
+            Vec::new(),
+        )
+    }
+}
+
+/// An index read from disk, of either kind
+pub enum LoadedIndex {
+    Native(Box<dyn NativeIndex>),
+    Wasm(WasmIndex),
+}
+
+impl LoadedIndex {
+    pub fn kind(&self) -> IndexKind {
+        match self {
+            LoadedIndex::Native(_) => IndexKind::Native,
+            LoadedIndex::Wasm(_) => IndexKind::Wasm,
+        }
+    }
+
+    pub fn into_index_like(self) -> Result<Box<dyn IndexLike>, Error> {
+        match self {
+            LoadedIndex::Native(index) => Ok(index.into_index_like()),
+            LoadedIndex::Wasm(index) => Ok(Box::new(WasmRuntimeIndex::from_wasm_index(index)?)),
+        }
+    }
+}
+
+pub fn checksum(bytes: &[u8]) -> u32 {
+    crc32fast::hash(bytes)
+}
+
+/// CRC32 of a file, e.g. the FASTA an index is built from
+pub fn file_checksum<P: AsRef<Path>>(path: P) -> Result<u32, Error> {
+    let mut file = File::open(path.as_ref())?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize())
+}
+
+pub fn write_index<T: Serialize, P: AsRef<Path>>(
+    payload: &T,
+    header: IndexHeader,
+    path: P,
+) -> Result<(), Error> {
+    let mut writer = BufWriter::new(File::create(path.as_ref())?);
+    write_index_to(payload, header, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the magic bytes, header and payload of an index to `writer`
+pub fn write_index_to<T: Serialize, W: Write>(payload: &T, header: IndexHeader, mut writer: W) -> Result<(), Error> {
+    let payload_bytes = bincode::serialize(payload)?;
+    let header = IndexHeader {
+        payload_length: payload_bytes.len() as u64,
+        payload_checksum: checksum(&payload_bytes),
+        ..header
+    };
+    let header_bytes = bincode::serialize(&header)?;
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(header_bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(&header_bytes)?;
+    writer.write_all(&payload_bytes)?;
+    Ok(())
+}
+
+/// Split an index file into its header and payload, or `None` if it has no header.
+fn split_header(bytes: &[u8]) -> Result<Option<(IndexHeader, &[u8])>, Error> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+
+    let truncated = || failure::err_msg("Index file is truncated");
+    let rest = &bytes[MAGIC.len()..];
+    if rest.len() < 8 {
+        return Err(truncated());
+    }
+    let mut len_bytes = [0u8; 4];
+    len_bytes.copy_from_slice(&rest[..4]);
+    let header_length = u32::from_le_bytes(len_bytes) as usize;
+    let rest = &rest[4..];
+    if rest.len() < header_length || header_length < 4 {
+        return Err(truncated());
+    }
+
+    let mut version_bytes = [0u8; 4];
+    version_bytes.copy_from_slice(&rest[..4]);
+    let version = u32::from_le_bytes(version_bytes);
+    if version > FORMAT_VERSION {
+        return Err(failure::err_msg(format!(
+            "Index format version {} is newer than supported version {}; upgrade tinyt to read it",
+            version, FORMAT_VERSION
+        )));
+    }
+
+    let header: IndexHeader = bincode::deserialize(&rest[..header_length])?;
+    let payload = &rest[header_length..];
+
+    if payload.len() as u64 != header.payload_length {
+        return Err(failure::err_msg(format!(
+            "Index payload is {} bytes, header records {}; the file is truncated or corrupt",
+            payload.len(),
+            header.payload_length
+        )));
+    }
+    if checksum(payload) != header.payload_checksum {
+        return Err(failure::err_msg(
+            "Index payload checksum does not match the header; the file is corrupt",
+        ));
+    }
+
+    Ok(Some((header, payload)))
+}
+
+/// Decode an index written before headers were introduced, trying the native
+/// then the WASM layout. Reads are bounded by the file size.
+fn parse_legacy(bytes: &[u8]) -> Result<LoadedIndex, Error> {
+    let options = || {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(bytes.len() as u64)
+    };
+
+    if let Ok(index) = options().deserialize::<LegacyPseudoaligner<KmerType>>(bytes) {
+        warn!("Index has no tinyt header, read as a legacy native index");
+        return Ok(LoadedIndex::Native(Box::new(Pseudoaligner::from(index))));
+    }
+    if let Ok(index) = options().deserialize::<WasmIndex>(bytes) {
+        warn!("Index has no tinyt header, read as a legacy WASM index");
+        config::check_kmer_size(index.k as usize)?;
+        return Ok(LoadedIndex::Wasm(index));
+    }
+
+    Err(failure::err_msg(
+        "Not a tinyt index: no tinyt header, and not a legacy native or WASM index for this version \
+         (re-export WASM indexes with tinyt index --wasm)",
+    ))
+}
+
+/// Parse an index from bytes, detecting its kind. Returns the header, or `None`
+/// for a legacy index.
+pub fn parse_index(bytes: &[u8]) -> Result<(Option<IndexHeader>, LoadedIndex), Error> {
+    let (header, payload) = match split_header(bytes)? {
+        Some(parts) => parts,
+        None => return Ok((None, parse_legacy(bytes)?)),
+    };
+
+    let index = match header.kind {
+        IndexKind::Native => {
+            config::check_kmer_size(header.k)?;
+            crate::with_kmer_type!(header.k, K => {
+                let index: Pseudoaligner<K> = bincode::deserialize(payload)?;
+                LoadedIndex::Native(Box::new(index))
+            })
+        }
+        IndexKind::Wasm => {
+            config::check_kmer_size(header.k)?;
+            let index: WasmIndex = bincode::deserialize(payload)?;
+            if index.k as usize != header.k {
+                return Err(failure::err_msg(format!(
+                    "WASM index payload has k={}, header records k={}",
+                    index.k, header.k
+                )));
+            }
+            LoadedIndex::Wasm(index)
+        }
+    };
+
+    Ok((Some(header), index))
+}
+
+pub fn read_index<P: AsRef<Path>>(path: P) -> Result<(Option<IndexHeader>, LoadedIndex), Error> {
+    let mut bytes = Vec::new();
+    File::open(path.as_ref())
+        .and_then(|mut f| f.read_to_end(&mut bytes))
+        .map_err(|e| failure::err_msg(format!("Could not read index {}: {}", path.as_ref().display(), e)))?;
+
+    let (header, index) = parse_index(&bytes)
+        .map_err(|e| failure::err_msg(format!("Could not load index {}: {}", path.as_ref().display(), e)))?;
+
+    match &header {
+        Some(h) => info!(
+            "Read {} index (format v{}, k={}, stranded={}, min_kmers={}, tinyt {})",
+            h.kind, h.format_version, h.k, h.stranded, h.min_kmers, h.tinyt_version
+        ),
+        None => info!("Read legacy {} index", index.kind()),
+    }
+
+    Ok((header, index))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::build_index::{build_index, NO_EDGE};
+    use crate::pseudoaligner::MapParams;
+    use debruijn::dna_string::DnaString;
+    use std::collections::HashMap;
+
+    fn small_wasm_index() -> WasmIndex {
+        WasmIndex {
+            k: KmerType::k() as u8,
+            kmers: vec![(1, 0, 0), (2, 0, 1)],
+            eq_classes: vec![vec![0]],
+            tx_names: vec!["del2".to_string()],
+            tx_gene_map: HashMap::new(),
+            gene_length_map: HashMap::new(),
+            tx_lengths: vec![21],
+            node_eq: vec![0],
+            node_seqs: vec![DnaString::from_dna_string("ACGTACGTACGTACGTACGTA")],
+            left_edges: vec![[NO_EDGE; 4]],
+            right_edges: vec![[NO_EDGE; 4]],
+        }
+    }
+
+    fn encode(index: &WasmIndex) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_index_to(index, IndexHeader::new(IndexKind::Wasm, index.k as usize, None), &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip_test() -> Result<(), Error> {
+        let bytes = encode(&small_wasm_index());
+        let (header, index) = parse_index(&bytes)?;
+        assert_eq!(header.unwrap().kind, IndexKind::Wasm);
+        assert_eq!(index.kind(), IndexKind::Wasm);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_payload_test() {
+        let mut bytes = encode(&small_wasm_index());
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        assert!(parse_index(&bytes).is_err());
+        assert!(parse_index(&bytes[..last]).is_err());
+    }
+
+    #[test]
+    fn legacy_wasm_test() -> Result<(), Error> {
+        let bytes = bincode::serialize(&small_wasm_index())?;
+        let (header, index) = parse_index(&bytes)?;
+        assert!(header.is_none());
+        assert_eq!(index.kind(), IndexKind::Wasm);
+
+        // headerless indexes have their k checked too
+        let bad_k = WasmIndex { k: 40, ..small_wasm_index() };
+        assert!(parse_index(&bincode::serialize(&bad_k)?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn native_kmer_size_round_trip_test() -> Result<(), Error> {
+        let tx = "ATGGCGTACCTGAAGCTTGACCGTATCGGATCCAAGTTCGAGCTAGGCATTCGACTGACGTTAGCCATGGTACGATCAGGTC";
+        let seqs = vec![DnaString::from_dna_string(tx)];
+        let tx_names = vec!["wt".to_string()];
+        let index = build_index::<config::Kmer25>(&seqs, &tx_names, &HashMap::new(), &HashMap::new(), 1, &IndexParams::default())?;
+
+        let mut bytes = Vec::new();
+        write_index_to(&index, IndexHeader::new(IndexKind::Native, 25, None), &mut bytes)?;
+
+        let (header, loaded) = parse_index(&bytes)?;
+        assert_eq!(header.unwrap().k, 25);
+        let loaded = loaded.into_index_like()?;
+        assert_eq!(loaded.k(), 25);
+        assert_eq!(loaded.tx_lengths(), &[82][..]);
+        let read = DnaString::from_dna_string(&tx[10..70]);
+        let params = MapParams::default();
+        assert_eq!(loaded.map_read(&read, &params), IndexLike::map_read(&index, &read, &params));
+        Ok(())
+    }
+}
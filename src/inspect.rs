@@ -0,0 +1,299 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Summary statistics and equivalence class tables for native and WASM indexes.
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use debruijn::{Kmer, Mer};
+use failure::{self, Error};
+use serde::Serialize;
+
+use crate::build_index::{IndexLike, WasmIndex};
+use crate::index_file::{LoadedIndex, NativeIndex};
+use crate::pseudoaligner::Pseudoaligner;
+
+/// One row per graph node, with the transcripts of the node's equivalence class
+#[derive(Serialize, Debug)]
+pub struct EcRow {
+    pub node_id: usize,
+    pub seq_length: usize,
+    pub transcripts: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableFormat {
+    Csv,
+    Tsv,
+    Json,
+}
+
+impl TableFormat {
+    pub fn from_name(name: &str) -> Result<TableFormat, Error> {
+        match name.to_lowercase().as_str() {
+            "csv" => Ok(TableFormat::Csv),
+            "tsv" => Ok(TableFormat::Tsv),
+            "json" => Ok(TableFormat::Json),
+            _ => Err(failure::err_msg(format!(
+                "Unknown table format '{}', expected csv, tsv or json",
+                name
+            ))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct IndexSummary {
+    pub k: usize,
+    pub transcripts: usize,
+    pub genes: usize,
+    pub nodes: usize,
+    pub kmers: usize,
+    pub eq_classes: usize,
+    pub unique_eq_classes: usize,
+    pub unique_nodes: usize,
+    /// (transcript, gene, k-mers whose equivalence class is only this transcript)
+    pub unique_kmers: Vec<(String, String, usize)>,
+}
+
+/// (sequence length, equivalence class) of each node of a native index
+pub(crate) fn native_nodes<K: Kmer>(al: &Pseudoaligner<K>) -> Vec<(usize, Vec<u32>)> {
+    al.dbg
+        .iter_nodes()
+        .map(|n| (n.sequence().len(), al.eq_classes[*n.data() as usize].clone()))
+        .collect()
+}
+
+/// (sequence length, equivalence class) of each node of a WASM index, with node
+/// lengths recovered from the number of k-mers per node
+fn wasm_nodes(idx: &WasmIndex) -> Vec<(usize, Vec<u32>)> {
+    let k = idx.k as usize;
+    let mut node_kmers = vec![0usize; idx.node_eq.len()];
+    for (_, node, _) in &idx.kmers {
+        node_kmers[*node as usize] += 1;
+    }
+    node_kmers
+        .iter()
+        .zip(idx.node_eq.iter())
+        .map(|(count, eqid)| (count + k - 1, idx.eq_classes[*eqid as usize].clone()))
+        .collect()
+}
+
+fn node_table(index: &LoadedIndex) -> (usize, Vec<(usize, Vec<u32>)>) {
+    match index {
+        LoadedIndex::Native(al) => (al.k(), al.node_table()),
+        LoadedIndex::Wasm(idx) => (idx.k as usize, wasm_nodes(idx)),
+    }
+}
+
+fn index_names(index: &LoadedIndex) -> (&Vec<String>, &HashMap<String, String>, &Vec<Vec<u32>>) {
+    match index {
+        LoadedIndex::Native(al) => (al.tx_names(), al.tx_gene_mapping(), al.eq_classes()),
+        LoadedIndex::Wasm(idx) => (&idx.tx_names, &idx.tx_gene_map, &idx.eq_classes),
+    }
+}
+
+pub fn summarise(index: &LoadedIndex) -> IndexSummary {
+    let (k, nodes) = node_table(index);
+    let (tx_names, tx_gene_map, eq_classes) = index_names(index);
+
+    let mut unique_kmer_counts = vec![0usize; tx_names.len()];
+    let mut kmers = 0;
+    let mut unique_nodes = 0;
+    for (seq_length, eq) in &nodes {
+        let node_kmers = seq_length + 1 - k;
+        kmers += node_kmers;
+        if eq.len() == 1 {
+            unique_nodes += 1;
+            unique_kmer_counts[eq[0] as usize] += node_kmers;
+        }
+    }
+
+    let genes: HashSet<&String> = tx_gene_map.values().collect();
+    let unique_kmers = tx_names
+        .iter()
+        .zip(unique_kmer_counts)
+        .map(|(name, count)| {
+            let gene = tx_gene_map.get(name).cloned().unwrap_or_default();
+            (name.clone(), gene, count)
+        })
+        .collect();
+
+    IndexSummary {
+        k,
+        transcripts: tx_names.len(),
+        genes: genes.len(),
+        nodes: nodes.len(),
+        kmers,
+        eq_classes: eq_classes.len(),
+        unique_eq_classes: eq_classes.iter().filter(|eq| eq.len() == 1).count(),
+        unique_nodes,
+        unique_kmers,
+    }
+}
+
+fn to_ec_rows(nodes: Vec<(usize, Vec<u32>)>, tx_names: &[String]) -> Vec<EcRow> {
+    nodes
+        .into_iter()
+        .enumerate()
+        .map(|(node_id, (seq_length, eq))| EcRow {
+            node_id,
+            seq_length,
+            transcripts: eq.iter().map(|t| tx_names[*t as usize].clone()).collect(),
+        })
+        .collect()
+}
+
+pub fn ec_table(index: &LoadedIndex) -> Vec<EcRow> {
+    let (_, nodes) = node_table(index);
+    let (tx_names, _, _) = index_names(index);
+    to_ec_rows(nodes, tx_names)
+}
+
+/// Equivalence class table of a native index, e.g. one that has just been built
+pub fn native_ec_table<K: Kmer>(al: &Pseudoaligner<K>) -> Vec<EcRow> {
+    to_ec_rows(native_nodes(al), &al.tx_names)
+}
+
+pub fn write_ec_table<W: Write>(rows: &[EcRow], format: TableFormat, mut writer: W) -> Result<(), Error> {
+    match format {
+        TableFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, rows)?;
+            writeln!(writer)?;
+        }
+        TableFormat::Csv => {
+            writeln!(writer, "EC,SeqLength,TranscriptCount,TranscriptNames")?;
+            for row in rows {
+                writeln!(
+                    writer,
+                    "EC{},{},{},\"{}\"",
+                    row.node_id,
+                    row.seq_length,
+                    row.transcripts.len(),
+                    row.transcripts.join(",")
+                )?;
+            }
+        }
+        TableFormat::Tsv => {
+            writeln!(writer, "EC\tSeqLength\tTranscriptCount\tTranscriptNames")?;
+            for row in rows {
+                writeln!(
+                    writer,
+                    "EC{}\t{}\t{}\t{}",
+                    row.node_id,
+                    row.seq_length,
+                    row.transcripts.len(),
+                    row.transcripts.join(",")
+                )?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::build_index::{build_index, export_wasm_index, IndexParams};
+    use crate::config::KmerType;
+    use debruijn::dna_string::DnaString;
+
+    /// An 82bp transcript and a copy with a 25bp deletion, both of gene G
+    fn small_index() -> Result<Pseudoaligner<KmerType>, Error> {
+        let tx = "ATGGCGTACCTGAAGCTTGACCGTATCGGATCCAAGTTCGAGCTAGGCATTCGACTGACGTTAGCCATGGTACGATCAGGTC";
+        let del = format!("{}{}", &tx[..30], &tx[55..]);
+        let seqs = vec![DnaString::from_dna_string(tx), DnaString::from_dna_string(&del)];
+        let tx_names = vec!["wt".to_string(), "del1".to_string()];
+        let tx_gene_map: HashMap<String, String> =
+            tx_names.iter().map(|name| (name.clone(), "G".to_string())).collect();
+        build_index::<KmerType>(&seqs, &tx_names, &tx_gene_map, &HashMap::new(), 1, &IndexParams::default())
+    }
+
+    #[test]
+    fn summarise_test() -> Result<(), Error> {
+        let index = small_index()?;
+        let wasm = LoadedIndex::Wasm(export_wasm_index(&index));
+        let native = LoadedIndex::Native(Box::new(index));
+
+        for loaded in &[native, wasm] {
+            let summary = summarise(loaded);
+            assert_eq!(summary.k, KmerType::k());
+            assert_eq!(summary.transcripts, 2);
+            assert_eq!(summary.genes, 1);
+            // the shared start and end of the transcripts, the deleted region of wt and
+            // the deletion junction of del1
+            assert_eq!(summary.nodes, 4);
+            assert_eq!(summary.kmers, 81);
+            assert_eq!(summary.eq_classes, 3);
+            assert_eq!(summary.unique_eq_classes, 2);
+            assert_eq!(summary.unique_nodes, 2);
+            assert_eq!(
+                summary.unique_kmers,
+                vec![
+                    ("wt".to_string(), "G".to_string(), 43),
+                    ("del1".to_string(), "G".to_string(), 18)
+                ]
+            );
+
+            let mut rows: Vec<(usize, Vec<String>)> =
+                ec_table(loaded).into_iter().map(|row| (row.seq_length, row.transcripts)).collect();
+            rows.sort();
+            assert_eq!(
+                rows,
+                vec![
+                    (27, vec!["wt".to_string(), "del1".to_string()]),
+                    (31, vec!["wt".to_string(), "del1".to_string()]),
+                    (37, vec!["del1".to_string()]),
+                    (62, vec!["wt".to_string()]),
+                ]
+            );
+        }
+        Ok(())
+    }
+
+    fn rows() -> Vec<EcRow> {
+        vec![
+            EcRow {
+                node_id: 0,
+                seq_length: 31,
+                transcripts: vec!["wt".to_string(), "del1".to_string()],
+            },
+            EcRow {
+                node_id: 1,
+                seq_length: 62,
+                transcripts: vec!["wt".to_string()],
+            },
+        ]
+    }
+
+    fn table(format: TableFormat) -> Result<String, Error> {
+        let mut out = Vec::new();
+        write_ec_table(&rows(), format, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn write_ec_table_test() -> Result<(), Error> {
+        assert_eq!(
+            table(TableFormat::Csv)?,
+            "EC,SeqLength,TranscriptCount,TranscriptNames\nEC0,31,2,\"wt,del1\"\nEC1,62,1,\"wt\"\n"
+        );
+        assert_eq!(
+            table(TableFormat::Tsv)?,
+            "EC\tSeqLength\tTranscriptCount\tTranscriptNames\nEC0\t31\t2\twt,del1\nEC1\t62\t1\twt\n"
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&table(TableFormat::Json)?)?;
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"node_id": 0, "seq_length": 31, "transcripts": ["wt", "del1"]},
+                {"node_id": 1, "seq_length": 62, "transcripts": ["wt"]}
+            ])
+        );
+
+        assert_eq!(TableFormat::from_name("TSV")?, TableFormat::Tsv);
+        assert!(TableFormat::from_name("xlsx").is_err());
+        Ok(())
+    }
+}
@@ -1,10 +1,24 @@
 // Copyright (c) 2018 10x Genomics, Inc. All rights reserved.
 // Copyright (c) 2021 Andrew Lonsdale tinyt
 
+#[cfg(feature = "htslib")]
+pub mod alignment;
+pub mod annotation;
+pub mod batch;
+pub mod bootstrap;
 pub mod build_index;
+pub mod call;
+pub mod compare;
 pub mod config;
+pub mod em;
 
 pub mod equiv_classes;
+pub mod index_file;
+pub mod inspect;
+pub mod normals;
 pub mod pseudoaligner;
+pub mod read_output;
+pub mod reference;
+pub mod report;
 pub mod scatter;
 pub mod utils;
@@ -0,0 +1,165 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Panel of normals: the background proportion of each deletion in known negative
+//! samples, from alternative splicing and mapping noise, to compare a sample against.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use failure::{self, Error};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::call::CountsTable;
+
+/// Proportions of one deletion over the normal samples
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeletionBackground {
+    pub gene: String,
+    pub deletion: String,
+    pub proportions: Vec<f64>,
+    pub mean: f64,
+    pub sd: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PanelOfNormals {
+    pub tinyt_version: String,
+    /// column the supporting reads were read from, e.g. `Count`
+    pub count_column: String,
+    pub samples: Vec<String>,
+    pub deletions: Vec<DeletionBackground>,
+}
+
+/// A sample's deletion proportion relative to the panel of normals
+#[derive(Clone, Copy, Debug)]
+pub struct NormalComparison {
+    pub samples: usize,
+    pub mean: f64,
+    pub sd: f64,
+    /// `None` if the normals have no spread
+    pub z_score: Option<f64>,
+    /// fraction of normals (with one pseudocount) at or above the sample's proportion
+    pub empirical_p: f64,
+}
+
+impl DeletionBackground {
+    pub fn compare(&self, proportion: f64) -> NormalComparison {
+        let at_or_above = self.proportions.iter().filter(|p| **p >= proportion).count();
+        NormalComparison {
+            samples: self.proportions.len(),
+            mean: self.mean,
+            sd: self.sd,
+            z_score: if self.sd > 0.0 {
+                Some((proportion - self.mean) / self.sd)
+            } else {
+                None
+            },
+            empirical_p: (at_or_above + 1) as f64 / (self.proportions.len() + 1) as f64,
+        }
+    }
+}
+
+impl PanelOfNormals {
+    /// Fit the background from (sample name, `tinyt map` results) of negative samples
+    pub fn build(tables: &[(String, CountsTable)], count_column: &str) -> Result<PanelOfNormals, Error> {
+        let mut order = Vec::new();
+        let mut proportions: HashMap<(String, String), Vec<f64>> = HashMap::new();
+
+        for (sample, table) in tables {
+            for count in table.deletion_counts(count_column)? {
+                if count.total == 0 {
+                    warn!("{} has no mapped reads for {}, skipped", sample, count.deletion);
+                    continue;
+                }
+                let key = (count.gene, count.deletion);
+                if !proportions.contains_key(&key) {
+                    order.push(key.clone());
+                }
+                proportions
+                    .entry(key)
+                    .or_insert_with(Vec::new)
+                    .push(count.count as f64 / count.total as f64);
+            }
+        }
+
+        let deletions = order
+            .into_iter()
+            .map(|key| {
+                let values = proportions.remove(&key).unwrap_or_default();
+                let n = values.len() as f64;
+                let mean = values.iter().sum::<f64>() / n;
+                let sd = if values.len() > 1 {
+                    (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+                } else {
+                    0.0
+                };
+                DeletionBackground {
+                    gene: key.0,
+                    deletion: key.1,
+                    proportions: values,
+                    mean,
+                    sd,
+                }
+            })
+            .collect();
+
+        Ok(PanelOfNormals {
+            tinyt_version: env!("CARGO_PKG_VERSION").to_string(),
+            count_column: count_column.to_string(),
+            samples: tables.iter().map(|(sample, _)| sample.clone()).collect(),
+            deletions,
+        })
+    }
+
+    pub fn get(&self, gene: &str, deletion: &str) -> Option<&DeletionBackground> {
+        self.deletions
+            .iter()
+            .find(|d| d.gene == gene && d.deletion == deletion)
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(path.as_ref())?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<PanelOfNormals, Error> {
+        let file = File::open(path.as_ref()).map_err(|e| {
+            failure::err_msg(format!("Could not open panel of normals {}: {}", path.as_ref().display(), e))
+        })?;
+        serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+            failure::err_msg(format!("Could not read panel of normals {}: {}", path.as_ref().display(), e))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn panel_test() -> Result<(), Error> {
+        let tables: Vec<(String, CountsTable)> = [1, 2, 3]
+            .iter()
+            .map(|c| {
+                let csv = format!("Gene,Deletion,Count,Total\nIKZF1,del4_7,{},1000\n", c);
+                (format!("normal{}", c), CountsTable::from_reader(csv.as_bytes()).unwrap())
+            })
+            .collect();
+        let panel = PanelOfNormals::build(&tables, "Count")?;
+        let background = panel.get("IKZF1", "del4_7").unwrap();
+        assert_eq!(background.proportions.len(), 3);
+        assert!((background.mean - 0.002).abs() < 1e-12);
+
+        let high = background.compare(0.05);
+        assert!(high.z_score.unwrap() > 10.0);
+        assert_eq!(high.empirical_p, 0.25);
+        assert_eq!(background.compare(0.001).empirical_p, 1.0);
+        assert!(panel.get("IKZF1", "del2").is_none());
+        Ok(())
+    }
+}
@@ -5,14 +5,14 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 use std::io::{self, Write};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
-use std::{self, fs::File, str};
+use std::{self, str};
 use std::fs::OpenOptions;
 
-use bio::io::fastq;
 use boomphf::hashmap::NoKeyBoomHashMap;
 use crossbeam_utils::thread::scope;
-use debruijn::dna_string::DnaString;
+use debruijn::dna_string::{DnaString, DnaStringSlice};
 
 use debruijn::graph::DebruijnGraph;
 use debruijn::{Dir, Kmer, Mer, Vmer};
@@ -24,10 +24,62 @@ use itertools::Itertools;  // itertools = "0.8"
 
 use crate::config::{LEFT_EXTEND_FRACTION, READ_COVERAGE_THRESHOLD, DEFAULT_ALLOWED_MISMATCHES, TRIM_VAL};
 use crate::equiv_classes::EqClassIdType;
-use crate::utils;
+use crate::utils::{self, ReadInput, ReadRecord};
 use crate::build_index::{IndexLike};
+use crate::bootstrap::{self, BootstrapParams};
+use crate::em::{self, EcCounts};
+use crate::read_output::{ReadFiles, ReadOutputs};
+use crate::report::{self, BootstrapIntervals, MappingStatistics, MappingSummary, ReportOptions, TranscriptRow};
+
+
+
+/// Thresholds used when mapping reads, defaulting to the values in `config`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct MapParams {
+    /// mismatches allowed while extending a read along the graph
+    pub mismatches: usize,
+    /// minimum bases of a read covered by the graph for a unique hit
+    pub min_coverage: usize,
+    /// minimum fraction of the read length covered for a unique hit, 0 to disable
+    pub min_coverage_fraction: f64,
+    /// position of the first kmer found, as a fraction of the read length, from which the read is extended back to its start
+    pub left_extend_fraction: f64,
+}
+
+impl Default for MapParams {
+    fn default() -> MapParams {
+        MapParams {
+            mismatches: DEFAULT_ALLOWED_MISMATCHES,
+            min_coverage: READ_COVERAGE_THRESHOLD,
+            min_coverage_fraction: 0.0,
+            left_extend_fraction: LEFT_EXTEND_FRACTION,
+        }
+    }
+}
 
+impl MapParams {
+    /// Bases a read of `read_length` must have covered: the larger of the absolute and fractional thresholds
+    pub fn coverage_threshold(&self, read_length: usize) -> usize {
+        let fractional = (self.min_coverage_fraction * read_length as f64).ceil() as usize;
+        self.min_coverage.max(fractional)
+    }
+
+    pub fn is_covered(&self, coverage: usize, read_length: usize) -> bool {
+        coverage >= self.coverage_threshold(read_length)
+    }
 
+    pub fn validate(&self) -> Result<(), Error> {
+        for (name, fraction) in &[
+            ("--min-coverage-fraction", self.min_coverage_fraction),
+            ("--left-extend-fraction", self.left_extend_fraction),
+        ] {
+            if !(0.0..=1.0).contains(fraction) {
+                return Err(failure::err_msg(format!("{} must be between 0 and 1, got {}", name, fraction)));
+            }
+        }
+        Ok(())
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Pseudoaligner<K: Kmer> {
@@ -37,6 +89,8 @@ pub struct Pseudoaligner<K: Kmer> {
     pub tx_names: Vec<String>,
     pub tx_gene_mapping: HashMap<String, String>,
     pub gene_length_mapping: HashMap<String, usize>,
+    /// length of each transcript by id, empty for indexes built before lengths were recorded
+    pub tx_lengths: Vec<usize>,
 }
 
 // pub trait IndexLike: Sync {
@@ -56,6 +110,7 @@ impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
         tx_names: Vec<String>,
         tx_gene_mapping: HashMap<String, String>,
         gene_length_mapping: HashMap<String, usize>,
+        tx_lengths: Vec<usize>,
     ) -> Pseudoaligner<K> {
         Pseudoaligner {
             dbg,
@@ -64,6 +119,7 @@ impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
             tx_names,
             tx_gene_mapping,
             gene_length_mapping,
+            tx_lengths,
         }
     }
 
@@ -78,347 +134,386 @@ impl<K: Kmer + Sync + Send> Pseudoaligner<K> {
 
     /// Pseudo-align `read_seq` and return a list of nodes that the read was aligned to, with configurable # of allowed mismatches
     pub fn map_read_to_nodes_with_mismatch(&self, read_seq: &DnaString, nodes: &mut Vec<usize>, allowed_mismatches: usize) -> Option<(usize, usize, usize)> {
-        let read_length = read_seq.len();
-        let mut read_coverage: usize = 0;
-        let mut mismatch_count: usize = 0;
+        let params = MapParams { mismatches: allowed_mismatches, ..MapParams::default() };
+        map_read_to_graph_nodes(self, read_seq, nodes, &params)
+    }
 
-        // We're filling out nodes
-        nodes.clear();
+    /// Convert a list of nodes contacted by a read into an equivalence class.
+    /// Supply node list in `nodes`. Equivalence class will be written to `eq_class`.
+    pub fn nodes_to_eq_class(&self, nodes: &mut Vec<usize>, eq_class: &mut Vec<u32>) {
+        graph_nodes_to_eq_class(self, nodes, eq_class)
+    }
 
-        let left_extend_threshold = (LEFT_EXTEND_FRACTION * read_length as f64) as usize;
+    /// Pseudoalign the `read_seq` to the graph. Returns a tuple of the
+    /// eqivalence class, the number of bases aligned on success,
+    /// and the number of mismatched bases, or None is no alignment could be found.
+    pub fn map_read_with_mismatch(&self, read_seq: &DnaString, allowed_mismatches: usize) -> Option<(Vec<u32>, usize, usize,usize)> {
+        let params = MapParams { mismatches: allowed_mismatches, ..MapParams::default() };
+        map_read_to_graph(self, read_seq, &params)
+    }
 
-        let mut kmer_pos: usize = 0;
-        let kmer_length = K::k();
+    /// Pseudoalign the `read_seq` to the graph with # mismatches = 2. Returns a tuple of the
+    /// eqivalence class and the number of bases aligned on success, and number of mismatches
+    /// or None is no alignment could be found.
+    pub fn map_read(&self, read_seq: &DnaString, mismatch_size: usize) -> Option<(Vec<u32>, usize, usize, usize)> {
+      match self.map_read_with_mismatch(read_seq, mismatch_size) {
+        Some((eq_class, read_coverage, mismatches, read_length)) => Some((eq_class, read_coverage,mismatches, read_length)),
+        None => None,
+      }
+    }
+}
 
-        if read_seq.len() < kmer_length {
-            return None;
-        }
 
-        let last_kmer_pos = read_length - kmer_length;
-        let mut kmer_lookups = 0;
+impl<K: Kmer + Sync + Send> IndexLike for Pseudoaligner<K> {
+    fn map_read(&self, read_seq: &DnaString, params: &MapParams) -> Option<(Vec<u32>, usize, usize, usize)> {
+        map_read_to_graph(self, read_seq, params)
+    }
+    fn k(&self) -> usize {
+        K::k()
+    }
+    fn tx_names(&self) -> &Vec<String> {
+        &self.tx_names
+    }
+    fn tx_gene_mapping(&self) -> &HashMap<String, String> {
+        &self.tx_gene_mapping
+    }
+    fn gene_length_mapping(&self) -> &HashMap<String, usize> {
+        &self.gene_length_mapping
+    }
+    fn tx_lengths(&self) -> &[usize] {
+        &self.tx_lengths
+    }
+}
+
 
-        {
-            // Scan the read for the first kmer that exists in the reference
-            let mut find_kmer_match = |kmer_pos: &mut usize| -> Option<(usize, usize)> {
-                while *kmer_pos <= last_kmer_pos {
-                    let read_kmer = read_seq.get_kmer(*kmer_pos);
 
-                    kmer_lookups += 1;
-                    match self.dbg_index.get(&read_kmer) {
-                        None => (),
-                        Some((nid, offset)) => {
-                            // Verify that the kmer actually matches -- the MPHF can have false
-                            // positives.
-                            let node = self.dbg.get_node(*nid as usize);
-                            let ref_seq_slice = node.sequence();
-                            let ref_kmer: K = ref_seq_slice.get_kmer(*offset as usize);
-
-                            if read_kmer == ref_kmer {
-                                return Some((*nid as usize, *offset as usize));
-                            }
-                        }
-                    };
-                    *kmer_pos += 1;
-                }
+/// The de Bruijn graph structure read mapping walks: node sequences, their left and
+/// right extensions and equivalence classes. Native and WASM indexes both implement it,
+/// so reads map identically with either kind of index.
+pub trait ReadGraph {
+    fn kmer_length(&self) -> usize;
 
-                None
-            };
+    /// Node and offset of an exact match of the read kmer starting at `pos`
+    fn find_kmer(&self, read_seq: &DnaString, pos: usize) -> Option<(usize, usize)>;
 
-            // extract the first exact matching position of a kmer
-            // from the read in the DBG
-            let (mut node_id, mut kmer_offset) = match find_kmer_match(&mut kmer_pos) {
-                None => (None, None),
-                Some((nid, offset)) => (Some(nid), Some(offset)),
-            };
+    fn node_sequence(&self, node_id: usize) -> DnaStringSlice<'_>;
 
-	    debug!(" kmer pos is {:?}",kmer_pos);
+    /// Node reached by extending `node_id` with `base` in direction `dir`, if that edge exists
+    fn extension(&self, node_id: usize, dir: Dir, base: u8) -> Option<usize>;
 
+    fn node_eq_class(&self, node_id: usize) -> &[u32];
+}
 
+impl<K: Kmer + Sync + Send> ReadGraph for Pseudoaligner<K> {
+    fn kmer_length(&self) -> usize {
+        K::k()
+    }
 
-            // check if we can extend back if there were SNP in every kmer query
-            if kmer_pos >= left_extend_threshold && node_id.is_some() {
-                let mut last_pos = kmer_pos - 1;
-                let mut prev_node_id = node_id.unwrap();
-                let mut prev_kmer_offset = if kmer_offset.unwrap() > 0 {
-                    kmer_offset.unwrap() - 1
-                } else {
-                    0
-                };
+    fn find_kmer(&self, read_seq: &DnaString, pos: usize) -> Option<(usize, usize)> {
+        let read_kmer: K = read_seq.get_kmer(pos);
+        let (nid, offset) = self.dbg_index.get(&read_kmer)?;
 
-                loop {
-                    let node = self.dbg.get_node(prev_node_id);
-                    debug!("{:?}, {:?}, {:?}, {:?}, {:?}",
-                             node, node.sequence(),
-                             self.eq_classes[ *node.data() as usize],
-                             prev_kmer_offset, last_pos);
-
-                    // length of remaining read before kmer match
-                    let skipped_read = last_pos + 1;
-
-                    // length of the skipped node sequence before kmer match
-                    let skipped_ref = prev_kmer_offset + 1;
-
-                    // find maximum extention possbile before fork or eof read
-                    let max_matchable_pos = std::cmp::min(skipped_read, skipped_ref);
-
-                    let ref_seq_slice = node.sequence();
-                    let mut premature_break = false;
-                    let mut matched_bases = 0;
-                    let mut seen_snp = 0;
-                    for idx in 0..max_matchable_pos {
-                        let ref_pos = prev_kmer_offset - idx;
-                        let read_offset = last_pos - idx;
-
-                        // compare base by base
-                        if ref_seq_slice.get(ref_pos) != read_seq.get(read_offset) {
-                            // Record mismatch
-                            mismatch_count += 1;
-
-                            // Allowing num_mismatch-SNP
-                            seen_snp += 1;
-                            if seen_snp > allowed_mismatches {
-                                premature_break = true;
-                                break;
-                            }
-                        }
+        // Verify that the kmer actually matches -- the MPHF can have false
+        // positives.
+        let node = self.dbg.get_node(*nid as usize);
+        let ref_kmer: K = node.sequence().get_kmer(*offset as usize);
+        if read_kmer == ref_kmer {
+            Some((*nid as usize, *offset as usize))
+        } else {
+            None
+        }
+    }
 
-                        matched_bases += 1;
-                        read_coverage += 1;
-                    }
+    fn node_sequence(&self, node_id: usize) -> DnaStringSlice<'_> {
+        self.dbg.get_node(node_id).sequence()
+    }
 
-                    //break the loop if end of read reached or a premature mismatch
-                    if last_pos + 1 - matched_bases == 0 || premature_break {
-                        break;
-                    }
+    fn extension(&self, node_id: usize, dir: Dir, base: u8) -> Option<usize> {
+        let node = self.dbg.get_node(node_id);
+        let exts = node.exts();
+        if !exts.has_ext(dir, base) {
+            return None;
+        }
+        let index = exts.get(dir).iter().position(|&x| x == base).unwrap();
+        let edges = match dir {
+            Dir::Left => node.l_edges(),
+            Dir::Right => node.r_edges(),
+        };
+        Some(edges[index].0)
+    }
 
-                    // adjust last position
-                    last_pos -= matched_bases;
-
-                    // If reached here then a fork is found in the reference.
-                    let exts = node.exts();
-                    let next_base = read_seq.get(last_pos);
-                    if exts.has_ext(Dir::Left, next_base) {
-                        // found a left extention.
-                        let index = exts
-                            .get(Dir::Left)
-                            .iter()
-                            .position(|&x| x == next_base)
-                            .unwrap();
-
-                        let edge = node.l_edges()[index];
-
-                        //update the previous node's id
-                        prev_node_id = edge.0;
-                        let prev_node = self.dbg.get_node(prev_node_id);
-                        prev_kmer_offset = prev_node.sequence().len() - kmer_length;
-
-                        // extract colors
-                        nodes.push(prev_node.node_id);
-                    } else {
-                        break;
-                    }
-                } // end-loop
+    fn node_eq_class(&self, node_id: usize) -> &[u32] {
+        let eqclass_id = self.dbg.get_node(node_id).data();
+        &self.eq_classes[*eqclass_id as usize]
+    }
+}
 
-		} //end-if
+/// Pseudo-align `read_seq` to `graph` and return a list of nodes that the read was aligned to,
+/// with the read coverage, mismatches and read length.
+pub fn map_read_to_graph_nodes<G: ReadGraph + ?Sized>(
+    graph: &G,
+    read_seq: &DnaString,
+    nodes: &mut Vec<usize>,
+    params: &MapParams,
+) -> Option<(usize, usize, usize)> {
+    let allowed_mismatches = params.mismatches;
+    let read_length = read_seq.len();
+    let mut read_coverage: usize = 0;
+    let mut mismatch_count: usize = 0;
+
+    // We're filling out nodes
+    nodes.clear();
+
+    let left_extend_threshold = (params.left_extend_fraction * read_length as f64) as usize;
+
+    let mut kmer_pos: usize = 0;
+    let kmer_length = graph.kmer_length();
+
+    if read_seq.len() < kmer_length {
+        return None;
+    }
 
-		// could enforce coverage minimum but for varying read lengths, easier to require first match to be close to start of read 
-                // could set to mismatch value
-            // forward search
-            if kmer_pos <= last_kmer_pos {
-                loop {
-                    let node = self.dbg.get_node(node_id.unwrap());
-                    debug!("{:?}, {:?}, {:?}, {:?}",
-                             node, node.sequence(),
-                             self.eq_classes[ *node.data() as usize],
-                             kmer_offset);
-                    kmer_pos += kmer_length;
-                    read_coverage += kmer_length;
+    let last_kmer_pos = read_length - kmer_length;
 
-                    // extract colors
-                    nodes.push(node.node_id);
-
-                    // length of remaining read after kmer match
-                    let remaining_read = read_length - kmer_pos;
-
-                    // length of the remaining node sequence after kmer match
-                    let ref_seq_slice = node.sequence();
-                    let ref_length = ref_seq_slice.len();
-                    let ref_offset = kmer_offset.unwrap() + kmer_length;
-                    let informative_ref = ref_length - ref_offset;
-
-                    // find maximum extention possbile before fork or eof read
-                    let max_matchable_pos = std::cmp::min(remaining_read, informative_ref);
-
-                    let mut premature_break = false;
-                    let mut matched_bases = 0;
-                    let mut seen_snp = 0;
-                    for idx in 0..max_matchable_pos {
-                        let ref_pos = ref_offset + idx;
-                        let read_offset = kmer_pos + idx;
-
-                        // compare base by base
-                        if ref_seq_slice.get(ref_pos) != read_seq.get(read_offset) {
-                            // Record mismatch
-                            mismatch_count += 1;
-
-                            // Allowing num_mismatch-SNP
-                            seen_snp += 1;
-                            if seen_snp > allowed_mismatches {
-                                premature_break = true;
-                                break;
-                            }
-                        }
+    // Scan the read for the first kmer that exists in the reference
+    let find_kmer_match = |kmer_pos: &mut usize| -> Option<(usize, usize)> {
+        while *kmer_pos <= last_kmer_pos {
+            if let Some(hit) = graph.find_kmer(read_seq, *kmer_pos) {
+                return Some(hit);
+            }
+            *kmer_pos += 1;
+        }
+        None
+    };
 
-                        matched_bases += 1;
-                        read_coverage += 1;
-                    }
+    // extract the first exact matching position of a kmer
+    // from the read in the DBG
+    let (mut node_id, mut kmer_offset) = match find_kmer_match(&mut kmer_pos) {
+        None => (None, None),
+        Some((nid, offset)) => (Some(nid), Some(offset)),
+    };
 
-                    kmer_pos += matched_bases;
-                    //break the loop if end of read reached or a premature mismatch
-                    if kmer_pos >= read_length {
-                        break;
-                    }
+    debug!(" kmer pos is {:?}", kmer_pos);
 
-                    // If reached here then a fork is found in the reference.
-                    let exts = node.exts();
-                    let next_base = read_seq.get(kmer_pos);
-
-                    if !premature_break && exts.has_ext(Dir::Right, next_base) {
-                        // found a right extention.
-                        let index = exts
-                            .get(Dir::Right)
-                            .iter()
-                            .position(|&x| x == next_base)
-                            .unwrap();
-
-                        let edge = node.r_edges()[index];
-
-                        //update the next node's id
-                        node_id = Some(edge.0);
-                        kmer_offset = Some(0);
-
-                        //adjust for kmer_position
-                        kmer_pos -= kmer_length - 1;
-                        read_coverage -= kmer_length - 1;
-                    } else {
-                        // can't extend node in dbg extract read using mphf
-                        // TODO: might have to check some cases
-                        if kmer_pos > last_kmer_pos {
-                            // can't search in mphf if no full kmer can be made
-                            break;
-                        }
+    // check if we can extend back if there were SNP in every kmer query
+    if kmer_pos > 0 && kmer_pos >= left_extend_threshold && node_id.is_some() {
+        let mut last_pos = kmer_pos - 1;
+        let mut prev_node_id = node_id.unwrap();
+        let mut prev_kmer_offset = if kmer_offset.unwrap() > 0 {
+            kmer_offset.unwrap() - 1
+        } else {
+            0
+        };
 
-                        // get the match through mphf
-                        match find_kmer_match(&mut kmer_pos) {
-                            None => break,
-                            Some((nid, offset)) => {
-                                node_id = Some(nid);
-                                kmer_offset = Some(offset);
-                            }
-                        };
+        loop {
+            let ref_seq_slice = graph.node_sequence(prev_node_id);
+            debug!("{:?}, {:?}, {:?}, {:?}, {:?}",
+                     prev_node_id, ref_seq_slice,
+                     graph.node_eq_class(prev_node_id),
+                     prev_kmer_offset, last_pos);
+
+            // length of remaining read before kmer match
+            let skipped_read = last_pos + 1;
+
+            // length of the skipped node sequence before kmer match
+            let skipped_ref = prev_kmer_offset + 1;
+
+            // find maximum extention possbile before fork or eof read
+            let max_matchable_pos = std::cmp::min(skipped_read, skipped_ref);
+
+            let mut premature_break = false;
+            let mut matched_bases = 0;
+            let mut seen_snp = 0;
+            for idx in 0..max_matchable_pos {
+                let ref_pos = prev_kmer_offset - idx;
+                let read_offset = last_pos - idx;
+
+                // compare base by base
+                if ref_seq_slice.get(ref_pos) != read_seq.get(read_offset) {
+                    // Record mismatch
+                    mismatch_count += 1;
+
+                    // Allowing num_mismatch-SNP
+                    seen_snp += 1;
+                    if seen_snp > allowed_mismatches {
+                        premature_break = true;
+                        break;
                     }
-                } // end-loop
-            } //end-if
+                }
 
-        }
+                matched_bases += 1;
+                read_coverage += 1;
+            }
 
-        if nodes.len() == 0 {
-            if read_coverage != 0 {
-                panic!(
-                    "Different read coverage {:?} than num of eqclasses {:?}",
-                    nodes.len(),
-                    read_coverage
-                );
+            //break the loop if end of read reached or a premature mismatch
+            if last_pos + 1 - matched_bases == 0 || premature_break {
+                break;
             }
-            //println!("lookups: {} -- no hit", kmer_lookups);
-            None
-        } else {
-            //println!("lookups: {} -- cov: {}", kmer_lookups, read_coverage);
-            Some((read_coverage, mismatch_count,read_length))
-        }
-    }
 
-    /// Convert a list of nodes contacted by a read into an equivalence class.
-    /// Supply node list in `nodes`. Equivalence class will be written to `eq_class`.
-    pub fn nodes_to_eq_class(&self, nodes: &mut Vec<usize>, eq_class: &mut Vec<u32>) {
-        eq_class.clear();
+            // adjust last position
+            last_pos -= matched_bases;
 
-        if nodes.len() == 0 {
-            return;
-        }
+            // If reached here then a fork is found in the reference.
+            match graph.extension(prev_node_id, Dir::Left, read_seq.get(last_pos)) {
+                Some(left_node_id) => {
+                    // found a left extention, update the previous node's id
+                    prev_node_id = left_node_id;
+                    prev_kmer_offset = graph.node_sequence(prev_node_id).len() - kmer_length;
 
-        // Sort nodes to get the shorter equivalence class first.
-        nodes.sort_by_key(|n| {
-            let eqclass_id = self.dbg.get_node(*n).data();
-            self.eq_classes[*eqclass_id as usize].len()
-        });
+                    // extract colors
+                    nodes.push(prev_node_id);
+                }
+                None => break,
+            }
+        } // end-loop
+    } //end-if
+
+    // could enforce coverage minimum but for varying read lengths, easier to require first match to be close to start of read
+    // could set to mismatch value
+    // forward search
+    if kmer_pos <= last_kmer_pos {
+        loop {
+            let current_node_id = node_id.unwrap();
+            let ref_seq_slice = graph.node_sequence(current_node_id);
+            debug!("{:?}, {:?}, {:?}, {:?}",
+                     current_node_id, ref_seq_slice,
+                     graph.node_eq_class(current_node_id),
+                     kmer_offset);
+            kmer_pos += kmer_length;
+            read_coverage += kmer_length;
+
+            // extract colors
+            nodes.push(current_node_id);
+
+            // length of remaining read after kmer match
+            let remaining_read = read_length - kmer_pos;
+
+            // length of the remaining node sequence after kmer match
+            let ref_length = ref_seq_slice.len();
+            let ref_offset = kmer_offset.unwrap() + kmer_length;
+            let informative_ref = ref_length - ref_offset;
+
+            // find maximum extention possbile before fork or eof read
+            let max_matchable_pos = std::cmp::min(remaining_read, informative_ref);
+
+            let mut premature_break = false;
+            let mut matched_bases = 0;
+            let mut seen_snp = 0;
+            for idx in 0..max_matchable_pos {
+                let ref_pos = ref_offset + idx;
+                let read_offset = kmer_pos + idx;
+
+                // compare base by base
+                if ref_seq_slice.get(ref_pos) != read_seq.get(read_offset) {
+                    // Record mismatch
+                    mismatch_count += 1;
+
+                    // Allowing num_mismatch-SNP
+                    seen_snp += 1;
+                    if seen_snp > allowed_mismatches {
+                        premature_break = true;
+                        break;
+                    }
+                }
 
-        let _lens: Vec<_> = nodes
-            .iter()
-            .map(|n| {
-                let eqclass_id = self.dbg.get_node(*n).data();
-                self.eq_classes[*eqclass_id as usize].len()
-            })
-            .collect();
-        //println!("nodes: {:?}, lens: {:?}", nodes, lens);
+                matched_bases += 1;
+                read_coverage += 1;
+            }
 
-        // Intersect the equivalence classes
-        let first_node = nodes[0];
+            kmer_pos += matched_bases;
+            //break the loop if end of read reached or a premature mismatch
+            if kmer_pos >= read_length {
+                break;
+            }
 
-        //println!("node: {}, seq: {:?}", first_node,  self.dbg.get_node(first_node).sequence());
-        let first_color = self.dbg.get_node(first_node).data();
-        eq_class.extend(&self.eq_classes[*first_color as usize]);
+            // If reached here then a fork is found in the reference.
+            let next_base = read_seq.get(kmer_pos);
+            let right_node_id = if premature_break {
+                None
+            } else {
+                graph.extension(current_node_id, Dir::Right, next_base)
+            };
 
-        for node in nodes.iter().skip(1) {
-            let color = self.dbg.get_node(*node).data();
-            intersect(eq_class, &self.eq_classes[*color as usize]);
-        }
-    }
+            match right_node_id {
+                Some(right_node_id) => {
+                    // found a right extention, update the next node's id
+                    node_id = Some(right_node_id);
+                    kmer_offset = Some(0);
 
-    /// Pseudoalign the `read_seq` to the graph. Returns a tuple of the
-    /// eqivalence class, the number of bases aligned on success,
-    /// and the number of mismatched bases, or None is no alignment could be found.
-    pub fn map_read_with_mismatch(&self, read_seq: &DnaString, allowed_mismatches: usize) -> Option<(Vec<u32>, usize, usize,usize)> {
-        let mut nodes = Vec::new();
+                    //adjust for kmer_position
+                    kmer_pos -= kmer_length - 1;
+                    read_coverage -= kmer_length - 1;
+                }
+                None => {
+                    // can't extend node in dbg extract read using mphf
+                    // TODO: might have to check some cases
+                    if kmer_pos > last_kmer_pos {
+                        // can't search in mphf if no full kmer can be made
+                        break;
+                    }
 
-        match self.map_read_to_nodes_with_mismatch(read_seq, &mut nodes, allowed_mismatches) {
-            Some((read_coverage, mismatches,read_length)) => {
-                let mut eq_class = Vec::new();
-                self.nodes_to_eq_class(&mut nodes, &mut eq_class);
-                Some((eq_class, read_coverage, mismatches,read_length))
+                    // get the match through mphf
+                    match find_kmer_match(&mut kmer_pos) {
+                        None => break,
+                        Some((nid, offset)) => {
+                            node_id = Some(nid);
+                            kmer_offset = Some(offset);
+                        }
+                    };
+                }
             }
-            None => None,
+        } // end-loop
+    } //end-if
+
+    if nodes.len() == 0 {
+        if read_coverage != 0 {
+            panic!(
+                "Different read coverage {:?} than num of eqclasses {:?}",
+                nodes.len(),
+                read_coverage
+            );
         }
-    }
-
-    /// Pseudoalign the `read_seq` to the graph with # mismatches = 2. Returns a tuple of the
-    /// eqivalence class and the number of bases aligned on success, and number of mismatches
-    /// or None is no alignment could be found.
-    pub fn map_read(&self, read_seq: &DnaString, mismatch_size: usize) -> Option<(Vec<u32>, usize, usize, usize)> {
-      match self.map_read_with_mismatch(read_seq, mismatch_size) {
-        Some((eq_class, read_coverage, mismatches, read_length)) => Some((eq_class, read_coverage,mismatches, read_length)),
-        None => None,
-      }
+        None
+    } else {
+        Some((read_coverage, mismatch_count, read_length))
     }
 }
 
+/// Convert a list of nodes of `graph` contacted by a read into an equivalence class.
+/// Supply node list in `nodes`. Equivalence class will be written to `eq_class`.
+pub fn graph_nodes_to_eq_class<G: ReadGraph + ?Sized>(graph: &G, nodes: &mut Vec<usize>, eq_class: &mut Vec<u32>) {
+    eq_class.clear();
 
-impl<K: Kmer + Sync + Send> IndexLike for Pseudoaligner<K> {
-    fn map_read(&self, read_seq: &DnaString, mismatch_size: usize) -> Option<(Vec<u32>, usize, usize, usize)> {
-        // delegate to existing method
-        Pseudoaligner::map_read(self, read_seq, mismatch_size)
-    }
-    fn tx_names(&self) -> &Vec<String> {
-        &self.tx_names
-    }
-    fn tx_gene_mapping(&self) -> &HashMap<String, String> {
-        &self.tx_gene_mapping
+    if nodes.len() == 0 {
+        return;
     }
-    fn gene_length_mapping(&self) -> &HashMap<String, usize> {
-        &self.gene_length_mapping
+
+    // Sort nodes to get the shorter equivalence class first.
+    nodes.sort_by_key(|n| graph.node_eq_class(*n).len());
+
+    // Intersect the equivalence classes
+    eq_class.extend(graph.node_eq_class(nodes[0]));
+    for node in nodes.iter().skip(1) {
+        intersect(eq_class, graph.node_eq_class(*node));
     }
 }
 
+/// Pseudoalign the `read_seq` to `graph`. Returns a tuple of the eqivalence class, the
+/// number of bases aligned, the number of mismatched bases and the read length, or None
+/// if no alignment could be found.
+pub fn map_read_to_graph<G: ReadGraph + ?Sized>(
+    graph: &G,
+    read_seq: &DnaString,
+    params: &MapParams,
+) -> Option<(Vec<u32>, usize, usize, usize)> {
+    let mut nodes = Vec::new();
+    let (read_coverage, mismatches, read_length) =
+        map_read_to_graph_nodes(graph, read_seq, &mut nodes, params)?;
+
+    let mut eq_class = Vec::new();
+    graph_nodes_to_eq_class(graph, &mut nodes, &mut eq_class);
+    Some((eq_class, read_coverage, mismatches, read_length))
+}
 
 
 /// Compute the intersection of v1 and v2 inplace on top of v1
@@ -458,23 +553,23 @@ pub fn intersect<T: Eq + Ord>(v1: &mut Vec<T>, v2: &[T]) {
 
 
 // high level function to call match_read and check revcomp, select best match for unique ec if in doubtc
-pub fn match_strands(record: &fastq::Record,  trim: bool,trimsize: usize,mismatchsize: usize, index: &dyn IndexLike  ) -> Option<(Option<(bool,bool,String,Vec<u32>, usize,usize,bool,usize)>,String)> {
+pub fn match_strands(record: &ReadRecord,  trim: bool,trimsize: usize,params: &MapParams, index: &dyn IndexLike  ) -> Option<(Option<(bool,bool,String,Vec<u32>, usize,usize,bool,usize)>,String)> {
 
 
  // make next steps a function so can be called for R1 and R2  in a paired end version
                             let dna_string = str::from_utf8(&record.seq()).unwrap();
                             let seq = DnaString::from_dna_string(dna_string);
-                            let read_data = index.map_read(&seq,mismatchsize);
+                            let read_data = index.map_read(&seq,params);
                             // Some format: Hit to ec, unique ec, read, eq_class, coverage,mistmatches,mistmatches, trimmed
-                            let wrapped_read_data = match_read(read_data,&dna_string.to_owned(),&record.id().to_owned(),trim,trimsize,mismatchsize,seq.len(),index);
+                            let wrapped_read_data = match_read(read_data,&dna_string.to_owned(),&record.id().to_owned(),trim,trimsize,params,seq.len(),index);
 
 
                             let dna_string_revcomp =  bio::alphabets::dna::revcomp(record.seq());
                             let dna_string_revcomp = str::from_utf8(&dna_string_revcomp).unwrap();
                             let seq_revcomp = DnaString::from_dna_string(dna_string_revcomp);
 			//	info!("test slice {:?}",&dna_string_revcomp[1..40]);
-                            let read_data_revcomp = index.map_read(&seq_revcomp,mismatchsize);
-                            let wrapped_read_data_revcomp = match_read(read_data_revcomp,&dna_string_revcomp.to_owned(),&record.id().to_owned(),trim,trimsize,mismatchsize,seq.len(),index);
+                            let read_data_revcomp = index.map_read(&seq_revcomp,params);
+                            let wrapped_read_data_revcomp = match_read(read_data_revcomp,&dna_string_revcomp.to_owned(),&record.id().to_owned(),trim,trimsize,params,seq.len(),index);
 
 			    // return one of the two reads, with origin
    			let return_result = match (&wrapped_read_data, &wrapped_read_data_revcomp) {
@@ -538,15 +633,13 @@ pub fn match_strands(record: &fastq::Record,  trim: bool,trimsize: usize,mismatc
 
 
 // function to match read to result
-pub fn match_read(optional: Option<(Vec<u32>, usize, usize,usize)>, seq: &String,  record_id: &String,  trim: bool, trimsize: usize, mismatchsize: usize,seqlength: usize, index: &dyn IndexLike  ) -> Option<(bool,bool,String,Vec<u32>, usize,usize,bool,usize)> {
+pub fn match_read(optional: Option<(Vec<u32>, usize, usize,usize)>, seq: &String,  record_id: &String,  trim: bool, trimsize: usize, params: &MapParams,seqlength: usize, index: &dyn IndexLike  ) -> Option<(bool,bool,String,Vec<u32>, usize,usize,bool,usize)> {
 
         // Some format: Hit to ec, unique ec, read, eq_class, coverage, mismatches,trimmed 
 
 	match optional {
         	Some((eq_class,coverage,mismatches,readlen)) => {
-                                    //if coverage >= seq.len() && mismatches <= mismatchsize &&  eq_class.len() == 1 {
-                                   //TODO THIS WAS CHANGED FOR WASM TESTING
-                                     if coverage >= READ_COVERAGE_THRESHOLD && eq_class.len() == 1 {
+                                     if params.is_covered(coverage, readlen) && eq_class.len() == 1 {
                                         debug!("{:?}",&seq);
                                       if trim { // trim check
 
@@ -556,7 +649,7 @@ pub fn match_read(optional: Option<(Vec<u32>, usize, usize,usize)>, seq: &String
                                         //println!("{:?}",seq);
                                         debug!("{:?}",&seq[trimsize..trim_end]);
                                         let trim_seq = DnaString::from_dna_string(&seq[trimsize..trim_end]);
-                                        let trim_read_data = index.map_read(&trim_seq,mismatchsize);
+                                        let trim_read_data = index.map_read(&trim_seq,params);
 					//debug!("{} length {} trimlength {}",record_id,seqlength, trim_seq.len()); 
 
                                         let wrapped_trim_read_data = match trim_read_data {
@@ -578,9 +671,9 @@ pub fn match_read(optional: Option<(Vec<u32>, usize, usize,usize)>, seq: &String
                                         }
                                         // trim these also? to be consistent, yes, indicates different
                                         None =>  {
-					debug!("orig {:?}",index.map_read(&DnaString::from_dna_string(&seq),mismatchsize));
-					debug!(" trimsize... {:?}",index.map_read(&DnaString::from_dna_string(&seq[trimsize..]),mismatchsize));
-					debug!(" ...trim_end {:?}",index.map_read(&DnaString::from_dna_string(&seq[..trim_end]),mismatchsize));
+					debug!("orig {:?}",index.map_read(&DnaString::from_dna_string(&seq),params));
+					debug!(" trimsize... {:?}",index.map_read(&DnaString::from_dna_string(&seq[trimsize..]),params));
+					debug!(" ...trim_end {:?}",index.map_read(&DnaString::from_dna_string(&seq[..trim_end]),params));
 					debug!("coded trim {:?}",trim_read_data);
 					debug!(" {} none trimed {:?}  returns None", record_id.to_owned(),eq_class); 
 					debug!("orig {:?} trimmed {:?}",&DnaString::from_dna_string(&seq),&DnaString::from_dna_string(&seq[trimsize..trim_end])); 
@@ -623,17 +716,210 @@ pub fn match_read(optional: Option<(Vec<u32>, usize, usize,usize)>, seq: &String
 
 
 
-pub fn process_reads<K: Kmer + Sync + Send>(
-    reader: fastq::Reader<File>,
-    reader_pair: Option<fastq::Reader<File>>,
+/// Result of `match_read`: (mapped, unique ec, read id, eq_class, coverage, mismatches, trimmed, read length)
+pub type ReadData = (bool, bool, String, Vec<u32>, usize, usize, bool, usize);
+
+/// Counters collected while mapping, summarised by `summarise_counts`
+#[derive(Default)]
+struct MappingCounts {
+    read_counter: usize,
+    mapped_read_counter: usize,
+    trimmed_read_counter: usize,
+    read_length_total: usize,
+    frequency: HashMap<String, u32>,
+    strandfrequency: HashMap<String, u32>,
+    ec_counts: EcCounts,
+}
+
+impl MappingCounts {
+    fn record(&mut self, read_data: ReadData, strand: String, index: &dyn IndexLike, check_read: bool, params: &MapParams) {
+        let (mapped, unique, _, eq_class, coverage, _, trimmed, readlen) = read_data;
+        *self.strandfrequency.entry(strand).or_insert(0) += 1;
+
+        if mapped {
+            self.mapped_read_counter += 1;
+
+            // read mapped to unique ec
+            if unique {
+                *self.frequency.entry(index.tx_names()[eq_class[0] as usize].clone()).or_insert(0) += 1;
+            } else if trimmed {
+                self.trimmed_read_counter += 1;
+            }
+
+            // same filters as the unique counts, so unique ecs agree with `frequency`
+            if !trimmed && params.is_covered(coverage, readlen) && !eq_class.is_empty() {
+                *self.ec_counts.entry(eq_class).or_insert(0) += 1;
+            }
+        }
+
+        self.read_counter += 1;
+        if check_read {
+            self.read_length_total += readlen
+        };
+    }
+}
+
+/// Log mapping statistics, run the EM over the ec counts and build the counts table
+fn summarise_counts(
+    index: &dyn IndexLike,
+    counts: MappingCounts,
+    trim: bool,
+    read_length_arg: usize,
+    is_paired: bool,
+    bootstrap: Option<BootstrapParams>,
+) -> MappingSummary {
+    let MappingCounts {
+        read_counter,
+        mapped_read_counter,
+        trimmed_read_counter,
+        read_length_total,
+        mut frequency,
+        strandfrequency,
+        ec_counts,
+    } = counts;
+
+    // calculates states
+    let unique_counter: u32 = frequency.values().sum();
+    info!("Processed reads: {}", read_counter);
+    info!("Unique  reads: {}", unique_counter);
+    if trim {
+        info!("Unique rejected reads: {}", trimmed_read_counter)
+    } else {
+        info!("Unique rejected reads: not run")
+    };
+    let shared_read_counter = mapped_read_counter - (unique_counter as usize) - trimmed_read_counter;
+    info!("Shared reads: {}", shared_read_counter);
+    info!("Mapped reads: {}", mapped_read_counter);
+    info!("Unmapped reads: {}", read_counter - mapped_read_counter);
+    // calculates read strand sates
+    for (key, value) in &strandfrequency {
+        info!("Mapped {} reads: {}", key, value);
+    }
+
+    let average_read_length = if read_length_arg == 0 {
+        read_length_total / read_counter.max(1)
+    } else {
+        read_length_arg
+    };
+
+    // relative per base pair value
+    let read_mult = if is_paired { 2 * average_read_length } else { average_read_length };
+
+    let (ecs, ec_totals) = em::ec_count_list(&ec_counts);
+    let eff_lens = em::effective_lengths(index, average_read_length);
+    let em_result = em::expectation_maximisation(&ecs, &ec_totals, &eff_lens);
+    info!(
+        "EM over {} equivalence classes finished after {} rounds",
+        ecs.len(),
+        em_result.rounds
+    );
+
+    // fill missing deletions with 0, and add any transcript the EM assigned reads to
+    for (tx_id, trans) in index.tx_names().iter().enumerate() {
+        if trans.contains("del") || em_result.counts[tx_id] > 0.0 {
+            frequency.entry(trans.clone()).or_insert(0);
+        }
+    }
+    let tx_ids: HashMap<&str, usize> = index
+        .tx_names()
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.as_str(), i))
+        .collect();
+
+    // bootstrap unique and EM counts, resampling all mapped reads so the total is unchanged
+    let bootstrap_counts = bootstrap.map(|params| {
+        info!("Running {} bootstraps with seed {}", params.bootstraps, params.seed);
+        let unmatched = mapped_read_counter as u32 - ec_totals.iter().sum::<u32>();
+        bootstrap::bootstrap_counts(&ecs, &ec_totals, unmatched, &eff_lens, params)
+    });
+
+    let mut transcripts = Vec::new();
+    for key in frequency.keys().sorted() {
+        let value = frequency[key];
+        let gene_id = &index.tx_gene_mapping()[key];
+        let gene_length = index.gene_length_mapping()[gene_id];
+        let prop = value as f32 / mapped_read_counter as f32;
+        let scalefactor = read_mult as f32 / gene_length as f32;
+        let tx_id = tx_ids[key.as_str()];
+
+        let intervals = bootstrap_counts.as_ref().map(|per_tx| {
+            let proportions: Vec<f64> = per_tx.unique[tx_id]
+                .iter()
+                .map(|c| c / mapped_read_counter.max(1) as f64)
+                .collect();
+            BootstrapIntervals {
+                count: bootstrap::summarise(&per_tx.unique[tx_id]),
+                proportion: bootstrap::summarise(&proportions),
+                em_count: bootstrap::summarise(&per_tx.em[tx_id]),
+            }
+        });
+
+        transcripts.push(TranscriptRow {
+            gene: gene_id.clone(),
+            deletion: key.clone(),
+            count: value,
+            total: mapped_read_counter,
+            gene_length,
+            read_length: average_read_length,
+            scale_factor: scalefactor,
+            proportion: prop,
+            scaled_proportion: prop / scalefactor,
+            em_count: em_result.counts[tx_id],
+            tpm: em_result.tpm[tx_id],
+            bootstrap: intervals,
+        });
+    }
+
+    MappingSummary {
+        statistics: MappingStatistics {
+            processed_reads: read_counter,
+            unique_reads: unique_counter,
+            unique_rejected_reads: if trim { Some(trimmed_read_counter) } else { None },
+            shared_reads: shared_read_counter,
+            mapped_reads: mapped_read_counter,
+            unmapped_reads: read_counter - mapped_read_counter,
+            average_read_length,
+            em_rounds: em_result.rounds,
+        },
+        strands: strandfrequency.into_iter().collect(),
+        transcripts,
+    }
+}
+
+/// Pass the reads of a read or pair with its mapping to the read files, if any are written
+fn write_read_files(
+    read_files: &ReadFiles,
+    selected_read: &Option<(Option<ReadData>, String)>,
+    reads: &[&ReadRecord],
+    index: &dyn IndexLike,
+) -> Result<(), Error> {
+    if let Some((Some(read_data), _)) = selected_read {
+        read_files.write(read_data, reads, index)?;
+    }
+    Ok(())
+}
+
+/// Error reading a read or pair, e.g. mismatched mate names or unequal R1 and R2 files
+fn read_error(reads: &str, err: io::Error) -> Error {
+    failure::err_msg(format!("Error in reading {}: {}", reads, err))
+}
+
+/// Map single end reads, or read pairs from split or interleaved files, e.g. from
+/// `utils::read_records` over one or more plain or gzipped FASTQ or FASTA files
+pub fn process_reads(
+    reads: ReadInput,
     index: &dyn IndexLike,
     outfile: Option<String>,
     num_threads: usize,
     trim: bool,
     trimsize: usize,
-    mismatchsize: usize,
+    map_params: &MapParams,
     read_length: Option<usize>,
-    flag_wasm: bool
+    flag_wasm: bool,
+    bootstrap: Option<BootstrapParams>,
+    mut outputs: ReadOutputs,
+    report_options: &ReportOptions,
 ) -> Result<(), Error> {
     info!("Done Reading index");
     info!("Starting Multi-threaded Mapping");
@@ -654,13 +940,13 @@ pub fn process_reads<K: Kmer + Sync + Send>(
     };
 
     let (tx, rx) = mpsc::sync_channel(num_threads);
+    let read_files = outputs.read_files();
 
     // if paired end, need a differnet atomix_reader
-    let is_paired =  if let Some(_) = &reader_pair {
-		    true 
-	} else {
-  		false 
- 	};
+    let is_paired = match &reads {
+        ReadInput::Paired(_) => true,
+        ReadInput::Single(_) => false,
+    };
 
 
    let read_length_arg  = match read_length {
@@ -681,28 +967,13 @@ pub fn process_reads<K: Kmer + Sync + Send>(
         // mapping & aggregation logic as the threaded receiver would do.
         info!("WASM flag set: running single-threaded mapping");
 
-        let mut read_counter: usize = 0;
-        let mut mapped_read_counter: usize = 0;
-        let mut trimmed_read_counter: usize = 0;
-        let mut avg_read_length = read_length_arg;
-        //to fix threads issue on WASM
-        //let mut frequency: HashMap<&str, u32> = HashMap::new();
-        let mut frequency: HashMap<String, u32> = HashMap::new();
-
-        let mut strandfrequency: HashMap<String, u32> = HashMap::new();
-
-        if is_paired {
-            // consume the paired reader
-            let reader_r2 = match reader_pair {
-                Some(r2) => r2,
-                None => panic!("paired reader missing"),
-            };
-            let mut iter1 = reader.records();
-            let mut iter2 = reader_r2.records();
+        let mut counts = MappingCounts::default();
 
-            loop {
-                match (iter1.next(), iter2.next()) {
-                    (Some(Ok(r1)), Some(Ok(r2))) => {
+        match reads {
+          ReadInput::Paired(pairs) => {
+            for result_pair in pairs {
+                match result_pair {
+                    (Ok(r1), Ok(r2)) => {
                         if trim && trimsize > &r1.seq().len() / 2 {
                             error!(
                                 "Trimsize {:?} too long, entire sequence trimmed for read {:?}",
@@ -712,9 +983,9 @@ pub fn process_reads<K: Kmer + Sync + Send>(
                             break;
                         }
 
-                        let compared_read_data = match_strands(&r1, trim, trimsize, mismatchsize, index);
+                        let compared_read_data = match_strands(&r1, trim, trimsize, map_params, index);
                         let compared_read_data_r2 =
-                            match_strands(&r2, trim, trimsize, mismatchsize, index);
+                            match_strands(&r2, trim, trimsize, map_params, index);
 
 
                         let selected_read = match (compared_read_data, compared_read_data_r2) {
@@ -765,35 +1036,22 @@ pub fn process_reads<K: Kmer + Sync + Send>(
                         // std::process::exit(0);
 
 
+                        write_read_files(&read_files, &selected_read, &[&r1, &r2], index)?;
                         if let Some((Some(read_data), strand)) = selected_read {
-                            *strandfrequency.entry(strand.to_string()).or_insert(0) += 1;
-                            if read_data.0 {
-                                mapped_read_counter += 1;
-                                if read_data.1 {
-                                 //   *frequency.entry(&index.tx_names()[read_data.3[0] as usize]).or_insert(0) += 1;
-                                    *frequency
-   .entry(index.tx_names()[read_data.3[0] as usize].clone())
-   .or_insert(0)
-   += 1;
-
-                                } else if read_data.6 {
-                                    trimmed_read_counter += 1;
-                                }
-                            }
-                            read_counter += 1;
-                            if check_read {
-                                avg_read_length += read_data.7
-                            }
+                            outputs.record(&read_data, &strand, index)?;
+                            counts.record(read_data, strand, index, check_read, map_params);
                         }
                     }
-                    _ => break,
+                    (Err(err), _) => return Err(read_error("R1", err)),
+                    (_, Err(err)) => return Err(read_error("R2", err)),
                 }
             }
-        } else {
-            for result in reader.records() {
+          }
+          ReadInput::Single(records) => {
+            for result in records {
                 let record = match result {
                     Ok(r) => r,
-                    Err(err) => panic!("Error {:?} in reading fastq", err),
+                    Err(err) => return Err(read_error("reads", err)),
                 };
                 if trim && trimsize > &record.seq().len() / 2 {
                     error!(
@@ -803,92 +1061,37 @@ pub fn process_reads<K: Kmer + Sync + Send>(
                     );
                     break;
                 }
-                let compared_read_data = match_strands(&record, trim, trimsize, mismatchsize, index);
+                let compared_read_data = match_strands(&record, trim, trimsize, map_params, index);
+                write_read_files(&read_files, &compared_read_data, &[&record], index)?;
                 if let Some((Some(read_data), strand)) = compared_read_data {
-                    *strandfrequency.entry(strand.to_string()).or_insert(0) += 1;
-                    if read_data.0 {
-                        mapped_read_counter += 1;
-                        if read_data.1 {
-                            *frequency.entry(index.tx_names()[read_data.3[0] as usize].clone()).or_insert(0) += 1;
-                        } else if read_data.6 {
-                            trimmed_read_counter += 1;
-                        }
-                    }
-                    read_counter += 1;
-                    if check_read {
-                        avg_read_length += read_data.7
-                    }
+                    outputs.record(&read_data, &strand, index)?;
+                    counts.record(read_data, strand, index, check_read, map_params);
                 }
             }
+          }
         }
 
                eprintln!("MADE IT OUT OF LOOP");
 
   
 
-        // finalize same as threaded receiver
-        let mut unique_counter: u32 = 0;
-        for (_key, value) in &frequency {
-            unique_counter = unique_counter + value
-        }
-        info!("Processed reads: {}", read_counter);
-        info!("Unique  reads: {}", unique_counter);
-        if trim {
-            info!("Unique rejected reads: {}", trimmed_read_counter)
-        } else {
-            info!("Unique rejected reads: not run")
-        };
-        info!(
-            "Shared reads: {}",
-            mapped_read_counter - (unique_counter as usize) - trimmed_read_counter
-        );
-        info!("Mapped reads: {}", mapped_read_counter);
-        info!("Unmapped reads: {}", read_counter - mapped_read_counter);
-        for (key, value) in &strandfrequency {
-            info!("Mapped {} reads: {}", key, value);
-        }
-
-        let average_read_length = if check_read {
-            avg_read_length / read_counter
-        } else {
-            read_length_arg
-        };
-        let read_mult = if is_paired { 2 * average_read_length } else { average_read_length };
-
-        for trans in index.tx_names() {
-            if trans.contains("del") {
-                if !frequency.contains_key(&trans as &str) {
-                    frequency.insert((&trans as &str).to_string(), 0);
-                }
-            }
-        }
-
-        writeln!(output_file, "Gene,Deletion,Count,Total,GeneLength,ReadLength,ScaleFactor,Proportion,ScaledProportion");
-        for key in &frequency.keys().sorted() {
-            let value = &frequency[&key as &str];
-            let gene_id = &index.tx_gene_mapping().get(&key.to_string()).unwrap();
-            let gene_length = &index.gene_length_mapping().get(&gene_id.to_string()).unwrap();
-            let prop = *value as f32 / mapped_read_counter as f32;
-            let scalefactor = (read_mult as f32 / (**gene_length as f32));
-            let scaleprop = prop / scalefactor;
-            writeln!(output_file, "{},{},{},{},{},{},{},{},{}", gene_id, key, value, mapped_read_counter, gene_length, average_read_length, scalefactor, prop, scaleprop);
-        }
+        let summary = summarise_counts(index, counts, trim, read_length_arg, is_paired, bootstrap);
+        report::write_report(&summary, report_options, &mut output_file)?;
+        outputs.finish()?;
     } else {
 
 
 
     scope(|scope| {
+        // shared by the mapping threads, which have the reads
+        let read_files = &read_files;
+        // set by the first thread to fail reading, so the others stop
+        let read_failed = &AtomicBool::new(false);
 
-		if is_paired {
+		match reads {
+		ReadInput::Paired(pairs) => {
 
-			let readerR2 = match reader_pair {
-                                Some(readerR2) => readerR2,
-                                None => panic!("Error {:?} in reading fastq R2", reader_pair),
-                            };
-
-
-
-       let atomic_reader_pair =  Arc::new(Mutex::new(reader.records().zip(readerR2.records()).into_iter()));
+       let atomic_reader_pair =  Arc::new(Mutex::new(pairs));
 //let atomic_reader_pair =         Arc::new(Mutex::new(reader.records()));
 info!("Spawning {} threads for Mapping.\n", num_threads);
         for _ in 0..num_threads {
@@ -897,13 +1100,25 @@ info!("Spawning {} threads for Mapping.\n", num_threads);
 
             scope.spawn(move |_| {
                 loop {
+                    if read_failed.load(Ordering::Relaxed) {
+                        tx.send(Ok(None)).expect("Could not send data!");
+                        break;
+                    }
                     // If work is available, do that work.
                     match utils::get_next_record_pair(&reader) {
                         Some(result_record) => {
 				let (record,recordR2) = match result_record {
                                 (Ok(record),Ok(recordR2)) => (record,recordR2),
-                                (Err(err),_) => panic!("Error {:?} in reading fastq R1", err),
-                                (_,Err(err)) => panic!("Error {:?} in reading fastq R2", err),
+                                (Err(err),_) => {
+                                    read_failed.store(true, Ordering::Relaxed);
+                                    tx.send(Err(read_error("R1", err))).expect("Could not send data!");
+                                    break;
+                                }
+                                (_,Err(err)) => {
+                                    read_failed.store(true, Ordering::Relaxed);
+                                    tx.send(Err(read_error("R2", err))).expect("Could not send data!");
+                                    break;
+                                }
                             };
 	
 
@@ -911,13 +1126,13 @@ info!("Spawning {} threads for Mapping.\n", num_threads);
 
                                                 //panic!("trim too long");std::process::exit(1)
                                                 error!{"Trimsize {:?} too long, entire sequence trimmed for read {:?}",trimsize, &record.id()};
-                                        	tx.send(None).expect("Could not send data!");
+                                        	tx.send(Ok(None)).expect("Could not send data!");
 						break;
 					}
 
 
-                            let compared_read_data = match_strands(&record,trim,trimsize,mismatchsize,index);
-                            let compared_read_data_R2 = match_strands(&recordR2,trim,trimsize,mismatchsize,index);
+                            let compared_read_data = match_strands(&record,trim,trimsize,map_params,index);
+                            let compared_read_data_R2 = match_strands(&recordR2,trim,trimsize,map_params,index);
 			
 				// compare EC class result from each read pair, return the best one for unique EC
 				//Option<(Option<(bool,bool,String,Vec<u32>, usize,usize,bool)>,String)>
@@ -998,11 +1213,16 @@ info!("Spawning {} threads for Mapping.\n", num_threads);
 
 				};
 
-                            tx.send(selected_read).expect("Could not send data!");
+                            if let Err(err) = write_read_files(read_files, &selected_read, &[&record, &recordR2], index) {
+                                read_failed.store(true, Ordering::Relaxed);
+                                tx.send(Err(err)).expect("Could not send data!");
+                                break;
+                            }
+                            tx.send(Ok(selected_read)).expect("Could not send data!");
                         }
                         None => {
                             // send None to tell receiver that the queue ended
-                            tx.send(None).expect("Could not send data!");
+                            tx.send(Ok(None)).expect("Could not send data!");
                             break;
                         }
                     }; //end-match
@@ -1012,8 +1232,9 @@ info!("Spawning {} threads for Mapping.\n", num_threads);
 
 
 
-	} else {
-let atomic_reader =         Arc::new(Mutex::new(reader.records()));
+	}
+	ReadInput::Single(records) => {
+let atomic_reader =         Arc::new(Mutex::new(records));
 info!("Spawning {} threads for Mapping.\n", num_threads);
         for _ in 0..num_threads {
             let tx = tx.clone();
@@ -1021,19 +1242,27 @@ info!("Spawning {} threads for Mapping.\n", num_threads);
 
             scope.spawn(move |_| {
                 loop {
+                    if read_failed.load(Ordering::Relaxed) {
+                        tx.send(Ok(None)).expect("Could not send data!");
+                        break;
+                    }
                     // If work is available, do that work.
                     match utils::get_next_record(&reader) {
                         Some(result_record) => {
                             let record = match result_record {
                                 Ok(record) => record,
-                                Err(err) => panic!("Error {:?} in reading fastq", err),
+                                Err(err) => {
+                                    read_failed.store(true, Ordering::Relaxed);
+                                    tx.send(Err(read_error("reads", err))).expect("Could not send data!");
+                                    break;
+                                }
                             };
 
 				   if trim && trimsize > &record.seq().len()/2 {
 
                                                 //panic!("trim too long");std::process::exit(1)
                                                 error!{"Trimsize {:?} too long, entire sequence trimmed for read {:?}",trimsize, &record.id()};
-                                                tx.send(None).expect("Could not send data!");
+                                                tx.send(Ok(None)).expect("Could not send data!");
                                                 break;
                                         }
 
@@ -1042,43 +1271,51 @@ info!("Spawning {} threads for Mapping.\n", num_threads);
                                 //match_strands
 
 
-                            let compared_read_data = match_strands(&record,trim,trimsize,mismatchsize,index);
+                            let compared_read_data = match_strands(&record,trim,trimsize,map_params,index);
 
-                            tx.send(compared_read_data).expect("Could not send data!");
+                            if let Err(err) = write_read_files(read_files, &compared_read_data, &[&record], index) {
+                                read_failed.store(true, Ordering::Relaxed);
+                                tx.send(Err(err)).expect("Could not send data!");
+                                break;
+                            }
+                            tx.send(Ok(compared_read_data)).expect("Could not send data!");
                         }
                         None => {
                             // send None to tell receiver that the queue ended
-                            tx.send(None).expect("Could not send data!");
+                            tx.send(Ok(None)).expect("Could not send data!");
                             break;
                         }
                     }; //end-match
                 } // end loop
             }); //end-scope
         } // end-for
-
+	}
 
 };
 
 
 
 
-        let mut read_counter: usize = 0;
-        let mut mapped_read_counter: usize = 0;
-        let mut trimmed_read_counter: usize = 0;
-       
         let mut dead_thread_count = 0;
 
-
-	let mut avg_read_length = read_length_arg;
-
-         // collect frequency of reads that match a unique ec
-        let mut frequency: HashMap<&str, u32> = HashMap::new();
-        let mut strandfrequency: HashMap<String, u32> = HashMap::new();
+         // collect frequency of reads that match a unique ec, and reads per ec for the EM
+        let mut counts = MappingCounts::default();
+        // the first error from a mapping thread, returned once all threads have stopped
+        let mut thread_error = None;
 
    // info!("TX MAP");
 
         for eq_class in rx.iter() {
 
+            // a thread that fails also stops
+            let eq_class = match eq_class {
+                Ok(eq_class) => eq_class,
+                Err(err) => {
+                    thread_error.get_or_insert(err);
+                    None
+                }
+            };
+
             match eq_class {
                 None => {
                     dead_thread_count += 1;
@@ -1098,45 +1335,21 @@ info!("Spawning {} threads for Mapping.\n", num_threads);
                 Some((None,_)) => { 
 
                 },
+                // once an output has failed, drain the remaining reads so the threads can stop
+                Some(_) if thread_error.is_some() => (),
                 Some((Some(read_data),strand)) => {
-                    //println!("{:?}", read_data);
-                    //println!("Strand:{:?}", strand);
-                   *strandfrequency.entry(strand.to_string()).or_insert(0) += 1;
-                        
-                  //  info!("Read made it here {:?}", read_data);
-                   
-
-                    if read_data.0 {
-                        mapped_read_counter += 1;
-
-                         // read mapped to unqiue ec
-                         if read_data.1 {
-                                                  //  info!("and here {:?}", read_data.0);
-
-                   *frequency.entry(&index.tx_names()[read_data.3[0] as usize]).or_insert(0) += 1;
-
-
-
-
-                    } else {
-			if read_data.6 { // was trimmed
-                        trimmed_read_counter += 1;
-
-			}
-			}
-                     } 
-
-                    read_counter += 1;
-
-		// read length check
-		if check_read {avg_read_length += read_data.7};
-
+                    if let Err(err) = outputs.record(&read_data, &strand, index) {
+                        read_failed.store(true, Ordering::Relaxed);
+                        thread_error = Some(err);
+                        continue;
+                    }
+                    counts.record(read_data, strand, index, check_read, map_params);
 
-                    if read_counter % 1_000_000 == 0 {
-                        let frac_mapped = mapped_read_counter as f32 * 100.0 / read_counter as f32;
+                    if counts.read_counter % 1_000_000 == 0 {
+                        let frac_mapped = counts.mapped_read_counter as f32 * 100.0 / counts.read_counter as f32;
                         eprint!(
                             "\rDone Mapping {} reads w/ Rate: {}",
-                            read_counter, frac_mapped
+                            counts.read_counter, frac_mapped
                         );
                         io::stderr().flush().expect("Could not flush stdout");
                     }
@@ -1144,66 +1357,20 @@ info!("Spawning {} threads for Mapping.\n", num_threads);
             } // end-match
         } // end-for
 
-
-    // calculates states
-    let mut unique_counter: u32 = 0;
-        for (key, value) in &frequency {
-            unique_counter = unique_counter + value
+        if let Some(err) = thread_error {
+            return Err(err);
         }
-    info!("Processed reads: {}", read_counter);
-    info!("Unique  reads: {}", unique_counter);
-    if trim {info!("Unique rejected reads: {}", trimmed_read_counter)} else { info!("Unique rejected reads: not run") };
-    info!("Shared reads: {}", mapped_read_counter-(unique_counter as usize)-trimmed_read_counter);
-    info!("Mapped reads: {}", mapped_read_counter);
-    info!("Unmapped reads: {}", read_counter-mapped_read_counter);
-    // calculates read strand sates
-        for (key, value) in &strandfrequency {
-    info!("Mapped {} reads: {}", key,value );
-        }
-
-	let average_read_length = if check_read {avg_read_length / read_counter } else {read_length_arg} ;
-
-
-		// relative per base pair value
-		let read_mult = if is_paired { 2*average_read_length} else {average_read_length}; 
-
-
-
-		//manipulate the hash - fill missing entries with 0 from index
-
-			for trans in index.tx_names() {
-			if trans.contains("del"){
-			//if trans.contains("_")// future use a more universal symbol, needs to work with single cases: del2
-
-			if !frequency.contains_key(&trans as &str) {
-			//println!(" {} is missing", trans);
-  			frequency.insert(&trans as &str, 0);
-			}		
-
-		}
 
-}
-     // can add in the tx_gene map entry, and gene length entry here
-    writeln!(output_file,"Gene,Deletion,Count,Total,GeneLength,ReadLength,ScaleFactor,Proportion,ScaledProportion");
-        //for (key, value) in &frequency.keys().sorted() {
-        for key in &frequency.keys().sorted() {
-	let value = &frequency[&key as &str];
-	let gene_id = &index.tx_gene_mapping().get(&key.to_string()).unwrap();
-	let gene_length = &index.gene_length_mapping().get(&gene_id.to_string()).unwrap();
-	 	let prop = *value as f32 / mapped_read_counter as f32;	
-		let scalefactor =  (read_mult as f32 / (**gene_length as f32));
-		let scaleprop = prop / scalefactor;
-
-
-            writeln!(output_file,"{},{},{},{},{},{},{},{},{}",gene_id, key, value,mapped_read_counter , gene_length,average_read_length,scalefactor,prop, scaleprop);
-        }
-
-        //println!("gene map {:?}",&index.tx_gene_mapping);
+        let summary = summarise_counts(index, counts, trim, read_length_arg, is_paired, bootstrap);
+        report::write_report(&summary, report_options, &mut output_file)?;
+        outputs.finish()
     })
-    .unwrap(); //end crossbeam
+    .unwrap()?; //end crossbeam
 
 }
 
+    read_files.finish()?;
+
     eprintln!();
 
     info!("Done Mapping Reads");
@@ -1216,6 +1383,9 @@ info!("Spawning {} threads for Mapping.\n", num_threads);
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::build_index::{build_index, IndexParams};
+    use crate::config::KmerType;
+    use crate::read_output::ReadAssignments;
     use proptest::collection::vec;
     use proptest::prelude::*;
     use proptest::proptest;
@@ -1270,6 +1440,125 @@ mod test {
         }
     }
 
+    #[test]
+    fn map_params_test() {
+        let params = MapParams::default();
+        assert_eq!(params.coverage_threshold(150), READ_COVERAGE_THRESHOLD);
+
+        let params = MapParams { min_coverage_fraction: 0.5, ..MapParams::default() };
+        assert_eq!(params.coverage_threshold(50), READ_COVERAGE_THRESHOLD);
+        assert_eq!(params.coverage_threshold(151), 76);
+        assert!(params.is_covered(76, 151));
+        assert!(!params.is_covered(75, 151));
+
+        assert!(params.validate().is_ok());
+        assert!(MapParams { left_extend_fraction: 1.5, ..params }.validate().is_err());
+
+        // a zero left extend threshold with the first kmer found at the start of the read
+        let tx = "ATGGCGTACCTGAAGCTTGACCGTATCGGATCCAAGTTCGAGCTAGGCATTCGACTGACGTTAGCC";
+        let seqs = vec![DnaString::from_dna_string(tx)];
+        let index = build_index::<KmerType>(&seqs, &vec!["wt".to_string()], &HashMap::new(), &HashMap::new(), 1, &IndexParams::default())
+            .unwrap();
+        let params = MapParams { left_extend_fraction: 0.0, ..MapParams::default() };
+        assert!(params.validate().is_ok());
+        let read = DnaString::from_dna_string(&tx[..50]);
+        assert_eq!(map_read_to_graph(&index, &read, &params), Some((vec![0], 50, 0, 50)));
+    }
+
+    fn read_records(reads: &[(&str, &str)]) -> utils::Records {
+        let records: Vec<io::Result<ReadRecord>> = reads
+            .iter()
+            .map(|(id, seq)| Ok(ReadRecord { id: id.to_string(), seq: seq.as_bytes().to_vec(), qual: None }))
+            .collect();
+        Box::new(records.into_iter())
+    }
+
+    /// Accepts `writes` writes, e.g. an output header, then fails
+    struct FailingWrite {
+        writes: usize,
+    }
+
+    impl Write for FailingWrite {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.writes == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "disk full"));
+            }
+            self.writes -= 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_output_test() {
+        let tx = "ATGGCGTACCTGAAGCTTGACCGTATCGGATCCAAGTTCGAGCTAGGCATTCGACTGACGTTAGCCATGGTACGATCAGGTC";
+        let seqs = vec![DnaString::from_dna_string(tx)];
+        let tx_names = vec!["wt".to_string()];
+        let index = build_index::<KmerType>(&seqs, &tx_names, &HashMap::new(), &HashMap::new(), 1, &IndexParams::default())
+            .unwrap();
+
+        let ids: Vec<String> = (0..100).map(|i| format!("r{}", i)).collect();
+        let reads: Vec<(&str, &str)> = ids.iter().enumerate().map(|(i, id)| (id.as_str(), &tx[i % 30..i % 30 + 50])).collect();
+        let outputs = ReadOutputs {
+            assignments: Some(ReadAssignments::from_writer(Box::new(FailingWrite { writes: 1 })).unwrap()),
+            ..ReadOutputs::default()
+        };
+        // the mapping threads must stop rather than block on a receiver that has returned
+        let result = process_reads(
+            ReadInput::Single(read_records(&reads)),
+            &index,
+            None,
+            4,
+            false,
+            0,
+            &MapParams::default(),
+            None,
+            false,
+            None,
+            outputs,
+            &ReportOptions::default(),
+        );
+        let err = result.expect_err("a failed read assignment write should fail mapping");
+        assert!(err.to_string().contains("disk full"));
+    }
+
+    #[test]
+    fn mismatched_pair_test() {
+        let tx = "ATGGCGTACCTGAAGCTTGACCGTATCGGATCCAAGTTCGAGCTAGGCATTCGACTGACGTTAGCCATGGTACGATCAGGTC";
+        let seqs = vec![DnaString::from_dna_string(tx)];
+        let tx_names = vec!["wt".to_string()];
+        let tx_gene_map: HashMap<String, String> = vec![("wt".to_string(), "G".to_string())].into_iter().collect();
+        let gene_length_map: HashMap<String, usize> = vec![("G".to_string(), tx.len())].into_iter().collect();
+        let index = build_index::<KmerType>(&seqs, &tx_names, &tx_gene_map, &gene_length_map, 1, &IndexParams::default())
+            .unwrap();
+
+        // the threaded path and the single threaded WASM path
+        for &(num_threads, wasm) in &[(2, false), (1, true)] {
+            let r1 = read_records(&[("a/1", &tx[0..50]), ("b/1", &tx[10..60])]);
+            let r2 = read_records(&[("a/2", &tx[20..70]), ("x/2", &tx[30..80])]);
+            let reads = ReadInput::Paired(utils::paired_records(r1, r2, false));
+            let result = process_reads(
+                reads,
+                &index,
+                None,
+                num_threads,
+                true,
+                2,
+                &MapParams::default(),
+                None,
+                wasm,
+                None,
+                ReadOutputs::default(),
+                &ReportOptions::default(),
+            );
+            let err = result.expect_err("mismatched mates should fail mapping");
+            assert!(err.to_string().contains("mismatched read names"));
+        }
+    }
+
     proptest! {
         #![proptest_config(ProptestConfig { cases: 1000, .. ProptestConfig::default()})]
         #[test]
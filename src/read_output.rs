@@ -0,0 +1,666 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Per read outputs of `tinyt map`, so the reads behind a deletion call can be reviewed.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use bio::alphabets::dna;
+use bio::io::fasta;
+use failure::{self, Error};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{info, warn};
+
+use crate::build_index::IndexLike;
+use crate::pseudoaligner::ReadData;
+use crate::utils::{self, ReadRecord};
+
+/// An output file, gzipped if the name ends in `.gz`. Gzipped files must be finished, as
+/// dropping a `GzEncoder` ignores errors writing the gzip trailer.
+enum OutputWriter {
+    Plain(BufWriter<File>),
+    Gz(GzEncoder<BufWriter<File>>),
+    /// any writer, e.g. one that fails
+    #[cfg(test)]
+    Test(Box<dyn Write + Send>),
+}
+
+impl OutputWriter {
+    fn create<P: AsRef<Path>>(path: P) -> Result<OutputWriter, Error> {
+        let file = File::create(path.as_ref())
+            .map_err(|e| failure::err_msg(format!("Could not create {}: {}", path.as_ref().display(), e)))?;
+        if path.as_ref().extension().map_or(false, |ext| ext == "gz") {
+            Ok(OutputWriter::Gz(GzEncoder::new(BufWriter::new(file), Compression::default())))
+        } else {
+            Ok(OutputWriter::Plain(BufWriter::new(file)))
+        }
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        match self {
+            OutputWriter::Plain(mut writer) => writer.flush()?,
+            OutputWriter::Gz(writer) => writer.finish()?.flush()?,
+            #[cfg(test)]
+            OutputWriter::Test(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(writer) => writer.write(buf),
+            OutputWriter::Gz(writer) => writer.write(buf),
+            #[cfg(test)]
+            OutputWriter::Test(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(writer) => writer.flush(),
+            OutputWriter::Gz(writer) => writer.flush(),
+            #[cfg(test)]
+            OutputWriter::Test(writer) => writer.flush(),
+        }
+    }
+}
+
+/// TSV of the mapping of each read, or of the selected mate of each pair
+pub struct ReadAssignments {
+    writer: OutputWriter,
+}
+
+impl ReadAssignments {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<ReadAssignments, Error> {
+        let assignments = ReadAssignments::with_writer(OutputWriter::create(path.as_ref())?)?;
+        info!("Writing read assignments to {}", path.as_ref().display());
+        Ok(assignments)
+    }
+
+    /// Read assignments written to any writer
+    #[cfg(test)]
+    pub(crate) fn from_writer(writer: Box<dyn Write + Send>) -> Result<ReadAssignments, Error> {
+        ReadAssignments::with_writer(OutputWriter::Test(writer))
+    }
+
+    fn with_writer(mut writer: OutputWriter) -> Result<ReadAssignments, Error> {
+        writeln!(
+            writer,
+            "ReadID\tStrand\tMapped\tUnique\tEquivalenceClass\tTranscripts\tCoverage\tMismatches\tTrimmed"
+        )?;
+        Ok(ReadAssignments { writer })
+    }
+
+    /// One line per read, with the strand or mate tag chosen by `match_strands` or pair
+    /// selection, and the equivalence class as transcript ids and names
+    pub fn write(&mut self, read_data: &ReadData, strand: &str, index: &dyn IndexLike) -> Result<(), Error> {
+        let (mapped, unique, id, eq_class, coverage, mismatches, trimmed, _) = read_data;
+        let ec: Vec<String> = eq_class.iter().map(|t| t.to_string()).collect();
+        let names: Vec<&str> = eq_class
+            .iter()
+            .map(|t| index.tx_names()[*t as usize].as_str())
+            .collect();
+        writeln!(
+            self.writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            id,
+            strand,
+            mapped,
+            unique,
+            ec.join(","),
+            names.join(","),
+            coverage,
+            mismatches,
+            trimmed
+        )?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        self.writer.finish()
+    }
+}
+
+/// Write a read as FASTQ, or as FASTA if it has no base qualities
+fn write_read(writer: &mut dyn Write, read: &ReadRecord) -> Result<(), Error> {
+    let seq = String::from_utf8_lossy(read.seq());
+    match read.qual() {
+        Some(qual) => writeln!(writer, "@{}\n{}\n+\n{}", read.id(), seq, String::from_utf8_lossy(qual))?,
+        None => writeln!(writer, ">{}\n{}", read.id(), seq)?,
+    }
+    Ok(())
+}
+
+/// A deletion's read files, one per mate, and the number of reads written
+struct DeletionFiles {
+    mates: Vec<BufWriter<File>>,
+    reads: usize,
+}
+
+/// The reads (both mates of a pair) that map uniquely to each deletion transcript,
+/// written to `<deletion>.fastq` or `<deletion>_R1.fastq` and `<deletion>_R2.fastq`
+pub struct DeletionReads {
+    outdir: PathBuf,
+    max_reads: usize,
+    files: HashMap<String, DeletionFiles>,
+}
+
+impl DeletionReads {
+    /// At most `max_reads` reads or pairs are written per deletion
+    pub fn create<P: AsRef<Path>>(outdir: P, max_reads: usize) -> Result<DeletionReads, Error> {
+        fs::create_dir_all(outdir.as_ref())?;
+        info!(
+            "Writing up to {} reads per deletion to {}",
+            max_reads,
+            outdir.as_ref().display()
+        );
+        Ok(DeletionReads {
+            outdir: outdir.as_ref().to_path_buf(),
+            max_reads,
+            files: HashMap::new(),
+        })
+    }
+
+    fn open(&self, deletion: &str, reads: &[&ReadRecord]) -> Result<DeletionFiles, Error> {
+        let extension = if reads[0].qual().is_some() { "fastq" } else { "fa" };
+        let names: Vec<String> = if reads.len() == 1 {
+            vec![format!("{}.{}", deletion, extension)]
+        } else {
+            (1..=reads.len())
+                .map(|mate| format!("{}_R{}.{}", deletion, mate, extension))
+                .collect()
+        };
+        let mut mates = Vec::new();
+        for name in names {
+            mates.push(BufWriter::new(File::create(self.outdir.join(name))?));
+        }
+        Ok(DeletionFiles { mates, reads: 0 })
+    }
+
+    /// Write a read, or both mates of a pair, if it maps uniquely to a deletion
+    pub fn write(&mut self, read_data: &ReadData, reads: &[&ReadRecord], index: &dyn IndexLike) -> Result<(), Error> {
+        let (mapped, unique, _, eq_class, _, _, _, _) = read_data;
+        if !(*mapped && *unique) {
+            return Ok(());
+        }
+        let tx_name = &index.tx_names()[eq_class[0] as usize];
+        if !tx_name.contains("del") {
+            return Ok(());
+        }
+
+        if !self.files.contains_key(tx_name) {
+            let files = self.open(tx_name, reads)?;
+            self.files.insert(tx_name.clone(), files);
+        }
+        let max_reads = self.max_reads;
+        let files = self.files.get_mut(tx_name).unwrap();
+        if files.reads < max_reads {
+            for (writer, read) in files.mates.iter_mut().zip(reads) {
+                write_read(writer, read)?;
+            }
+        }
+        files.reads += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        for (deletion, mut files) in self.files {
+            if files.reads > self.max_reads {
+                warn!(
+                    "{} of {} reads written for {}",
+                    self.max_reads, files.reads, deletion
+                );
+            }
+            for writer in &mut files.mates {
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A transcript's length and the first position of each of its k-mers
+struct TranscriptKmers {
+    length: usize,
+    kmers: HashMap<Vec<u8>, usize>,
+}
+
+impl TranscriptKmers {
+    fn new(seq: &[u8], k: usize) -> TranscriptKmers {
+        let mut kmers = HashMap::new();
+        for (pos, kmer) in seq.windows(k).enumerate() {
+            kmers.entry(kmer.to_vec()).or_insert(pos);
+        }
+        TranscriptKmers {
+            length: seq.len(),
+            kmers,
+        }
+    }
+}
+
+/// Where a read is placed on a transcript: 0-based position, strand, CIGAR and the
+/// read sequence and qualities on the transcript strand
+#[derive(Debug, PartialEq)]
+struct Placement {
+    pos: usize,
+    reverse: bool,
+    cigar: String,
+    seq: Vec<u8>,
+    qual: Option<Vec<u8>>,
+}
+
+/// Places reads on the index transcripts and formats them as SAM records
+struct TranscriptPlacer {
+    transcripts: Vec<TranscriptKmers>,
+    tx_names: Vec<String>,
+    k: usize,
+}
+
+impl TranscriptPlacer {
+    /// `transcripts` are the name and sequence of each transcript, in index order
+    fn new(transcripts: &[(String, Vec<u8>)], k: usize) -> TranscriptPlacer {
+        TranscriptPlacer {
+            transcripts: transcripts.iter().map(|(_, seq)| TranscriptKmers::new(seq, k)).collect(),
+            tx_names: transcripts.iter().map(|(name, _)| name.clone()).collect(),
+            k,
+        }
+    }
+
+    fn place(&self, tx_id: usize, read: &ReadRecord) -> Option<Placement> {
+        let transcript = &self.transcripts[tx_id];
+        let k = self.k;
+        let forward = read.seq().to_ascii_uppercase();
+
+        for &reverse in &[false, true] {
+            let seq = if reverse { dna::revcomp(&forward) } else { forward.clone() };
+            let hit = seq
+                .windows(k)
+                .enumerate()
+                .find_map(|(read_pos, kmer)| transcript.kmers.get(kmer).map(|tx_pos| (read_pos, *tx_pos)));
+            let (read_pos, tx_pos) = match hit {
+                Some(hit) => hit,
+                None => continue,
+            };
+
+            let left_clip = read_pos.saturating_sub(tx_pos);
+            let pos = tx_pos.saturating_sub(read_pos);
+            let right_clip = (pos + seq.len() - left_clip).saturating_sub(transcript.length);
+            let matched = seq.len() - left_clip - right_clip;
+            let mut cigar = String::new();
+            for (len, op) in &[(left_clip, 'S'), (matched, 'M'), (right_clip, 'S')] {
+                if *len > 0 {
+                    cigar.push_str(&format!("{}{}", len, op));
+                }
+            }
+
+            let qual = read.qual().map(|q| {
+                let mut q = q.to_vec();
+                if reverse {
+                    q.reverse();
+                }
+                q
+            });
+            return Some(Placement {
+                pos,
+                reverse,
+                cigar,
+                seq,
+                qual,
+            });
+        }
+        None
+    }
+
+    /// SAM records of a mapped read, or both mates of a pair, placed on the first transcript
+    /// of its equivalence class. Reads unique to a transcript get MAPQ 60, shared reads 0.
+    fn sam_records(&self, read_data: &ReadData, reads: &[&ReadRecord]) -> Vec<String> {
+        let (mapped, unique, _, eq_class, _, mismatches, _, _) = read_data;
+        if !*mapped || eq_class.is_empty() {
+            return Vec::new();
+        }
+        let tx_id = eq_class[0] as usize;
+        let mapq = if *unique { 60 } else { 0 };
+        let placements: Vec<Option<Placement>> = reads.iter().map(|read| self.place(tx_id, read)).collect();
+        let paired = reads.len() == 2;
+
+        let mut records = Vec::new();
+        for (mate, (read, placement)) in reads.iter().zip(&placements).enumerate() {
+            let mate_placement = if paired { placements[1 - mate].as_ref() } else { None };
+            let mut flag = 0;
+            if paired {
+                flag |= 0x1 | if mate == 0 { 0x40 } else { 0x80 };
+                match mate_placement {
+                    Some(m) if m.reverse => flag |= 0x20,
+                    Some(_) => (),
+                    None => flag |= 0x8,
+                }
+            }
+
+            let (pos, cigar, seq, qual) = match placement {
+                Some(p) => {
+                    if p.reverse {
+                        flag |= 0x10;
+                    }
+                    (p.pos + 1, p.cigar.as_str(), p.seq.clone(), p.qual.clone())
+                }
+                None => {
+                    flag |= 0x4;
+                    // unplaced mates go with their mate
+                    let pos = mate_placement.map_or(0, |m| m.pos + 1);
+                    (pos, "*", read.seq().to_vec(), read.qual().map(|q| q.to_vec()))
+                }
+            };
+            let (rname, rnext, pnext) = match (placement, mate_placement) {
+                (None, None) => ("*", "*", 0),
+                (_, Some(m)) => (self.tx_names[tx_id].as_str(), "=", m.pos + 1),
+                (Some(_), None) if paired => (self.tx_names[tx_id].as_str(), "=", pos),
+                (Some(_), None) => (self.tx_names[tx_id].as_str(), "*", 0),
+            };
+
+            records.push(format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t0\t{}\t{}\tXM:i:{}",
+                utils::mate_name(read.id()),
+                flag,
+                rname,
+                pos,
+                if placement.is_some() { mapq } else { 0 },
+                cigar,
+                rnext,
+                pnext,
+                String::from_utf8_lossy(&seq),
+                qual.map_or("*".to_string(), |q| String::from_utf8_lossy(&q).to_string()),
+                mismatches
+            ));
+        }
+        records
+    }
+}
+
+/// Mapped reads as SAM (or BAM, with the `htslib` feature), with the index transcripts as
+/// reference sequences. The de Bruijn graph has no transcript coordinates: its compacted
+/// nodes are shared between transcripts, and a node offset from the graph walk is not a
+/// transcript position. So a read is placed at its first k-mer found in the transcriptome
+/// FASTA sequence of the transcript it maps to, an approximate position with the ends soft
+/// clipped where they run off the transcript. The read's mismatches during mapping are in
+/// the `XM` tag.
+pub struct PseudoAlignments {
+    path: PathBuf,
+    sam_path: PathBuf,
+    writer: BufWriter<File>,
+    placer: TranscriptPlacer,
+    written: usize,
+}
+
+impl PseudoAlignments {
+    /// `transcripts_fasta` is the transcriptome the index was built from
+    pub fn create<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        transcripts_fasta: Q,
+        index: &dyn IndexLike,
+    ) -> Result<PseudoAlignments, Error> {
+        let is_bam = path.as_ref().extension().map_or(false, |ext| ext == "bam");
+        if is_bam && !cfg!(feature = "htslib") {
+            return Err(failure::err_msg(
+                "BAM output needs tinyt built with --features htslib, use a .sam file name instead",
+            ));
+        }
+        // BAM is converted from SAM once mapping is done
+        let sam_path = if is_bam {
+            PathBuf::from(format!("{}.tmp.sam", path.as_ref().display()))
+        } else {
+            path.as_ref().to_path_buf()
+        };
+
+        let mut sequences: HashMap<String, Vec<u8>> = HashMap::new();
+        let reader = fasta::Reader::from_file(transcripts_fasta.as_ref()).map_err(|e| {
+            failure::err_msg(format!(
+                "Could not open transcripts {}: {}",
+                transcripts_fasta.as_ref().display(),
+                e
+            ))
+        })?;
+        let mut fasta_format = None;
+        for record in reader.records() {
+            let record = record?;
+            if fasta_format.is_none() {
+                fasta_format = Some(utils::detect_fasta_format(&record)?);
+            }
+            let (tx_id, _) = utils::extract_tx_gene_id(&record, fasta_format.as_ref().unwrap());
+            sequences.insert(tx_id, record.seq().to_ascii_uppercase());
+        }
+
+        let mut transcripts = Vec::new();
+        for tx_name in index.tx_names() {
+            let seq = sequences.get(tx_name).ok_or_else(|| {
+                failure::err_msg(format!(
+                    "Transcript {} of the index is not in {}",
+                    tx_name,
+                    transcripts_fasta.as_ref().display()
+                ))
+            })?;
+            transcripts.push((tx_name.clone(), seq.clone()));
+        }
+
+        let mut writer = BufWriter::new(File::create(&sam_path)?);
+        writeln!(writer, "@HD\tVN:1.6\tSO:unsorted")?;
+        for (tx_name, seq) in &transcripts {
+            writeln!(writer, "@SQ\tSN:{}\tLN:{}", tx_name, seq.len())?;
+        }
+        writeln!(writer, "@PG\tID:tinyt\tPN:tinyt\tVN:{}", env!("CARGO_PKG_VERSION"))?;
+        info!("Writing pseudo-alignments to {}", path.as_ref().display());
+
+        Ok(PseudoAlignments {
+            path: path.as_ref().to_path_buf(),
+            sam_path,
+            writer,
+            placer: TranscriptPlacer::new(&transcripts, index.k()),
+            written: 0,
+        })
+    }
+
+    /// Write a mapped read, or both mates of a pair, placed on the first transcript of its
+    /// equivalence class
+    pub fn write(&mut self, read_data: &ReadData, reads: &[&ReadRecord]) -> Result<(), Error> {
+        for record in self.placer.sam_records(read_data, reads) {
+            writeln!(self.writer, "{}", record)?;
+            self.written += 1;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        if self.sam_path != self.path {
+            sam_to_bam(&self.sam_path, &self.path)?;
+            fs::remove_file(&self.sam_path)?;
+        }
+        info!(
+            "{} pseudo-alignments written to {}, sort and index before viewing",
+            self.written,
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(feature = "htslib")]
+fn sam_to_bam(sam: &Path, bam: &Path) -> Result<(), Error> {
+    use rust_htslib::bam::{self, Read};
+
+    let mut reader = bam::Reader::from_path(sam)?;
+    let header = bam::Header::from_template(reader.header());
+    let mut writer = bam::Writer::from_path(bam, &header, bam::Format::Bam)?;
+    for record in reader.records() {
+        writer.write(&record?)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "htslib"))]
+fn sam_to_bam(_sam: &Path, _bam: &Path) -> Result<(), Error> {
+    Err(failure::err_msg("BAM output needs tinyt built with --features htslib"))
+}
+
+/// Reads with the mapping result of each read or pair, written from the mapping threads
+#[derive(Default)]
+pub struct ReadFiles {
+    pub deletion_reads: Option<Mutex<DeletionReads>>,
+    pub alignments: Option<Mutex<PseudoAlignments>>,
+}
+
+impl ReadFiles {
+    pub fn write(&self, read_data: &ReadData, reads: &[&ReadRecord], index: &dyn IndexLike) -> Result<(), Error> {
+        if let Some(deletion_reads) = &self.deletion_reads {
+            deletion_reads.lock().unwrap().write(read_data, reads, index)?;
+        }
+        if let Some(alignments) = &self.alignments {
+            alignments.lock().unwrap().write(read_data, reads)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        if let Some(deletion_reads) = self.deletion_reads {
+            deletion_reads.into_inner().unwrap().finish()?;
+        }
+        if let Some(alignments) = self.alignments {
+            alignments.into_inner().unwrap().finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Optional per read outputs written while mapping
+#[derive(Default)]
+pub struct ReadOutputs {
+    pub assignments: Option<ReadAssignments>,
+    pub deletion_reads: Option<DeletionReads>,
+    pub alignments: Option<PseudoAlignments>,
+}
+
+impl ReadOutputs {
+    pub fn record(&mut self, read_data: &ReadData, strand: &str, index: &dyn IndexLike) -> Result<(), Error> {
+        if let Some(assignments) = &mut self.assignments {
+            assignments.write(read_data, strand, index)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        if let Some(assignments) = self.assignments {
+            assignments.finish()?;
+        }
+        Ok(())
+    }
+
+    /// The outputs that need the reads, for the mapping threads
+    pub fn read_files(&mut self) -> ReadFiles {
+        ReadFiles {
+            deletion_reads: self.deletion_reads.take().map(Mutex::new),
+            alignments: self.alignments.take().map(Mutex::new),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TX: &[u8] = b"ATGGCGTACCTGAAGCTTGACCGTATCGGATCCAAGTTCGAGCTAGGCATTCGACTGACGTTAGCCATGGTACGATCAGGTC";
+
+    fn placer() -> TranscriptPlacer {
+        TranscriptPlacer::new(&[("tx1".to_string(), TX.to_vec())], 20)
+    }
+
+    fn read(id: &str, seq: &[u8]) -> ReadRecord {
+        ReadRecord {
+            id: id.to_string(),
+            seq: seq.to_vec(),
+            qual: None,
+        }
+    }
+
+    #[test]
+    fn place_forward_test() {
+        let placement = placer().place(0, &read("r", &TX[10..50])).unwrap();
+        assert_eq!(
+            placement,
+            Placement {
+                pos: 10,
+                reverse: false,
+                cigar: "40M".to_string(),
+                seq: TX[10..50].to_vec(),
+                qual: None,
+            }
+        );
+    }
+
+    #[test]
+    fn place_reverse_test() {
+        let mut record = read("r", &dna::revcomp(&TX[10..50]));
+        record.qual = Some((b'A'..b'A' + 40).collect());
+        let placement = placer().place(0, &record).unwrap();
+        assert!(placement.reverse);
+        assert_eq!(placement.pos, 10);
+        assert_eq!(placement.cigar, "40M");
+        assert_eq!(placement.seq, TX[10..50].to_vec());
+        assert_eq!(placement.qual, Some((b'A'..b'A' + 40).rev().collect()));
+    }
+
+    #[test]
+    fn place_soft_clip_test() {
+        let seq = [b"GGGGG".as_ref(), TX, b"TTTTT".as_ref()].concat();
+        let placement = placer().place(0, &read("r", &seq)).unwrap();
+        assert!(!placement.reverse);
+        assert_eq!(placement.pos, 0);
+        assert_eq!(placement.cigar, "5S82M5S");
+        assert_eq!(placement.seq, seq);
+
+        assert!(placer().place(0, &read("r", &[b'A'; 40])).is_none());
+    }
+
+    #[test]
+    fn unplaced_mate_test() {
+        let junk = b"ACACACACACACACACACACACACACACACACACACACAC";
+        let read_data: ReadData = (true, true, "r".to_string(), vec![0], 40, 0, false, 40);
+        let mate1 = read("r/1", &TX[10..50]);
+        let mate2 = read("r/2", junk);
+        let records = placer().sam_records(&read_data, &[&mate1, &mate2]);
+        assert_eq!(
+            records,
+            vec![
+                format!(
+                    "r\t73\ttx1\t11\t60\t40M\t=\t11\t0\t{}\t*\tXM:i:0",
+                    String::from_utf8_lossy(&TX[10..50])
+                ),
+                format!(
+                    "r\t133\ttx1\t11\t0\t*\t=\t11\t0\t{}\t*\tXM:i:0",
+                    String::from_utf8_lossy(junk)
+                ),
+            ]
+        );
+
+        let unmapped: ReadData = (false, false, "r".to_string(), vec![], 0, 0, false, 40);
+        assert!(placer().sam_records(&unmapped, &[&mate1, &mate2]).is_empty());
+    }
+
+    #[test]
+    fn gzipped_assignments_test() -> Result<(), Error> {
+        use flate2::read::MultiGzDecoder;
+        use std::io::Read;
+
+        let path = std::env::temp_dir().join(format!("tinyt_assignments_test_{}.tsv.gz", std::process::id()));
+        ReadAssignments::create(&path)?.finish()?;
+        let mut tsv = String::new();
+        let decoded = File::open(&path).and_then(|file| MultiGzDecoder::new(file).read_to_string(&mut tsv));
+        fs::remove_file(&path)?;
+        decoded?;
+        assert!(tsv.starts_with("ReadID\tStrand\tMapped"));
+        Ok(())
+    }
+}
@@ -0,0 +1,384 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Generate a Toblerone transcriptome: the canonical transcript of each gene
+//! plus every contiguous internal exon deletion, spliced from an indexed genome FASTA.
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use bio::alphabets::dna;
+use bio::io::fasta;
+use debruijn::dna_string::DnaString;
+use failure::{self, Error};
+use log::{info, warn};
+
+/// Placeholder fields appended to each header so that `utils::read_transcripts`
+/// detects the Gencode style `tx|gene|...` format (9 tokens).
+const HEADER_PADDING: &str = "3|4|5|6|7|8|9";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strand {
+    Forward,
+    Reverse,
+}
+
+impl Strand {
+    pub fn from_symbol(s: &str) -> Result<Strand, Error> {
+        match s {
+            "+" => Ok(Strand::Forward),
+            "-" => Ok(Strand::Reverse),
+            _ => Err(failure::err_msg(format!("Unknown strand '{}'", s))),
+        }
+    }
+}
+
+/// A canonical transcript definition. `exons` are 0-based, half-open genomic
+/// intervals in ascending genomic order, regardless of strand.
+#[derive(Clone, Debug)]
+pub struct CanonicalTranscript {
+    pub tx_id: String,
+    pub gene: String,
+    pub chrom: String,
+    pub strand: Strand,
+    pub exons: Vec<(u64, u64)>,
+}
+
+impl CanonicalTranscript {
+    /// Exons in transcript orientation, so exon 1 is the 5' exon on either strand.
+    pub fn ordered_exons(&self) -> Vec<(u64, u64)> {
+        let mut exons = self.exons.clone();
+        if self.strand == Strand::Reverse {
+            exons.reverse();
+        }
+        exons
+    }
+}
+
+/// A transcript of the Toblerone transcriptome, ready to be written or indexed.
+#[derive(Clone, Debug)]
+pub struct ReferenceTranscript {
+    pub name: String,
+    pub gene: String,
+    pub seq: Vec<u8>,
+}
+
+impl ReferenceTranscript {
+    /// FASTA identifier in the format expected by `utils::read_transcripts`
+    pub fn fasta_id(&self) -> String {
+        format!("{}|{}|{}", self.name, self.gene, HEADER_PADDING)
+    }
+}
+
+/// Read canonical transcripts from a BED12 file. If `gene` is given it is used as the
+/// gene name for every transcript, otherwise the BED name column is used.
+pub fn read_bed12<P: AsRef<Path>>(path: P, gene: Option<&str>) -> Result<Vec<CanonicalTranscript>, Error> {
+    parse_bed12(BufReader::new(File::open(path.as_ref())?), gene)
+}
+
+/// Parse BED12 records, as for `read_bed12`
+pub fn parse_bed12<R: BufRead>(reader: R, gene: Option<&str>) -> Result<Vec<CanonicalTranscript>, Error> {
+    let mut transcripts = Vec::new();
+
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.trim_end().split('\t').collect();
+        if fields.len() < 12 {
+            return Err(failure::err_msg(format!(
+                "BED12 line {} has {} columns, expected 12",
+                line_no + 1,
+                fields.len()
+            )));
+        }
+
+        let parse_list = |s: &str| -> Result<Vec<u64>, Error> {
+            s.split(',')
+                .filter(|x| !x.is_empty())
+                .map(|x| x.parse::<u64>().map_err(Error::from))
+                .collect()
+        };
+
+        let chrom_start: u64 = fields[1].parse()?;
+        let block_count: usize = fields[9].parse()?;
+        let block_sizes = parse_list(fields[10])?;
+        let block_starts = parse_list(fields[11])?;
+
+        if block_sizes.len() < block_count || block_starts.len() < block_count {
+            return Err(failure::err_msg(format!(
+                "BED12 line {} lists fewer blocks than blockCount {}",
+                line_no + 1,
+                block_count
+            )));
+        }
+
+        let exons = (0..block_count)
+            .map(|i| {
+                let start = chrom_start + block_starts[i];
+                (start, start + block_sizes[i])
+            })
+            .collect();
+
+        transcripts.push(CanonicalTranscript {
+            tx_id: fields[3].to_string(),
+            gene: gene.unwrap_or(fields[3]).to_string(),
+            chrom: fields[0].to_string(),
+            strand: Strand::from_symbol(fields[5])?,
+            exons,
+        });
+    }
+
+    info!("Read {} canonical transcripts from BED12", transcripts.len());
+    Ok(transcripts)
+}
+
+/// Contiguous runs of internal exons, as 1-based inclusive (first, last) exon numbers,
+/// that can be deleted from a transcript with `exon_count` exons. The first and last
+/// exons are never deleted, giving (N-1 choose 2) deletions.
+pub fn internal_deletions(exon_count: usize) -> Vec<(usize, usize)> {
+    let mut deletions = Vec::new();
+    for last in 2..exon_count {
+        for first in 2..=last {
+            deletions.push((first, last));
+        }
+    }
+    deletions
+}
+
+/// Name of a deletion transcript, e.g. `ENST1_del2_3` for exons 2 and 3 removed from `ENST1`
+pub fn deletion_name(tx_name: &str, first: usize, last: usize) -> String {
+    let exons: Vec<String> = (first..=last).map(|e| e.to_string()).collect();
+    format!("{}_del{}", tx_name, exons.join("_"))
+}
+
+/// Fetch an exon from the genome in transcript orientation
+fn fetch_exon<R: io::Read + io::Seek>(
+    genome: &mut fasta::IndexedReader<R>,
+    chrom: &str,
+    exon: (u64, u64),
+    strand: Strand,
+) -> Result<Vec<u8>, Error> {
+    let mut seq = Vec::new();
+    genome
+        .fetch(chrom, exon.0, exon.1)
+        .map_err(|e| failure::err_msg(format!("Could not fetch {}:{}-{}: {}", chrom, exon.0, exon.1, e)))?;
+    genome
+        .read(&mut seq)
+        .map_err(|e| failure::err_msg(format!("Could not read {}:{}-{}: {}", chrom, exon.0, exon.1, e)))?;
+
+    if seq.len() as u64 != exon.1 - exon.0 {
+        return Err(failure::err_msg(format!(
+            "Exon {}:{}-{} extends past the end of the genome sequence",
+            chrom, exon.0, exon.1
+        )));
+    }
+
+    seq.make_ascii_uppercase();
+    if strand == Strand::Reverse {
+        seq = dna::revcomp(&seq);
+    }
+    Ok(seq)
+}
+
+/// Splice the canonical transcript and all of its internal deletion transcripts.
+pub fn deletion_transcripts<R: io::Read + io::Seek>(
+    tx: &CanonicalTranscript,
+    genome: &mut fasta::IndexedReader<R>,
+) -> Result<Vec<ReferenceTranscript>, Error> {
+    let exon_seqs = tx
+        .ordered_exons()
+        .into_iter()
+        .map(|exon| fetch_exon(genome, &tx.chrom, exon, tx.strand))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let splice = |skip: Option<(usize, usize)>| -> Vec<u8> {
+        let mut seq = Vec::new();
+        for (i, exon_seq) in exon_seqs.iter().enumerate() {
+            let exon_number = i + 1;
+            match skip {
+                Some((first, last)) if exon_number >= first && exon_number <= last => (),
+                _ => seq.extend_from_slice(exon_seq),
+            }
+        }
+        seq
+    };
+
+    // '|' separates the header fields read back by utils::read_transcripts
+    let tx_name = tx.tx_id.replace('|', "~");
+    let mut transcripts = vec![ReferenceTranscript {
+        name: tx_name.clone(),
+        gene: tx.gene.clone(),
+        seq: splice(None),
+    }];
+
+    let deletions = internal_deletions(exon_seqs.len());
+    if deletions.is_empty() {
+        warn!(
+            "{} has {} exons, no internal exons to delete",
+            tx.tx_id,
+            exon_seqs.len()
+        );
+    }
+
+    for (first, last) in deletions {
+        transcripts.push(ReferenceTranscript {
+            name: deletion_name(&tx_name, first, last),
+            gene: tx.gene.clone(),
+            seq: splice(Some((first, last))),
+        });
+    }
+
+    Ok(transcripts)
+}
+
+/// Build the full Toblerone transcriptome for a set of canonical transcripts.
+pub fn build_reference<R: io::Read + io::Seek>(
+    canonical: &[CanonicalTranscript],
+    genome: &mut fasta::IndexedReader<R>,
+) -> Result<Vec<ReferenceTranscript>, Error> {
+    let mut transcripts = Vec::new();
+    let mut seen = HashSet::new();
+
+    for tx in canonical {
+        let generated = deletion_transcripts(tx, genome)?;
+        info!(
+            "{} ({}): {} exons, {} deletion transcripts",
+            tx.tx_id,
+            tx.gene,
+            tx.exons.len(),
+            generated.len() - 1
+        );
+
+        for t in generated {
+            // the index keys transcripts by name
+            if !seen.insert(t.name.clone()) {
+                return Err(failure::err_msg(format!(
+                    "Duplicate transcript name {} ({}); each BED12 or GTF transcript must be listed once",
+                    t.name, t.gene
+                )));
+            }
+            transcripts.push(t);
+        }
+    }
+
+    Ok(transcripts)
+}
+
+pub fn write_fasta<W: Write>(transcripts: &[ReferenceTranscript], writer: W) -> Result<(), Error> {
+    let mut writer = fasta::Writer::new(writer);
+    for t in transcripts {
+        writer.write(&t.fasta_id(), None, &t.seq)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Convert the generated transcriptome into the inputs of `build_index`, in the same
+/// form as `utils::read_transcripts` returns for the equivalent FASTA file.
+pub fn to_index_input(
+    transcripts: &[ReferenceTranscript],
+) -> (Vec<DnaString>, Vec<String>, HashMap<String, String>, HashMap<String, usize>) {
+    let mut seqs = Vec::new();
+    let mut tx_names = Vec::new();
+    let mut tx_gene_map = HashMap::new();
+    let mut gene_length_map = HashMap::new();
+
+    for t in transcripts {
+        seqs.push(DnaString::from_acgt_bytes_hashn(&t.seq, t.fasta_id().as_bytes()));
+        tx_names.push(t.name.clone());
+        tx_gene_map.insert(t.name.clone(), t.gene.clone());
+
+        let current_length = gene_length_map.entry(t.gene.clone()).or_insert(0);
+        if t.seq.len() > *current_length {
+            *current_length = t.seq.len()
+        };
+    }
+
+    (seqs, tx_names, tx_gene_map, gene_length_map)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn internal_deletions_test() {
+        // N exons gives (N-1 choose 2) deletions
+        for n in 3..12 {
+            assert_eq!(internal_deletions(n).len(), (n - 1) * (n - 2) / 2);
+        }
+        assert!(internal_deletions(2).is_empty());
+
+        let names: Vec<String> = internal_deletions(5)
+            .into_iter()
+            .map(|(first, last)| deletion_name("TX1", first, last))
+            .collect();
+        assert_eq!(
+            names,
+            vec!["TX1_del2", "TX1_del2_3", "TX1_del3", "TX1_del2_3_4", "TX1_del3_4", "TX1_del4"]
+        );
+    }
+
+    #[test]
+    fn ordered_exons_test() {
+        let mut tx = CanonicalTranscript {
+            tx_id: "TX1".to_string(),
+            gene: "GENE".to_string(),
+            chrom: "chr1".to_string(),
+            strand: Strand::Forward,
+            exons: vec![(10, 20), (30, 40), (50, 60)],
+        };
+        assert_eq!(tx.ordered_exons()[0], (10, 20));
+
+        tx.strand = Strand::Reverse;
+        assert_eq!(tx.ordered_exons()[0], (50, 60));
+    }
+
+    fn genome() -> fasta::IndexedReader<io::Cursor<Vec<u8>>> {
+        // chr1: exons at 2-6, 10-14, 18-22 and 26-30, the last soft masked in lower case
+        let fasta = b">chr1\nNNAACCNNNNGGTTNNNNCATGNNNNacgtNN\n>chr2\nNNTTTTGGGGNN\n".to_vec();
+        let fai = b"chr1\t32\t6\t32\t33\nchr2\t12\t45\t12\t13\n".to_vec();
+        fasta::IndexedReader::new(io::Cursor::new(fasta), io::Cursor::new(fai)).unwrap()
+    }
+
+    #[test]
+    fn bed12_reference_test() {
+        let bed = "track name=test\n\
+                   chr1\t2\t30\tTX1\t0\t-\t2\t30\t0\t4\t4,4,4,4,\t0,8,16,24,\n\
+                   chr2\t2\t10\tTX2\t0\t+\t2\t10\t0\t2\t4,4,\t0,4,\n";
+        let canonical = parse_bed12(io::Cursor::new(bed), None).unwrap();
+        assert_eq!(canonical.len(), 2);
+        assert_eq!(canonical[0].strand, Strand::Reverse);
+        assert_eq!(canonical[0].exons, vec![(2, 6), (10, 14), (18, 22), (26, 30)]);
+
+        let transcripts = build_reference(&canonical, &mut genome()).unwrap();
+        let summary: Vec<(&str, &str, &[u8])> = transcripts
+            .iter()
+            .map(|t| (t.name.as_str(), t.gene.as_str(), t.seq.as_slice()))
+            .collect();
+
+        // minus strand: exons 1-4 are acgt, CATG, GGTT and AACC reverse complemented
+        assert_eq!(
+            summary,
+            vec![
+                ("TX1", "TX1", &b"ACGTCATGAACCGGTT"[..]),
+                ("TX1_del2", "TX1", &b"ACGTAACCGGTT"[..]),
+                ("TX1_del2_3", "TX1", &b"ACGTGGTT"[..]),
+                ("TX1_del3", "TX1", &b"ACGTCATGGGTT"[..]),
+                ("TX2", "TX2", &b"TTTTGGGG"[..]),
+            ]
+        );
+        assert_eq!(transcripts[1].fasta_id(), "TX1_del2|TX1|3|4|5|6|7|8|9");
+
+        // the same transcript twice would give the index duplicate names
+        let duplicated = vec![canonical[1].clone(), canonical[1].clone()];
+        assert!(build_reference(&duplicated, &mut genome()).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright (c) 2021 Andrew Lonsdale tinyt
+
+//! Results of `tinyt map`: the per transcript table as CSV, or a single JSON document
+//! that also records the run parameters, index metadata and mapping statistics.
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use failure::{self, Error};
+use serde::Serialize;
+
+use crate::bootstrap::BootstrapSummary;
+use crate::index_file::IndexHeader;
+use crate::pseudoaligner::MapParams;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Result<OutputFormat, Error> {
+        match name.to_lowercase().as_str() {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(failure::err_msg(format!(
+                "Unknown output format '{}', expected csv or json",
+                name
+            ))),
+        }
+    }
+}
+
+/// Settings of a `tinyt map` run
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RunParameters {
+    pub reads: Vec<String>,
+    pub reads_pair: Vec<String>,
+    pub interleaved: bool,
+    pub num_threads: usize,
+    pub trim: bool,
+    pub trim_size: usize,
+    /// mismatch and coverage thresholds used to map and count reads
+    pub mapping: MapParams,
+    pub read_length: Option<usize>,
+    pub bootstraps: usize,
+    pub seed: Option<u64>,
+}
+
+/// The run recorded in JSON output: parameters, and the index file and its header
+/// (`None` for indexes written before headers were introduced)
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RunInfo {
+    pub tinyt_version: String,
+    pub index: String,
+    pub index_header: Option<IndexHeader>,
+    pub parameters: RunParameters,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReportOptions {
+    pub format: OutputFormat,
+    pub run: RunInfo,
+}
+
+impl Default for ReportOptions {
+    fn default() -> ReportOptions {
+        ReportOptions {
+            format: OutputFormat::Csv,
+            run: RunInfo::default(),
+        }
+    }
+}
+
+/// Read counters of a run, logged and included in JSON output
+#[derive(Serialize, Clone, Debug)]
+pub struct MappingStatistics {
+    pub processed_reads: usize,
+    pub unique_reads: u32,
+    /// `None` if the trim check was not run
+    pub unique_rejected_reads: Option<usize>,
+    pub shared_reads: usize,
+    pub mapped_reads: usize,
+    pub unmapped_reads: usize,
+    pub average_read_length: usize,
+    pub em_rounds: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BootstrapIntervals {
+    pub count: BootstrapSummary,
+    pub proportion: BootstrapSummary,
+    pub em_count: BootstrapSummary,
+}
+
+/// One row of the per transcript table
+#[derive(Serialize, Clone, Debug)]
+pub struct TranscriptRow {
+    pub gene: String,
+    pub deletion: String,
+    pub count: u32,
+    pub total: usize,
+    pub gene_length: usize,
+    pub read_length: usize,
+    pub scale_factor: f32,
+    pub proportion: f32,
+    pub scaled_proportion: f32,
+    pub em_count: f64,
+    pub tpm: f64,
+    pub bootstrap: Option<BootstrapIntervals>,
+}
+
+/// Everything `tinyt map` reports for a sample
+#[derive(Serialize, Clone, Debug)]
+pub struct MappingSummary {
+    pub statistics: MappingStatistics,
+    /// reads per strand or mate selection, e.g. `forward`, `from R1`
+    pub strands: BTreeMap<String, u32>,
+    pub transcripts: Vec<TranscriptRow>,
+}
+
+#[derive(Serialize)]
+struct MapReport<'a> {
+    run: &'a RunInfo,
+    #[serde(flatten)]
+    summary: &'a MappingSummary,
+}
+
+pub fn write_csv(summary: &MappingSummary, output_file: &mut dyn Write) -> Result<(), Error> {
+    let has_bootstrap = summary.transcripts.iter().any(|row| row.bootstrap.is_some());
+    write!(
+        output_file,
+        "Gene,Deletion,Count,Total,GeneLength,ReadLength,ScaleFactor,Proportion,ScaledProportion,EMCount,TPM"
+    )?;
+    if has_bootstrap {
+        write!(
+            output_file,
+            ",CountMean,CountSD,CountLower,CountUpper,ProportionMean,ProportionSD,ProportionLower,ProportionUpper,\
+             EMCountMean,EMCountSD,EMCountLower,EMCountUpper"
+        )?;
+    }
+    writeln!(output_file)?;
+
+    for row in &summary.transcripts {
+        write!(
+            output_file,
+            "{},{},{},{},{},{},{},{},{},{:.2},{:.2}",
+            row.gene,
+            row.deletion,
+            row.count,
+            row.total,
+            row.gene_length,
+            row.read_length,
+            row.scale_factor,
+            row.proportion,
+            row.scaled_proportion,
+            row.em_count,
+            row.tpm
+        )?;
+        if let Some(intervals) = &row.bootstrap {
+            let (count, proportion, em_count) = (&intervals.count, &intervals.proportion, &intervals.em_count);
+            write!(
+                output_file,
+                ",{:.2},{:.2},{},{},{},{},{},{},{:.2},{:.2},{:.2},{:.2}",
+                count.mean,
+                count.sd,
+                count.lower,
+                count.upper,
+                proportion.mean,
+                proportion.sd,
+                proportion.lower,
+                proportion.upper,
+                em_count.mean,
+                em_count.sd,
+                em_count.lower,
+                em_count.upper
+            )?;
+        }
+        writeln!(output_file)?;
+    }
+    Ok(())
+}
+
+pub fn write_json(summary: &MappingSummary, run: &RunInfo, output_file: &mut dyn Write) -> Result<(), Error> {
+    serde_json::to_writer_pretty(&mut *output_file, &MapReport { run, summary })?;
+    writeln!(output_file)?;
+    Ok(())
+}
+
+pub fn write_report(summary: &MappingSummary, options: &ReportOptions, output_file: &mut dyn Write) -> Result<(), Error> {
+    match options.format {
+        OutputFormat::Csv => write_csv(summary, output_file),
+        OutputFormat::Json => write_json(summary, &options.run, output_file),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn row(deletion: &str, count: u32, bootstrap: Option<BootstrapIntervals>) -> TranscriptRow {
+        TranscriptRow {
+            gene: "GENE1".to_string(),
+            deletion: deletion.to_string(),
+            count,
+            total: 40,
+            gene_length: 1000,
+            read_length: 100,
+            scale_factor: 2.5,
+            proportion: count as f32 / 40.0,
+            scaled_proportion: count as f32 / 100.0,
+            em_count: count as f64,
+            tpm: count as f64 * 1000.0 / 3.0,
+            bootstrap,
+        }
+    }
+
+    fn summary(transcripts: Vec<TranscriptRow>) -> MappingSummary {
+        MappingSummary {
+            statistics: MappingStatistics {
+                processed_reads: 50,
+                unique_reads: 30,
+                unique_rejected_reads: None,
+                shared_reads: 10,
+                mapped_reads: 40,
+                unmapped_reads: 10,
+                average_read_length: 100,
+                em_rounds: 3,
+            },
+            strands: vec![("forward".to_string(), 40)].into_iter().collect(),
+            transcripts,
+        }
+    }
+
+    fn csv(summary: &MappingSummary) -> Result<String, Error> {
+        let mut out = Vec::new();
+        write_csv(summary, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn write_csv_test() -> Result<(), Error> {
+        let rows = vec![row("GENE1-001", 30, None), row("GENE1-001del1", 10, None)];
+        assert_eq!(
+            csv(&summary(rows))?,
+            "Gene,Deletion,Count,Total,GeneLength,ReadLength,ScaleFactor,Proportion,ScaledProportion,EMCount,TPM\n\
+             GENE1,GENE1-001,30,40,1000,100,2.5,0.75,0.3,30.00,10000.00\n\
+             GENE1,GENE1-001del1,10,40,1000,100,2.5,0.25,0.1,10.00,3333.33\n"
+        );
+
+        let summary_of = |mean: f64| BootstrapSummary {
+            mean,
+            sd: 1.5,
+            lower: mean - 3.0,
+            upper: mean + 3.0,
+        };
+        let intervals = BootstrapIntervals {
+            count: summary_of(10.25),
+            proportion: summary_of(0.5),
+            em_count: summary_of(12.25),
+        };
+        let rows = vec![row("GENE1-001del1", 10, Some(intervals))];
+        assert_eq!(
+            csv(&summary(rows))?,
+            "Gene,Deletion,Count,Total,GeneLength,ReadLength,ScaleFactor,Proportion,ScaledProportion,EMCount,TPM,\
+             CountMean,CountSD,CountLower,CountUpper,ProportionMean,ProportionSD,ProportionLower,ProportionUpper,\
+             EMCountMean,EMCountSD,EMCountLower,EMCountUpper\n\
+             GENE1,GENE1-001del1,10,40,1000,100,2.5,0.25,0.1,10.00,3333.33,10.25,1.50,7.25,13.25,0.5,1.5,-2.5,3.5,\
+             12.25,1.50,9.25,15.25\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn write_json_test() -> Result<(), Error> {
+        let mut out = Vec::new();
+        write_json(&summary(vec![row("GENE1-001del1", 10, None)]), &RunInfo::default(), &mut out)?;
+        let json: serde_json::Value = serde_json::from_slice(&out)?;
+
+        let keys: Vec<&String> = json.as_object().unwrap().keys().collect();
+        assert_eq!(keys, vec!["run", "statistics", "strands", "transcripts"]);
+        assert!(json["run"]["parameters"].is_object());
+        assert_eq!(json["statistics"]["mapped_reads"], 40);
+        assert_eq!(json["strands"]["forward"], 40);
+        assert_eq!(json["transcripts"][0]["deletion"], "GENE1-001del1");
+        assert!(json["transcripts"][0]["bootstrap"].is_null());
+        Ok(())
+    }
+}
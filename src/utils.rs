@@ -17,7 +17,7 @@ use serde::{de::DeserializeOwned, Serialize};
 
 use bio::io::{fasta, fastq};
 use debruijn::dna_string::DnaString;
-use log::info;
+use log::{info, warn};
 
 use crate::config::FastaFormat;
  use debruijn::Mer;
@@ -44,20 +44,222 @@ pub fn read_obj<T: DeserializeOwned, P: AsRef<Path> + Debug>(
     deserialize_from(&mut reader)
 }
 
-/// Open a (possibly gzipped) file into a BufReader.
-fn _open_with_gz<P: AsRef<Path>>(p: P) -> Result<Box<dyn BufRead>, Error> {
-    let r = File::open(p.as_ref())?;
+/// Open a plain or gzipped (including multi-member bgzip) file into a BufReader, or stdin
+/// for `-`. Gzip is detected from the magic bytes rather than the file extension.
+pub fn open_with_gz<P: AsRef<Path>>(p: P) -> Result<Box<dyn BufRead + Send>, Error> {
+    let r: Box<dyn io::Read + Send> = if p.as_ref() == Path::new("-") {
+        Box::new(io::stdin())
+    } else {
+        let file = File::open(p.as_ref())
+            .map_err(|e| failure::err_msg(format!("Could not open {}: {}", p.as_ref().display(), e)))?;
+        Box::new(file)
+    };
 
-    if p.as_ref().extension().unwrap() == "gz" {
-        let gz = MultiGzDecoder::new(r);
-        let buf_reader = BufReader::with_capacity(32 * 1024, gz);
-        Ok(Box::new(buf_reader))
+    let mut buf_reader = BufReader::with_capacity(32 * 1024, r);
+    if buf_reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        let gz = MultiGzDecoder::new(buf_reader);
+        Ok(Box::new(BufReader::with_capacity(32 * 1024, gz)))
     } else {
-        let buf_reader = BufReader::with_capacity(32 * 1024, r);
         Ok(Box::new(buf_reader))
     }
 }
 
+/// A read to map, from FASTQ, FASTA or SAM/BAM/CRAM. FASTA reads have no base qualities.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadRecord {
+    pub id: String,
+    pub seq: Vec<u8>,
+    pub qual: Option<Vec<u8>>,
+}
+
+impl ReadRecord {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn seq(&self) -> &[u8] {
+        &self.seq
+    }
+
+    pub fn qual(&self) -> Option<&[u8]> {
+        self.qual.as_deref()
+    }
+}
+
+impl From<fastq::Record> for ReadRecord {
+    fn from(record: fastq::Record) -> ReadRecord {
+        ReadRecord {
+            id: record.id().to_string(),
+            seq: record.seq().to_vec(),
+            qual: Some(record.qual().to_vec()),
+        }
+    }
+}
+
+impl From<fasta::Record> for ReadRecord {
+    fn from(record: fasta::Record) -> ReadRecord {
+        ReadRecord {
+            id: record.id().to_string(),
+            seq: record.seq().to_vec(),
+            qual: None,
+        }
+    }
+}
+
+pub type RecordResult = io::Result<ReadRecord>;
+pub type Records = Box<dyn Iterator<Item = RecordResult> + Send>;
+pub type RecordPairs = Box<dyn Iterator<Item = (RecordResult, RecordResult)> + Send>;
+
+/// Reads to map, either single end or as R1/R2 pairs
+pub enum ReadInput {
+    Single(Records),
+    Paired(RecordPairs),
+}
+
+/// Records of a FASTQ or FASTA file, told apart by the first character
+fn file_records<P: AsRef<Path>>(path: P) -> Result<Records, Error> {
+    let mut reader = open_with_gz(path.as_ref())?;
+    if reader.fill_buf()?.first() == Some(&b'>') {
+        info!("Reading FASTA reads from {}, without base qualities", path.as_ref().display());
+        let records = fasta::Reader::new(reader).records();
+        Ok(Box::new(records.map(|r| r.map(ReadRecord::from))))
+    } else {
+        let records = fastq::Reader::new(reader).records();
+        Ok(Box::new(records.map(|r| r.map(ReadRecord::from))))
+    }
+}
+
+/// Records of one or more plain or gzipped FASTQ or FASTA files, read one after the other
+/// as a single sample. All files are opened up front so a missing file fails before mapping.
+pub fn read_records<P: AsRef<Path>>(paths: &[P]) -> Result<Records, Error> {
+    let mut readers = Vec::new();
+    for path in paths {
+        readers.push(file_records(path)?);
+    }
+    Ok(Box::new(readers.into_iter().flatten()))
+}
+
+/// SAM, BAM or CRAM reads rather than FASTQ, by file extension
+pub fn is_alignment_file<P: AsRef<Path>>(path: P) -> bool {
+    match path.as_ref().extension().and_then(|e| e.to_str()) {
+        Some(ext) => ["sam", "bam", "cram"].contains(&ext.to_ascii_lowercase().as_str()),
+        None => false,
+    }
+}
+
+/// Read name without a trailing `/1` or `/2` mate suffix
+pub fn mate_name(id: &str) -> &str {
+    if id.ends_with("/1") || id.ends_with("/2") {
+        &id[..id.len() - 2]
+    } else {
+        id
+    }
+}
+
+/// Check the mates of a pair have the same read name, reporting the pair number if not
+fn check_mates(pair: usize, r1: RecordResult, r2: RecordResult) -> (RecordResult, RecordResult) {
+    match (r1, r2) {
+        (Ok(r1), Ok(r2)) if mate_name(r1.id()) != mate_name(r2.id()) => {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "read pair {} has mismatched read names {} and {}",
+                    pair,
+                    r1.id(),
+                    r2.id()
+                ),
+            );
+            (Ok(r1), Err(err))
+        }
+        mates => mates,
+    }
+}
+
+/// Pairs R1 and R2 records from separate files, checking each pair's names agree and
+/// that neither file ends before the other
+struct PairedRecords {
+    reads: Records,
+    reads_pair: Records,
+    pair: usize,
+    allow_unequal: bool,
+    done: bool,
+}
+
+impl Iterator for PairedRecords {
+    type Item = (RecordResult, RecordResult);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let (r1, r2) = match (self.reads.next(), self.reads_pair.next()) {
+            (Some(r1), Some(r2)) => (r1, r2),
+            (None, None) => return None,
+            (r1, r2) => {
+                self.done = true;
+                let (longer, shorter) = if r1.is_some() { ("R1", "R2") } else { ("R2", "R1") };
+                let msg = format!(
+                    "{} ended after {} records but {} has more reads",
+                    shorter, self.pair, longer
+                );
+                if self.allow_unequal {
+                    warn!("{}, the remaining {} reads are not mapped", msg, longer);
+                    return None;
+                }
+                let err = Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg));
+                return match r1 {
+                    Some(r1) => Some((r1, err)),
+                    None => Some((err, r2.unwrap())),
+                };
+            }
+        };
+        self.pair += 1;
+        Some(check_mates(self.pair, r1, r2))
+    }
+}
+
+/// R1/R2 pairs from separate files. Files of different lengths are an error, unless
+/// `allow_unequal`, when mapping stops with a warning at the end of the shorter file.
+pub fn paired_records(reads: Records, reads_pair: Records, allow_unequal: bool) -> RecordPairs {
+    Box::new(PairedRecords {
+        reads,
+        reads_pair,
+        pair: 0,
+        allow_unequal,
+        done: false,
+    })
+}
+
+/// Pairs consecutive records of an interleaved FASTQ as R1/R2
+struct InterleavedPairs {
+    records: Records,
+    pair: usize,
+}
+
+impl Iterator for InterleavedPairs {
+    type Item = (RecordResult, RecordResult);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let r1 = self.records.next()?;
+        self.pair += 1;
+        match self.records.next() {
+            Some(r2) => Some(check_mates(self.pair, r1, r2)),
+            None => Some((
+                r1,
+                Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("interleaved FASTQ has no R2 for pair {}", self.pair),
+                )),
+            )),
+        }
+    }
+}
+
+/// R1/R2 pairs from interleaved records, checking each pair's names agree
+pub fn interleaved_pairs(records: Records) -> RecordPairs {
+    Box::new(InterleavedPairs { records, pair: 0 })
+}
+
 pub fn read_transcripts(
     reader: fasta::Reader<File>,
 ) -> Result<(Vec<DnaString>, Vec<String>, HashMap<String, String>, HashMap<String, usize >), Error> {
@@ -156,18 +358,14 @@ pub fn extract_tx_gene_id(record: &fasta::Record, fasta_format: &FastaFormat) ->
 }
 
 
-pub fn get_next_record_pair<R: io::Read>(
-    reader: &Arc<Mutex<std::iter::Zip<fastq::Records<R>,fastq::Records<R>>>>,
-) -> Option<(Result<fastq::Record, io::Error>, Result<fastq::Record, io::Error>)> {
+pub fn get_next_record_pair<I: Iterator>(reader: &Arc<Mutex<I>>) -> Option<I::Item> {
     let mut lock = reader.lock().unwrap();
     lock.next()
 }
 
 
 
-pub fn get_next_record<R: io::Read>(
-    reader: &Arc<Mutex<fastq::Records<R>>>,
-) -> Option<Result<fastq::Record, io::Error>> {
+pub fn get_next_record<I: Iterator>(reader: &Arc<Mutex<I>>) -> Option<I::Item> {
     let mut lock = reader.lock().unwrap();
     lock.next()
 }
@@ -298,9 +496,46 @@ pub fn kmers_to_u64_vec(seq: &DnaString, k: usize) -> Vec<u64> {
 // }
 
 
-use bio::io::fastq::Record;
-
-pub fn dna_from_fastq_record(rec: &Record) -> DnaString {
+pub fn dna_from_read_record(rec: &ReadRecord) -> DnaString {
     let s = std::str::from_utf8(rec.seq()).unwrap_or("");
     DnaString::from_dna_string(s)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn records(fastq: &'static str) -> Records {
+        Box::new(fastq::Reader::new(io::Cursor::new(fastq)).records().map(|r| r.map(ReadRecord::from)))
+    }
+
+    #[test]
+    fn interleaved_test() {
+        assert_eq!(mate_name("read1/1"), "read1");
+        assert_eq!(mate_name("read1"), "read1");
+
+        let fastq = "@a/1\nACGT\n+\nIIII\n@a/2\nTTTT\n+\nIIII\n@b/1\nACGT\n+\nIIII\n@c/2\nACGT\n+\nIIII\n@d\nACGT\n+\nIIII\n";
+        let pairs: Vec<_> = interleaved_pairs(records(fastq)).collect();
+        assert_eq!(pairs.len(), 3);
+        assert_eq!(pairs[0].1.as_ref().unwrap().id(), "a/2");
+        assert_eq!(pairs[1].1.as_ref().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(pairs[2].1.as_ref().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn paired_records_test() {
+        let r1 = "@a/1\nACGT\n+\nIIII\n@b/1\nACGT\n+\nIIII\n@c/1\nACGT\n+\nIIII\n";
+        let r2 = "@a/2\nACGT\n+\nIIII\n@x/2\nACGT\n+\nIIII\n";
+
+        let pairs: Vec<_> = paired_records(records(r1), records(r2), false).collect();
+        assert_eq!(pairs.len(), 3);
+        assert!(pairs[0].1.is_ok());
+        let mismatch = pairs[1].1.as_ref().unwrap_err();
+        assert_eq!(mismatch.kind(), io::ErrorKind::InvalidData);
+        assert!(mismatch.to_string().starts_with("read pair 2 "));
+        assert_eq!(pairs[2].1.as_ref().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // with unequal lengths allowed, pairing stops at the end of R2
+        assert_eq!(paired_records(records(r1), records(r2), true).count(), 2);
+    }
+}